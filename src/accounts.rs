@@ -0,0 +1,203 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::world::Serial;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccessLevel {
+    Player = 0,
+    Counselor = 1,
+    GameMaster = 2,
+    Administrator = 3,
+    Owner = 4,
+}
+
+impl AccessLevel {
+    pub fn from_u8(value: u8) -> Option<AccessLevel> {
+        match value {
+            0 => Some(AccessLevel::Player),
+            1 => Some(AccessLevel::Counselor),
+            2 => Some(AccessLevel::GameMaster),
+            3 => Some(AccessLevel::Administrator),
+            4 => Some(AccessLevel::Owner),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for AccessLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "player" => Ok(AccessLevel::Player),
+            "counselor" => Ok(AccessLevel::Counselor),
+            "gm" | "gamemaster" => Ok(AccessLevel::GameMaster),
+            "admin" | "administrator" => Ok(AccessLevel::Administrator),
+            "owner" => Ok(AccessLevel::Owner),
+            _ => Err(format!("Unknown access level: {}", value)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Account {
+    pub username: String,
+    // An Argon2 hash in PHC string format, which includes its salt.
+    pub password_hash: String,
+    pub access_level: AccessLevel,
+    pub character: Option<Serial>,
+}
+
+impl Account {
+    pub fn new(username: &str, password: &str, access_level: AccessLevel) -> Self {
+        Account {
+            username: String::from(username),
+            password_hash: hash_password(password),
+            access_level,
+            character: None,
+        }
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        PasswordHash::new(&self.password_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 can hash any password with the default parameters")
+        .to_string()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    UnknownAccount,
+    IncorrectPassword,
+}
+
+// Argon2 is slow on purpose, so passwords are hashed and checked on a thread
+// of their own rather than holding up the game loop for every login.
+pub struct Passwords {
+    job_tx: mpsc::Sender<Box<dyn FnOnce() + Send>>,
+}
+
+impl Passwords {
+    pub fn start() -> Passwords {
+        let (job_tx, job_rx) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        thread::spawn(move || {
+            for job in job_rx {
+                job();
+            }
+        });
+
+        Passwords { job_tx }
+    }
+
+    pub fn run(&self, job: impl FnOnce() + Send + 'static) {
+        let _ = self.job_tx.send(Box::new(job));
+    }
+}
+
+#[derive(Clone)]
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Accounts {
+            accounts: HashMap::new(),
+        }
+    }
+
+    // Returns None if the username is taken.
+    pub fn create(
+        &mut self,
+        username: &str,
+        password: &str,
+        access_level: AccessLevel,
+    ) -> Option<&mut Account> {
+        match self.accounts.entry(username.to_lowercase()) {
+            Entry::Occupied(_) => None,
+            Entry::Vacant(entry) => {
+                Some(entry.insert(Account::new(username, password, access_level)))
+            }
+        }
+    }
+
+    pub fn insert(&mut self, account: Account) {
+        self.accounts
+            .insert(account.username.to_lowercase(), account);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(&username.to_lowercase())
+    }
+
+    pub fn get_mut(&mut self, username: &str) -> Option<&mut Account> {
+        self.accounts.get_mut(&username.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_only_creates_accounts_whose_username_is_free() {
+        let mut accounts = Accounts::new();
+        assert!(accounts.get("alice").is_none());
+
+        accounts
+            .create("alice", "pass", AccessLevel::Owner)
+            .unwrap();
+        assert_eq!(
+            accounts.get("alice").unwrap().access_level,
+            AccessLevel::Owner
+        );
+        assert!(accounts
+            .create("Alice", "other", AccessLevel::Player)
+            .is_none());
+    }
+
+    #[test]
+    fn it_stores_salted_password_hashes() {
+        let alice = Account::new("alice", "pass", AccessLevel::Player);
+        let bob = Account::new("bob", "pass", AccessLevel::Player);
+        assert!(!alice.password_hash.contains("pass"));
+        assert_ne!(alice.password_hash, bob.password_hash);
+        assert!(alice.check_password("pass"));
+        assert!(!alice.check_password("Pass"));
+    }
+
+    #[test]
+    fn it_ignores_username_case() {
+        let mut accounts = Accounts::new();
+        accounts
+            .create("Alice", "pass", AccessLevel::Player)
+            .unwrap();
+        assert!(accounts.get("alice").unwrap().check_password("pass"));
+    }
+
+    #[test]
+    fn it_orders_access_levels() {
+        assert!(AccessLevel::Owner > AccessLevel::Administrator);
+        assert!(AccessLevel::Counselor > AccessLevel::Player);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::config::Config;
+use crate::shard::Shard;
+use crate::speech;
+use crate::timer::{Late, Repeat, Timer};
+
+// Schedules the next autosave. Each save schedules the one after it, so a
+// reloaded config takes effect from the next save on.
+pub fn start(shard: &mut Shard) {
+    let Some((delay, warning_interval)) = schedule(&shard.config) else {
+        println!("Autosave is turned off");
+        shard.autosave = None;
+        return;
+    };
+
+    let inbox = shard.inbox.clone();
+    let handle = shard.timers.register(Timer {
+        repeat: Repeat::Once,
+        late: Late::Skip,
+        interval: 0,
+        next: shard.timers.now() + delay,
+        callback: Box::new(move || inbox.run(move |shard| warn(shard, warning_interval))),
+    });
+    shard.autosave = Some(handle);
+}
+
+// Pushes the next autosave back a whole interval, e.g. after a manual save.
+pub fn postpone(shard: &Shard) {
+    if let (Some(handle), Some((delay, _))) = (&shard.autosave, schedule(&shard.config)) {
+        handle.reschedule(shard.timers.now() + delay);
+    }
+}
+
+pub fn stop(shard: &mut Shard) {
+    if let Some(handle) = shard.autosave.take() {
+        handle.cancel();
+    }
+}
+
+// Returns how long until players are warned of the next autosave and how
+// long after that the save happens, in milliseconds, or None if autosave is
+// turned off.
+fn schedule(config: &Config) -> Option<(i64, i64)> {
+    let interval = config.autosave_interval_minutes as i64 * 60 * 1000;
+    if interval == 0 {
+        return None;
+    }
+
+    let warning_interval = (config.autosave_warning_seconds as i64 * 1000).min(interval);
+    Some((interval - warning_interval, warning_interval))
+}
+
+fn warn(shard: &mut Shard, warning_interval: i64) {
+    if warning_interval > 0 {
+        let message = format!(
+            "The world will be saved in {} seconds.",
+            warning_interval / 1000
+        );
+        speech::broadcast_system_message(&shard.sessions, &message);
+    }
+
+    let inbox = shard.inbox.clone();
+    shard.timers.register(Timer {
+        repeat: Repeat::Once,
+        late: Late::Skip,
+        interval: 0,
+        next: shard.timers.now() + warning_interval,
+        callback: Box::new(move || {
+            inbox.run(|shard| {
+                // The save thread reports how the save went.
+                shard.save(false);
+                start(shard);
+            })
+        }),
+    });
+}
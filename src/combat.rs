@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::events::{self, Attack, Damage};
+use crate::sessions::SessionId;
+use crate::shard::Shard;
+use crate::skills::{self, SkillId};
+use crate::state::{MobileProperty, State, StateDelta};
+use crate::tcp::packets;
+use crate::templates::ItemTemplate;
+use crate::timer::{Late, Repeat, Timer, TimerHandle};
+use crate::world::{Mobile, Notoriety, Serial};
+
+// The skills weapons are fought with.
+pub const WEAPON_SKILLS: [&str; 4] = ["swordsmanship", "mace_fighting", "fencing", "wrestling"];
+
+// Mobiles have to be next to each other to land a blow, and give up once
+// they lose sight of each other.
+const MELEE_RANGE: u16 = 1;
+const COMBAT_RANGE: u16 = 18;
+
+// The quickest anything can swing, in milliseconds.
+const SHORTEST_SWING: i64 = 1_250;
+
+// What a mobile holding no weapon fights with.
+const FISTS: Weapon = Weapon {
+    skill: 43, // wrestling
+    damage: [1, 8],
+    speed: 50,
+    animation: 31,
+};
+
+// Only humans have a different animation for each weapon. Everything else
+// attacks the same way whatever it holds.
+const HUMAN_BODIES: [u16; 2] = [0x190, 0x191];
+const MONSTER_ATTACK_ANIMATION: u16 = 4;
+
+// Who each mobile in combat is fighting, and the timer for its next swing.
+#[derive(Default)]
+pub struct Combat {
+    fighting: HashMap<Serial, (Serial, TimerHandle)>,
+}
+
+impl Combat {
+    pub fn target(&self, attacker: Serial) -> Option<Serial> {
+        self.fighting.get(&attacker).map(|&(defender, _)| defender)
+    }
+}
+
+// A weapon's template stats, with its skill looked up.
+struct Weapon {
+    skill: SkillId,
+    damage: [u16; 2],
+    speed: u16,
+    animation: u16,
+}
+
+// A player asked to attack a mobile, by double-clicking it in war mode or
+// with a macro.
+pub fn attack(shard: &mut Shard, session_id: SessionId, defender: Serial) {
+    let Some(attacker) = shard
+        .sessions
+        .get(session_id)
+        .and_then(|session| session.mobile)
+    else {
+        return;
+    };
+
+    start(shard, attacker, defender);
+}
+
+// A player toggled war mode. Leaving it stops them fighting.
+pub fn set_war_mode(shard: &mut Shard, session_id: SessionId, war_mode: bool) {
+    let Some(session) = shard.sessions.get(session_id) else {
+        return;
+    };
+    let Some(serial) = session.mobile else {
+        return;
+    };
+    session.send(packets::war_mode_packet(war_mode));
+
+    if let Some(mobile) = shard.world.mobile_mut(serial) {
+        mobile.set_war_mode(war_mode);
+    }
+    if !war_mode {
+        stop(shard, serial);
+    }
+}
+
+// Makes `attacker` fight `defender` until one of them dies, they move too
+// far apart or something else stops it. Returns false if it can't, e.g.
+// because the defender is already dead or a subscriber to the attack
+// cancelled it.
+pub fn start(shard: &mut Shard, attacker: Serial, defender: Serial) -> bool {
+    // The timers of removed mobiles were cancelled along with them.
+    shard
+        .combat
+        .fighting
+        .retain(|_, (_, timer)| timer.is_active());
+
+    if attacker == defender || shard.combat.target(attacker) == Some(defender) {
+        return false;
+    }
+    let (Some(from), Some(to)) = (shard.world.mobile(attacker), shard.world.mobile(defender))
+    else {
+        return false;
+    };
+    if from.hits == 0
+        || to.hits == 0
+        || to.notoriety == Notoriety::Invulnerable
+        || !from.location.in_range(&to.location, COMBAT_RANGE)
+    {
+        return false;
+    }
+
+    let mut attack = Attack { attacker, defender };
+    if !events::publish(shard, &mut attack) {
+        return false;
+    }
+
+    stop(shard, attacker);
+    if shard
+        .world
+        .mobile(attacker)
+        .is_some_and(|mobile| !mobile.war_mode)
+    {
+        if let Some(mobile) = shard.world.mobile_mut(attacker) {
+            mobile.set_war_mode(true);
+        }
+    }
+    if let Some(session) = shard.mobile_session(attacker) {
+        session.send(packets::change_combatant_packet(defender));
+    }
+    let weapon = gear(shard, attacker).weapon;
+    schedule_swing(shard, attacker, defender, &weapon);
+
+    true
+}
+
+// Stops the mobile fighting whoever it was.
+pub fn stop(shard: &mut Shard, attacker: Serial) {
+    let Some((_, timer)) = shard.combat.fighting.remove(&attacker) else {
+        return;
+    };
+    timer.cancel();
+
+    if let Some(session) = shard.mobile_session(attacker) {
+        session.send(packets::change_combatant_packet(0));
+    }
+}
+
+// Nobody fights the dead, and the dead don't fight.
+pub fn stop_all(shard: &mut Shard, serial: Serial) {
+    let attackers: Vec<Serial> = shard
+        .combat
+        .fighting
+        .iter()
+        .filter(|&(&attacker, &(defender, _))| attacker == serial || defender == serial)
+        .map(|(&attacker, _)| attacker)
+        .collect();
+    for attacker in attackers {
+        stop(shard, attacker);
+    }
+}
+
+fn schedule_swing(shard: &mut Shard, attacker: Serial, defender: Serial, weapon: &Weapon) {
+    let Some(mobile) = shard.world.mobile(attacker) else {
+        return;
+    };
+    let delay = swing_delay(mobile, weapon);
+
+    let inbox = shard.inbox.clone();
+    let timer = shard.timers.register(Timer {
+        repeat: Repeat::Once,
+        late: Late::Skip,
+        interval: 0,
+        next: shard.timers.now() + delay,
+        callback: Box::new(move || inbox.run(move |shard| swing(shard, attacker))),
+    });
+    shard.world.own_timer(attacker, timer.clone());
+    shard.combat.fighting.insert(attacker, (defender, timer));
+}
+
+// Faster weapons and more stamina swing sooner.
+fn swing_delay(mobile: &Mobile, weapon: &Weapon) -> i64 {
+    let speed = (mobile.stamina as i64 + 100) * weapon.speed.max(1) as i64;
+    (15_000_000 / speed).max(SHORTEST_SWING)
+}
+
+// Has a go at hitting the defender, then waits for the next swing.
+fn swing(shard: &mut Shard, attacker: Serial) {
+    let Some(defender) = shard.combat.target(attacker) else {
+        return;
+    };
+    let (Some(from), Some(to)) = (shard.world.mobile(attacker), shard.world.mobile(defender))
+    else {
+        stop(shard, attacker);
+        return;
+    };
+    if from.hits == 0 || to.hits == 0 || !from.location.in_range(&to.location, COMBAT_RANGE) {
+        stop(shard, attacker);
+        return;
+    }
+    let weapon = gear(shard, attacker).weapon;
+    // Too far away to reach, for now.
+    if !from.location.in_range(&to.location, MELEE_RANGE) {
+        schedule_swing(shard, attacker, defender, &weapon);
+        return;
+    }
+
+    let defence = gear(shard, defender);
+    let defence_skill = to.skills.get(defence.weapon.skill).value();
+    let mut rng = rand::thread_rng();
+    // Only borrowed mutably if the swing teaches it something, so a swing
+    // that changes nothing about the attacker doesn't count as a change.
+    let (hit, learned) = skills::roll_chance(
+        from,
+        weapon.skill,
+        hit_chance(from.skills.get(weapon.skill).value(), defence_skill),
+        &mut rng,
+    );
+    let amount = hit.then(|| damage_dealt(from, &weapon, defence.armor, &mut rng));
+
+    let animation = if HUMAN_BODIES.contains(&from.body) {
+        weapon.animation
+    } else {
+        MONSTER_ATTACK_ANIMATION
+    };
+    let location = from.location;
+    if learned {
+        if let Some(mobile) = shard.world.mobile_mut(attacker) {
+            skills::gain(mobile, weapon.skill);
+        }
+    }
+    if let Some(session) = shard.mobile_session(attacker) {
+        session.send(packets::swing_packet(attacker, defender));
+    }
+    for session in shard.sessions_in_range(&location, COMBAT_RANGE) {
+        session.send(packets::animation_packet(attacker, animation));
+    }
+
+    if let Some(amount) = amount {
+        let taken = damage(shard, defender, Some(attacker), amount);
+        if taken > 0 {
+            for serial in [attacker, defender] {
+                if let Some(session) = shard.mobile_session(serial) {
+                    session.send(packets::damage_packet(defender, taken));
+                }
+            }
+        }
+    }
+
+    retaliate(shard, defender, attacker);
+    // Unless the blow ended the fight.
+    if shard.combat.target(attacker) == Some(defender) {
+        schedule_swing(shard, attacker, defender, &weapon);
+    }
+}
+
+// Mobiles fight back when they aren't already fighting, except players who
+// aren't in war mode.
+fn retaliate(shard: &mut Shard, defender: Serial, attacker: Serial) {
+    if shard.combat.target(defender).is_some() {
+        return;
+    }
+    let Some(mobile) = shard.world.mobile(defender) else {
+        return;
+    };
+    if shard.mobile_session(defender).is_some() && !mobile.war_mode {
+        return;
+    }
+
+    start(shard, defender, attacker);
+}
+
+// Equally skilled fighters hit each other half the time.
+fn hit_chance(attack: u16, defence: u16) -> f64 {
+    (attack as f64 + 500.0) / ((defence as f64 + 500.0) * 2.0)
+}
+
+// Strength adds up to 30% at 100. Armor soaks up between half and all of
+// its rating, but every blow that lands does something.
+fn damage_dealt(mobile: &Mobile, weapon: &Weapon, armor: u16, rng: &mut impl Rng) -> u16 {
+    let [min, max] = weapon.damage;
+    let base = rng.gen_range(min..=max) as f64;
+    let dealt = (base * (1.0 + mobile.strength as f64 * 0.003)) as u16;
+    let absorbed = rng.gen_range(armor / 2..=armor);
+    dealt.saturating_sub(absorbed).max(1)
+}
+
+// What a mobile fights with and how much damage it soaks up, from what it's
+// holding or wearing.
+struct Gear {
+    weapon: Weapon,
+    armor: u16,
+}
+
+// The first weapon the mobile is holding, or its fists, and all of its
+// armor.
+fn gear(shard: &Shard, serial: Serial) -> Gear {
+    let mut weapon = None;
+    let mut armor = 0u16;
+    for template in held(shard, serial) {
+        armor = armor.saturating_add(template.armor);
+        weapon = weapon.or_else(|| {
+            let weapon = template.weapon.as_ref()?;
+            Some(Weapon {
+                skill: skills::by_name(&weapon.skill)?,
+                damage: weapon.damage,
+                speed: weapon.speed,
+                animation: weapon.animation,
+            })
+        });
+    }
+
+    Gear {
+        weapon: weapon.unwrap_or(FISTS),
+        armor,
+    }
+}
+
+// The templates of what the mobile is holding or wearing. Items in its
+// backpack don't count.
+fn held<'a>(shard: &'a Shard, serial: Serial) -> impl Iterator<Item = &'a ItemTemplate> + 'a {
+    shard
+        .world
+        .contents(serial)
+        .into_iter()
+        .filter_map(|item| shard.world.items.get(&item)?.template.as_deref())
+        .filter_map(|id| shard.templates.item(id))
+}
+
+// Takes hits from a mobile, unless a subscriber to the damage stops it.
+// Returns how many hits were taken. If that takes it to 0 it dies at the
+// end of the tick, along with anything else whose hits ran out.
+pub fn damage(shard: &mut Shard, target: Serial, source: Option<Serial>, amount: u16) -> u16 {
+    // The dead can't be hurt.
+    if shard
+        .world
+        .mobile(target)
+        .is_none_or(|mobile| mobile.hits == 0)
+    {
+        return 0;
+    }
+
+    let mut damage = Damage {
+        target,
+        source,
+        amount,
+    };
+    if !events::publish(shard, &mut damage) {
+        return 0;
+    }
+
+    // A subscriber might have removed the target.
+    let Some(mobile) = shard.world.mobile_mut(target) else {
+        return 0;
+    };
+    let hits = mobile.hits;
+    mobile.update_state(&[StateDelta::new(
+        MobileProperty::Hits,
+        -(damage.amount as i32),
+    )]);
+
+    hits - mobile.hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Commands;
+    use crate::death;
+    use crate::events::{Death, Flow};
+    use crate::game_loop;
+    use crate::location::START_LOCATION;
+    use crate::shard::tests::{shard, shard_with_clock};
+    use rand::rngs::mock::StepRng;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn it_kills_mobiles_whose_hits_run_out() {
+        let (mut shard, _) = shard("combat-death");
+        let mobile = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let died = Arc::new(AtomicBool::new(false));
+        let dying = Arc::clone(&died);
+        shard.events.subscribe(0, move |_, _: &mut Death| {
+            dying.store(true, Ordering::Relaxed);
+            Flow::Continue
+        });
+
+        assert_eq!(damage(&mut shard, mobile, None, 60), 60);
+        death::reap(&mut shard);
+        assert!(!died.load(Ordering::Relaxed));
+        assert_eq!(damage(&mut shard, mobile, None, 60), 40);
+        death::reap(&mut shard);
+        assert!(died.load(Ordering::Relaxed));
+
+        // The dead don't die again.
+        died.store(false, Ordering::Relaxed);
+        assert_eq!(damage(&mut shard, mobile, None, 60), 0);
+        death::reap(&mut shard);
+        assert!(!died.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn it_swings_until_the_defender_dies() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("combat-swing");
+        let commands = Commands::new();
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let mut location = START_LOCATION;
+        location.x += 1;
+        let orc = shard.world.add_mobile("an orc", 0x11, location);
+        // Bob can't miss, and one blow is enough.
+        let wrestling = skills::by_name("wrestling").unwrap();
+        shard
+            .world
+            .mobile_mut(bob)
+            .unwrap()
+            .set_skill(wrestling, 1000);
+        shard.world.mobile_mut(orc).unwrap().hits = 1;
+
+        assert!(start(&mut shard, bob, orc));
+        assert!(!start(&mut shard, bob, orc));
+        assert_eq!(shard.combat.target(bob), Some(orc));
+        assert!(shard.world.mobile(bob).unwrap().war_mode);
+
+        clock.advance(1_500);
+        shard.timers.flush();
+        game_loop::tick(&mut shard, &messages_rx, &commands);
+        // Dead creatures leave nothing but a corpse.
+        assert!(shard.world.mobile(orc).is_none());
+        assert_eq!(shard.world.mobile(bob).unwrap().kills, 1);
+        assert_eq!(shard.combat.target(bob), None);
+        assert_eq!(shard.combat.target(orc), None);
+    }
+
+    #[test]
+    fn it_only_counts_a_swing_as_changing_what_it_changed() {
+        let (mut shard, _) = shard("combat-untouched");
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let orc = shard.world.add_mobile("an orc", 0x11, START_LOCATION);
+        // Bob can't miss, so there's nothing for him to learn.
+        let wrestling = skills::by_name("wrestling").unwrap();
+        shard
+            .world
+            .mobile_mut(bob)
+            .unwrap()
+            .set_skill(wrestling, 1000);
+        assert!(start(&mut shard, bob, orc));
+        shard.world.take_touched_mobiles();
+        shard.world.take_changes();
+
+        swing(&mut shard, bob);
+
+        assert_eq!(shard.world.take_touched_mobiles(), vec![orc]);
+        let changes = shard.world.take_changes();
+        assert!(changes.mobiles.iter().all(|mobile| mobile.serial != bob));
+    }
+
+    #[test]
+    fn it_gives_up_on_defenders_out_of_range() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("combat-range");
+        let commands = Commands::new();
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let orc = shard.world.add_mobile("an orc", 0x11, START_LOCATION);
+        assert!(start(&mut shard, bob, orc));
+
+        let mut location = START_LOCATION;
+        location.x += COMBAT_RANGE + 1;
+        shard.world.mobile_mut(orc).unwrap().move_to(location);
+        clock.advance(1_500);
+        shard.timers.flush();
+        game_loop::tick(&mut shard, &messages_rx, &commands);
+        assert_eq!(shard.combat.target(bob), None);
+        assert_eq!(shard.world.mobile(orc).unwrap().hits, 100);
+    }
+
+    #[test]
+    fn it_works_out_swings_and_damage() {
+        let mut mobile = Mobile::new(1, "Bob", 0x190, START_LOCATION);
+        assert_eq!(swing_delay(&mobile, &FISTS), 1_500);
+        mobile.stamina = 0;
+        assert_eq!(swing_delay(&mobile, &FISTS), 3_000);
+
+        assert_eq!(hit_chance(500, 500), 0.5);
+        assert_eq!(hit_chance(1000, 0), 1.5);
+
+        // The lowest rolls: the least damage, and the least absorbed.
+        mobile.strength = 100;
+        let club = Weapon {
+            damage: [10, 20],
+            ..FISTS
+        };
+        let mut rng = StepRng::new(0, 0);
+        assert_eq!(damage_dealt(&mobile, &club, 4, &mut rng), 11);
+        assert_eq!(damage_dealt(&mobile, &club, 100, &mut rng), 1);
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use rhai::{Array, Dynamic, INT};
+
+use crate::accounts::AccessLevel;
+use crate::scripts::{self, ScriptCommand};
+use crate::sessions::SessionId;
+use crate::shard::Shard;
+use crate::speech;
+use crate::world::{self, Mobile};
+
+mod audit_log;
+mod builtins;
+
+#[derive(Clone, Copy)]
+pub enum Caller {
+    Session(SessionId),
+    Console,
+}
+
+pub enum CommandError {
+    Usage,
+    Failed(String),
+}
+
+pub type CommandResult = Result<(), CommandError>;
+
+type Handler = fn(&mut CommandContext, &[&str]) -> CommandResult;
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub access_level: AccessLevel,
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub handler: Handler,
+}
+
+pub struct CommandContext<'a> {
+    pub shard: &'a mut Shard,
+    pub commands: &'a Commands,
+    pub caller: Caller,
+    pub access_level: AccessLevel,
+}
+
+impl CommandContext<'_> {
+    pub fn reply(&self, text: &str) {
+        match self.caller {
+            Caller::Session(session_id) => {
+                speech::send_system_message(&self.shard.sessions, session_id, text)
+            }
+            Caller::Console => println!("{}", text),
+        }
+    }
+
+    pub fn caller_mobile(&mut self) -> Result<&mut Mobile, CommandError> {
+        let serial = match self.caller {
+            Caller::Session(session_id) => self
+                .shard
+                .sessions
+                .get(session_id)
+                .and_then(|session| session.mobile),
+            Caller::Console => None,
+        };
+
+        serial
+            .and_then(|serial| self.shard.world.mobile_mut(serial))
+            .ok_or_else(|| {
+                CommandError::Failed(String::from("That command can only be used in game."))
+            })
+    }
+}
+
+// Parses a required argument, treating anything missing or malformed as a
+// usage error so the caller is shown how to use the command.
+pub fn argument<T: FromStr + TryFrom<u32>>(args: &[&str], index: usize) -> Result<T, CommandError> {
+    args.get(index)
+        .and_then(|arg| world::parse_number(arg))
+        .ok_or(CommandError::Usage)
+}
+
+pub struct Commands {
+    commands: Vec<Command>,
+    names: HashMap<String, usize>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        let mut commands = Commands {
+            commands: vec![],
+            names: HashMap::new(),
+        };
+
+        builtins::register(&mut commands);
+
+        commands
+    }
+
+    pub fn register(&mut self, command: Command) {
+        let index = self.commands.len();
+
+        for name in std::iter::once(&command.name).chain(command.aliases) {
+            if self.names.insert(name.to_lowercase(), index).is_some() {
+                println!("Command {} has been registered twice", name);
+            }
+        }
+
+        self.commands.push(command);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        let index = self.names.get(&name.to_lowercase())?;
+        self.commands.get(*index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter()
+    }
+
+    pub fn execute(&self, shard: &mut Shard, caller: Caller, command_line: &str) {
+        let mut words = command_line.split_whitespace();
+        let Some(name) = words.next() else {
+            return;
+        };
+        let args: Vec<&str> = words.collect();
+
+        let (caller_name, access_level) = match caller {
+            Caller::Session(session_id) => match shard.sessions.get(session_id) {
+                Some(session) => (
+                    session.account.clone().unwrap_or_default(),
+                    session.access_level,
+                ),
+                None => return,
+            },
+            Caller::Console => (String::from("console"), AccessLevel::Owner),
+        };
+
+        // Builtin commands win over script commands with the same name.
+        let command = self.find(name);
+        let script_command = match command {
+            Some(_) => None,
+            None => shard.scripts.command(name).cloned(),
+        };
+        let required_access_level = command
+            .map(|command| command.access_level)
+            .or(script_command.as_ref().map(|command| command.access_level));
+        let allowed = required_access_level.is_some_and(|required| access_level >= required);
+
+        let outcome = if allowed { "ran" } else { "was refused" };
+        audit_log::record(
+            &shard.config.audit_log_path,
+            &caller_name,
+            access_level,
+            outcome,
+            command_line,
+        );
+
+        let mut context = CommandContext {
+            shard,
+            commands: self,
+            caller,
+            access_level,
+        };
+
+        // Players are told a command doesn't exist whether or not it does,
+        // so they can't go looking for staff commands.
+        if !allowed {
+            context.reply(&format!("Unknown command: {}", name));
+            return;
+        }
+        if let Some(script_command) = script_command {
+            execute_script(&mut context, &script_command, &args);
+            return;
+        }
+        let Some(command) = command else {
+            return;
+        };
+
+        match (command.handler)(&mut context, &args) {
+            Ok(()) => {}
+            Err(CommandError::Usage) => {
+                let prefix = match caller {
+                    Caller::Session(_) => context.shard.config.command_prefix.clone(),
+                    Caller::Console => String::new(),
+                };
+                context.reply(&format!(
+                    "Usage: {}{} {}",
+                    prefix, command.name, command.usage
+                ));
+            }
+            Err(CommandError::Failed(message)) => context.reply(&message),
+        }
+    }
+}
+
+// Passes the script the caller's serial, or () from the console, and the
+// arguments, and replies with whatever string it returns.
+fn execute_script(context: &mut CommandContext, command: &ScriptCommand, args: &[&str]) {
+    let caller = match context.caller {
+        Caller::Session(session_id) => context
+            .shard
+            .sessions
+            .get(session_id)
+            .and_then(|session| session.mobile),
+        Caller::Console => None,
+    };
+    let caller = caller.map_or(Dynamic::UNIT, |serial| Dynamic::from(serial as INT));
+    let args: Array = args
+        .iter()
+        .map(|&arg| Dynamic::from(String::from(arg)))
+        .collect();
+
+    match scripts::call(
+        context.shard,
+        &command.function,
+        None,
+        vec![caller, args.into()],
+    ) {
+        Some(reply) if reply.is_string() => context.reply(&reply.to_string()),
+        Some(_) => {}
+        // The error goes to the console, where whoever writes the scripts
+        // will see it.
+        None => context.reply("Something went wrong running that command."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::{Location, START_LOCATION};
+    use crate::shard;
+    use async_std::channel;
+
+    fn shard_with_session(name: &str, access_level: AccessLevel) -> (Shard, SessionId) {
+        let (mut shard, _) = shard::tests::shard(name);
+
+        let (outgoing_tx, _outgoing_rx) = channel::unbounded();
+        let session_id = shard.sessions.add(outgoing_tx);
+        let mobile = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let session = shard.sessions.get_mut(session_id).unwrap();
+        session.access_level = access_level;
+        session.mobile = Some(mobile);
+
+        (shard, session_id)
+    }
+
+    #[test]
+    fn it_finds_commands_by_name_or_alias_in_any_case() {
+        let commands = Commands::new();
+        assert_eq!(commands.find("GO").unwrap().name, "go");
+        assert_eq!(commands.find("teleport").unwrap().name, "go");
+        assert!(commands.find("fly").is_none());
+    }
+
+    #[test]
+    fn it_runs_commands_the_caller_has_access_to() {
+        let commands = Commands::new();
+        let (mut shard, session_id) =
+            shard_with_session("commands-allowed", AccessLevel::Counselor);
+
+        commands.execute(&mut shard, Caller::Session(session_id), "go 1495 1629 10");
+
+        let location = shard.session_mobile(session_id).unwrap().location;
+        assert_eq!(
+            location,
+            Location {
+                x: 1495,
+                y: 1629,
+                z: 10
+            }
+        );
+    }
+
+    #[test]
+    fn it_refuses_commands_above_the_callers_access_level() {
+        let commands = Commands::new();
+        let (mut shard, session_id) = shard_with_session("commands-refused", AccessLevel::Player);
+
+        commands.execute(&mut shard, Caller::Session(session_id), "go 1495 1629");
+
+        let location = shard.session_mobile(session_id).unwrap().location;
+        assert_eq!(location, START_LOCATION);
+    }
+
+    #[test]
+    fn it_sets_properties_on_entities() {
+        let commands = Commands::new();
+        let (mut shard, session_id) = shard_with_session("commands-set", AccessLevel::GameMaster);
+        let item = shard.world.add_item(0x0EED, START_LOCATION);
+
+        let command_line = format!("set 0x{:08X} hue 0x21", item);
+        commands.execute(&mut shard, Caller::Session(session_id), &command_line);
+
+        assert_eq!(shard.world.items[&item].hue, 0x21);
+    }
+
+    #[test]
+    fn it_adjusts_properties_within_their_limits() {
+        let commands = Commands::new();
+        let (mut shard, session_id) =
+            shard_with_session("commands-adjust", AccessLevel::GameMaster);
+        let mobile = shard.world.add_mobile("Dave", 0x190, START_LOCATION);
+
+        let command_line = format!("adjust 0x{:08X} hits -250", mobile);
+        commands.execute(&mut shard, Caller::Session(session_id), &command_line);
+        assert_eq!(shard.world.mobiles[&mobile].hits, 0);
+
+        let command_line = format!("adjust 0x{:08X} anger 10", mobile);
+        commands.execute(&mut shard, Caller::Session(session_id), &command_line);
+        let command_line = format!("adjust 0x{:08X} hits 500", mobile);
+        commands.execute(&mut shard, Caller::Session(session_id), &command_line);
+        assert_eq!(shard.world.mobiles[&mobile].hits, 100);
+    }
+
+    #[test]
+    fn it_makes_accounts_up_to_below_the_callers_access_level() {
+        let commands = Commands::new();
+        let (mut shard, session_id) =
+            shard_with_session("commands-accounts", AccessLevel::Administrator);
+
+        commands.execute(&mut shard, Caller::Session(session_id), "account carol gm");
+        commands.execute(
+            &mut shard,
+            Caller::Session(session_id),
+            "account dave owner",
+        );
+        assert_eq!(
+            shard.accounts.get_mut("carol").unwrap().access_level,
+            AccessLevel::GameMaster
+        );
+        assert!(shard.accounts.get_mut("dave").is_none());
+
+        commands.execute(&mut shard, Caller::Console, "account dave owner");
+        assert_eq!(
+            shard.accounts.get_mut("dave").unwrap().access_level,
+            AccessLevel::Owner
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use chrono::prelude::*;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::accounts::AccessLevel;
+
+// Appends a line per command to the audit log, e.g.
+// "2023-11-20 18:04:12 alice (GameMaster) ran: go 1495 1629"
+pub fn record(
+    path: &str,
+    caller_name: &str,
+    access_level: AccessLevel,
+    outcome: &str,
+    command_line: &str,
+) {
+    let line = format!(
+        "{} {} ({:?}) {}: {}\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        caller_name,
+        access_level,
+        outcome,
+        command_line
+    );
+
+    if let Err(e) = append(path, &line) {
+        println!("Error writing to command audit log {}: {}", path, e);
+    }
+}
+
+fn append(path: &str, line: &str) -> std::io::Result<()> {
+    if let Some(directory) = Path::new(path).parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
@@ -0,0 +1,692 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use super::{argument, Caller, Command, CommandContext, CommandError, CommandResult, Commands};
+use crate::accounts::AccessLevel;
+use crate::autosave;
+use crate::combat;
+use crate::config;
+use crate::death;
+use crate::events::{self, Move};
+use crate::location::Location;
+use crate::scripts;
+use crate::sessions::SessionId;
+use crate::speech;
+use crate::state::{ItemProperty, MobileProperty, State, StateDelta};
+use crate::templates;
+use crate::world::{self, Serial};
+
+pub fn register(commands: &mut Commands) {
+    commands.register(Command {
+        name: "help",
+        aliases: &["commands"],
+        access_level: AccessLevel::Player,
+        usage: "",
+        description: "Lists the commands you can use.",
+        handler: help,
+    });
+    commands.register(Command {
+        name: "go",
+        aliases: &["teleport"],
+        access_level: AccessLevel::Counselor,
+        usage: "<x> <y> [z]",
+        description: "Teleports you to a location.",
+        handler: go,
+    });
+    commands.register(Command {
+        name: "where",
+        aliases: &[],
+        access_level: AccessLevel::Counselor,
+        usage: "",
+        description: "Tells you where you are.",
+        handler: where_,
+    });
+    commands.register(Command {
+        name: "add",
+        aliases: &[],
+        access_level: AccessLevel::GameMaster,
+        usage: "<item <graphic | template> [container] | mobile <body> [name] | mobile <template>>",
+        description: "Creates an item or mobile at your feet, or an item in a container.",
+        handler: add,
+    });
+    commands.register(Command {
+        name: "remove",
+        aliases: &["delete"],
+        access_level: AccessLevel::GameMaster,
+        usage: "<serial>",
+        description: "Deletes an item or mobile.",
+        handler: remove,
+    });
+    commands.register(Command {
+        name: "set",
+        aliases: &[],
+        access_level: AccessLevel::GameMaster,
+        usage: "<serial> <property> <value>",
+        description: "Changes a property of an item or mobile.",
+        handler: set,
+    });
+    commands.register(Command {
+        name: "adjust",
+        aliases: &[],
+        access_level: AccessLevel::GameMaster,
+        usage: "<serial> <property> <amount>",
+        description: "Raises or lowers a number property of an item or mobile, within its limits.",
+        handler: adjust,
+    });
+    commands.register(Command {
+        name: "damage",
+        aliases: &[],
+        access_level: AccessLevel::GameMaster,
+        usage: "<serial> <amount>",
+        description: "Hurts a mobile as if something had hit it, killing it if its hits run out.",
+        handler: damage,
+    });
+    commands.register(Command {
+        name: "resurrect",
+        aliases: &["res"],
+        access_level: AccessLevel::GameMaster,
+        usage: "<serial>",
+        description: "Brings a ghost back to life.",
+        handler: resurrect,
+    });
+    commands.register(Command {
+        name: "broadcast",
+        aliases: &["bc"],
+        access_level: AccessLevel::GameMaster,
+        usage: "<message>",
+        description: "Sends a message to everyone online.",
+        handler: broadcast,
+    });
+    commands.register(Command {
+        name: "online",
+        aliases: &["sessions"],
+        access_level: AccessLevel::Counselor,
+        usage: "",
+        description: "Lists everyone who is connected.",
+        handler: online,
+    });
+    commands.register(Command {
+        name: "kick",
+        aliases: &[],
+        access_level: AccessLevel::GameMaster,
+        usage: "<session id | account>",
+        description: "Disconnects someone.",
+        handler: kick,
+    });
+    commands.register(Command {
+        name: "account",
+        aliases: &[],
+        access_level: AccessLevel::Administrator,
+        usage: "<username> [access level]",
+        description:
+            "Creates an account with a random password, or changes an account's access level.",
+        handler: account,
+    });
+    commands.register(Command {
+        name: "timers",
+        aliases: &[],
+        access_level: AccessLevel::Administrator,
+        usage: "",
+        description: "Shows timer statistics.",
+        handler: timers,
+    });
+    commands.register(Command {
+        name: "reload",
+        aliases: &[],
+        access_level: AccessLevel::GameMaster,
+        usage: "",
+        description: "Reloads the config file, templates and scripts, keeping everyone connected.",
+        handler: reload,
+    });
+    commands.register(Command {
+        name: "save",
+        aliases: &[],
+        access_level: AccessLevel::Administrator,
+        usage: "[full]",
+        description: "Saves the world, or all of it with full, in the background.",
+        handler: save,
+    });
+    commands.register(Command {
+        name: "shutdown",
+        aliases: &[],
+        access_level: AccessLevel::Administrator,
+        usage: "[delay in seconds]",
+        description: "Warns everyone, then saves and shuts the server down.",
+        handler: shutdown,
+    });
+}
+
+fn help(context: &mut CommandContext, _args: &[&str]) -> CommandResult {
+    let builtin = context.commands.iter().map(|command| {
+        (
+            command.access_level,
+            command.name,
+            command.usage,
+            command.description,
+        )
+    });
+    // Script commands hidden by a builtin can't be run.
+    let scripted = context
+        .shard
+        .scripts
+        .commands()
+        .filter(|command| context.commands.find(&command.name).is_none())
+        .map(|command| {
+            (
+                command.access_level,
+                command.name.as_str(),
+                command.usage.as_str(),
+                command.description.as_str(),
+            )
+        });
+    let lines: Vec<String> = builtin
+        .chain(scripted)
+        .filter(|(access_level, ..)| context.access_level >= *access_level)
+        .map(|(_, name, usage, description)| format!("{} {} - {}", name, usage, description))
+        .collect();
+
+    for line in lines {
+        context.reply(&line);
+    }
+
+    Ok(())
+}
+
+fn go(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let x = argument(args, 0)?;
+    let y = argument(args, 1)?;
+    let z = if args.len() > 2 {
+        argument(args, 2)?
+    } else {
+        0
+    };
+
+    let mobile = context.caller_mobile()?;
+    let mut move_ = Move {
+        mobile: mobile.serial,
+        from: mobile.location,
+        to: Location { x, y, z },
+    };
+    if !events::publish(context.shard, &mut move_) {
+        return Err(CommandError::Failed(String::from("You can't go there.")));
+    }
+
+    context.caller_mobile()?.move_to(move_.to);
+
+    Ok(())
+}
+
+fn where_(context: &mut CommandContext, _args: &[&str]) -> CommandResult {
+    let location = context.caller_mobile()?.location;
+
+    context.reply(&format!(
+        "You are at {}, {}, {}",
+        location.x, location.y, location.z
+    ));
+
+    Ok(())
+}
+
+fn add(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let location = context.caller_mobile()?.location;
+
+    let serial = match args.first().copied() {
+        Some("item") => {
+            let container: Option<Serial> = match args.get(2) {
+                Some(_) => Some(argument(args, 2)?),
+                None => None,
+            };
+
+            let shard = &mut *context.shard;
+            let world = &mut shard.world;
+            let serial = match args.get(1).copied() {
+                Some(template) if shard.templates.item(template).is_some() => shard
+                    .templates
+                    .create_item(world, template, location)
+                    .map_err(CommandError::Failed)?,
+                _ => world.add_item(argument(args, 1)?, location),
+            };
+            if let Some(container) = container {
+                if let Err(message) = world.place_in(serial, container) {
+                    world.remove(serial);
+                    return Err(CommandError::Failed(message));
+                }
+            }
+            serial
+        }
+        Some("mobile") => {
+            let shard = &mut *context.shard;
+            if let Some(template) = args
+                .get(1)
+                .filter(|id| shard.templates.mobile(id).is_some())
+            {
+                shard
+                    .templates
+                    .create_mobile(&mut shard.world, template, location)
+                    .map_err(CommandError::Failed)?
+            } else {
+                let body = argument(args, 1)?;
+                let name = args.get(2..).unwrap_or_default().join(" ");
+                shard.world.add_mobile(&name, body, location)
+            }
+        }
+        _ => return Err(CommandError::Usage),
+    };
+
+    context.reply(&format!("Added 0x{:08X}", serial));
+
+    Ok(())
+}
+
+fn remove(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let serial: Serial = argument(args, 0)?;
+
+    let is_online_character = context
+        .shard
+        .sessions
+        .iter()
+        .any(|session| session.mobile == Some(serial));
+
+    if is_online_character {
+        return Err(CommandError::Failed(String::from(
+            "You can't remove the character of someone who is online.",
+        )));
+    }
+
+    if !context.shard.world.remove(serial) {
+        return Err(CommandError::Failed(format!(
+            "Nothing has serial 0x{:08X}",
+            serial
+        )));
+    }
+
+    context.reply(&format!("Removed 0x{:08X}", serial));
+
+    Ok(())
+}
+
+fn set(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    if args.len() < 3 {
+        return Err(CommandError::Usage);
+    }
+
+    let serial: Serial = argument(args, 0)?;
+    let property = args[1].to_lowercase();
+    let value = args[2..].join(" ");
+
+    let world = &mut context.shard.world;
+    let result = if world::is_item(serial) {
+        world
+            .item_mut(serial)
+            .map(|item| item.set_property(&property, &value))
+    } else {
+        world
+            .mobile_mut(serial)
+            .map(|mobile| mobile.set_property(&property, &value))
+    };
+
+    match result {
+        Some(Ok(())) => {
+            context.reply(&format!("Set {} to {}", property, value));
+            Ok(())
+        }
+        Some(Err(message)) => Err(CommandError::Failed(message)),
+        None => Err(CommandError::Failed(format!(
+            "Nothing has serial 0x{:08X}",
+            serial
+        ))),
+    }
+}
+
+fn adjust(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    if args.len() != 3 {
+        return Err(CommandError::Usage);
+    }
+
+    let serial: Serial = argument(args, 0)?;
+    let property = args[1].to_lowercase();
+    let delta: i32 = args[2].parse().map_err(|_| CommandError::Usage)?;
+
+    let world = &mut context.shard.world;
+    let changed = if world::is_item(serial) {
+        let property: ItemProperty = property.parse().map_err(CommandError::Failed)?;
+        world.item_mut(serial).map(|item| {
+            !item
+                .update_state(&[StateDelta::new(property, delta)])
+                .is_empty()
+        })
+    } else {
+        let property: MobileProperty = property.parse().map_err(CommandError::Failed)?;
+        world.mobile_mut(serial).map(|mobile| {
+            !mobile
+                .update_state(&[StateDelta::new(property, delta)])
+                .is_empty()
+        })
+    };
+
+    match changed {
+        Some(true) => {
+            context.reply(&format!("Adjusted {} by {}", property, delta));
+            Ok(())
+        }
+        Some(false) => Err(CommandError::Failed(format!(
+            "{} is already as far as it can go",
+            property
+        ))),
+        None => Err(CommandError::Failed(format!(
+            "Nothing has serial 0x{:08X}",
+            serial
+        ))),
+    }
+}
+
+fn damage(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let serial: Serial = argument(args, 0)?;
+    let amount = argument(args, 1)?;
+
+    let Some(mobile) = context.shard.world.mobile(serial) else {
+        return Err(CommandError::Failed(format!(
+            "There's no mobile with serial 0x{:08X}",
+            serial
+        )));
+    };
+    let name = mobile.name.clone();
+
+    let taken = combat::damage(context.shard, serial, None, amount);
+    context.reply(&format!("{} took {} damage", name, taken));
+
+    Ok(())
+}
+
+fn resurrect(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let serial: Serial = argument(args, 0)?;
+
+    let Some(mobile) = context.shard.world.mobile(serial) else {
+        return Err(CommandError::Failed(format!(
+            "There's no mobile with serial 0x{:08X}",
+            serial
+        )));
+    };
+    let name = mobile.name.clone();
+
+    if !death::resurrect(context.shard, serial) {
+        return Err(CommandError::Failed(format!("{} isn't dead", name)));
+    }
+    context.reply(&format!("Resurrected {}", name));
+
+    Ok(())
+}
+
+fn broadcast(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    if args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    speech::broadcast_system_message(&context.shard.sessions, &args.join(" "));
+
+    Ok(())
+}
+
+fn online(context: &mut CommandContext, _args: &[&str]) -> CommandResult {
+    let shard = &context.shard;
+
+    let mut lines: Vec<(SessionId, String)> = shard
+        .sessions
+        .iter()
+        .map(|session| {
+            let location = session
+                .mobile
+                .and_then(|serial| shard.world.mobile(serial))
+                .map(|mobile| {
+                    let location = mobile.location;
+                    format!("at {}, {}, {}", location.x, location.y, location.z)
+                })
+                .unwrap_or_else(|| String::from("not in game"));
+
+            let line = format!(
+                "{}: {} ({:?}) {}",
+                session.id,
+                session.account.as_deref().unwrap_or("not logged in"),
+                session.access_level,
+                location
+            );
+
+            (session.id, line)
+        })
+        .collect();
+    lines.sort();
+
+    context.reply(&format!("{} online", lines.len()));
+    for (_, line) in lines {
+        context.reply(&line);
+    }
+
+    Ok(())
+}
+
+fn kick(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let target = args.first().ok_or(CommandError::Usage)?;
+
+    let session = context.shard.sessions.iter().find(|session| {
+        target.parse() == Ok(session.id)
+            || session
+                .account
+                .as_deref()
+                .is_some_and(|account| account.eq_ignore_ascii_case(target))
+    });
+
+    let Some(session) = session else {
+        return Err(CommandError::Failed(format!(
+            "Nobody online matches {}",
+            target
+        )));
+    };
+
+    // Staff can't kick each other, only those below them. The console can
+    // kick anyone.
+    if let Caller::Session(_) = context.caller {
+        if session.access_level >= context.access_level {
+            return Err(CommandError::Failed(String::from(
+                "You can't kick someone with the same or higher access level.",
+            )));
+        }
+    }
+
+    session.send(speech::system_message_packet(
+        "You have been disconnected by staff.",
+    ));
+    session.disconnect();
+
+    let id = session.id;
+    context.reply(&format!("Kicked session {}", id));
+
+    Ok(())
+}
+
+fn account(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let username = *args.first().ok_or(CommandError::Usage)?;
+    let access_level = match args.get(1) {
+        Some(access_level) => Some(access_level.parse().map_err(CommandError::Failed)?),
+        None => None,
+    };
+
+    let shard = &mut *context.shard;
+    let current = shard
+        .accounts
+        .get_mut(username)
+        .map(|account| account.access_level);
+
+    // Staff can only manage accounts below them, and only up to below their
+    // own access level. The console can manage any, which is how the owner
+    // is made.
+    if let Caller::Session(_) = context.caller {
+        let too_high = |access_level: AccessLevel| access_level >= context.access_level;
+        if current.is_some_and(too_high) || access_level.is_some_and(too_high) {
+            return Err(CommandError::Failed(String::from(
+                "You can't manage accounts with the same or higher access level.",
+            )));
+        }
+    }
+
+    let Some(current) = current else {
+        let password: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        let access_level = access_level.unwrap_or(AccessLevel::Player);
+        shard.accounts.create(username, &password, access_level);
+        context.reply(&format!(
+            "Created {:?} account {} with password {}",
+            access_level, username, password
+        ));
+        return Ok(());
+    };
+
+    let Some(access_level) = access_level else {
+        context.reply(&format!("{} is a {:?} account", username, current));
+        return Ok(());
+    };
+    if let Some(account) = shard.accounts.get_mut(username) {
+        account.access_level = access_level;
+    }
+    // Whoever is logged in to it gets the new access level straight away.
+    for session in shard.sessions.iter_mut() {
+        if session
+            .account
+            .as_deref()
+            .is_some_and(|account| account.eq_ignore_ascii_case(username))
+        {
+            session.access_level = access_level;
+        }
+    }
+    context.reply(&format!("{} is now a {:?} account", username, access_level));
+
+    Ok(())
+}
+
+fn timers(context: &mut CommandContext, _args: &[&str]) -> CommandResult {
+    let stats = context.shard.timers.stats();
+
+    let registered = stats.registered.load(Ordering::Relaxed);
+    let pending = stats.pending.load(Ordering::Relaxed);
+    let executed = stats.executed.load(Ordering::Relaxed);
+    let cancelled = stats.cancelled.load(Ordering::Relaxed);
+    let skipped = stats.skipped.load(Ordering::Relaxed);
+    let running = stats.running.load(Ordering::Relaxed);
+    let panicked = stats.panicked.load(Ordering::Relaxed);
+
+    context.reply(&format!(
+        "Timers registered: {}, pending: {}, callbacks executed: {}, cancelled: {}, missed firings skipped: {}",
+        registered, pending, executed, cancelled, skipped
+    ));
+    context.reply(&format!(
+        "Callbacks running or waiting to run: {}, panicked: {}",
+        running, panicked
+    ));
+
+    if executed > 0 {
+        let total_lateness = stats.total_lateness.load(Ordering::Relaxed);
+        let worst_lateness = stats.worst_lateness.load(Ordering::Relaxed);
+        context.reply(&format!(
+            "Callbacks ran {} ms late on average, {} ms at worst",
+            total_lateness / executed as u64,
+            worst_lateness
+        ));
+    }
+
+    let autosave = match &context.shard.autosave {
+        Some(handle) if handle.is_active() => format!("Autosave is timer {}", handle.id()),
+        Some(_) => String::from("Autosave is saving now"),
+        None => String::from("Autosave is turned off"),
+    };
+    context.reply(&autosave);
+
+    Ok(())
+}
+
+fn reload(context: &mut CommandContext, _args: &[&str]) -> CommandResult {
+    // Nothing is swapped in until everything has been read and checked, so
+    // a typo anywhere leaves the server running as it was.
+    let mut config = config::load(config::CONFIG_PATH)
+        .map_err(|e| CommandError::Failed(format!("Nothing reloaded: {}", e)))?;
+    let templates = match templates::load(&config.templates_path) {
+        Ok(templates) => templates,
+        Err(errors) => {
+            for error in errors {
+                context.reply(&error);
+            }
+            return Err(CommandError::Failed(String::from(
+                "Nothing reloaded, as the templates above failed to load",
+            )));
+        }
+    };
+    let loaded = match context.shard.scripts.load(&config.scripts_path) {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            for error in errors {
+                context.reply(&error);
+            }
+            return Err(CommandError::Failed(String::from(
+                "Nothing reloaded, as the scripts above failed to load",
+            )));
+        }
+    };
+
+    let template_count = templates.len();
+    let script_count = loaded.len();
+    let kept = config.keep_startup_settings(&context.shard.config);
+    context.shard.config = config;
+    context.shard.templates = templates;
+    scripts::install(context.shard, loaded);
+
+    // Autosaves follow the new interval from now on.
+    if context.shard.config.autosave_interval_minutes == 0 {
+        autosave::stop(context.shard);
+    } else {
+        autosave::postpone(context.shard);
+    }
+
+    context.reply(&format!(
+        "Reloaded the config, {} templates and {} scripts",
+        template_count, script_count
+    ));
+    if !kept.is_empty() {
+        context.reply(&format!(
+            "{} only change on a restart, so the server is still using the old values",
+            kept.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+fn save(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let full = match args.first().copied() {
+        None => false,
+        Some("full") => true,
+        Some(_) => return Err(CommandError::Usage),
+    };
+
+    // The save thread reports on the console once it's done.
+    context.shard.save(full);
+    autosave::postpone(context.shard);
+
+    context.reply("Saving the world");
+
+    Ok(())
+}
+
+fn shutdown(context: &mut CommandContext, args: &[&str]) -> CommandResult {
+    let delay = if args.is_empty() {
+        0
+    } else {
+        argument(args, 0)?
+    };
+
+    context.shard.shutdown.request(Duration::from_secs(delay));
+
+    Ok(())
+}
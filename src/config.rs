@@ -0,0 +1,165 @@
+use serde::Deserialize;
+
+use crate::persistence::Backend;
+use std::fs;
+use std::io::ErrorKind;
+
+pub const CONFIG_PATH: &str = "config.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub command_prefix: String,
+    pub audit_log_path: String,
+    // Lets anyone log in with a username nobody has, making them a player
+    // account. Otherwise accounts are made with the account command.
+    pub auto_create_accounts: bool,
+    // Either flat_file or sqlite. Give save_path a .db extension for sqlite.
+    pub storage: Backend,
+    pub save_path: String,
+    // Set to 0 to turn autosaving off.
+    pub autosave_interval_minutes: u64,
+    pub autosave_warning_seconds: u64,
+    // Most saves only write what has changed since the last one. Every
+    // `saves_per_snapshot` saves the whole world is written instead.
+    pub saves_per_snapshot: u32,
+    pub backup_path: String,
+    // Every snapshot is backed up. The newest `backups_kept` are always kept,
+    // along with the newest backup from each of the last
+    // `hourly_backups_kept` hours and `daily_backups_kept` days.
+    pub backups_kept: usize,
+    pub hourly_backups_kept: usize,
+    pub daily_backups_kept: usize,
+    // How often the game loop handles what has come in from connections,
+    // the console and timers, in milliseconds.
+    pub tick_ms: u64,
+    // How many threads run timer callbacks. 0 runs them on the game loop.
+    pub timer_workers: usize,
+    // A timer whose callback panics is dropped, unless this is more than 0,
+    // in which case it's tried again `timer_panic_retry_ms` later, up to
+    // this many times in a row.
+    pub timer_panic_retries: u32,
+    pub timer_panic_retry_ms: u64,
+    // Every .rhai file in here is loaded at startup, in name order. A
+    // missing directory just means there are no scripts.
+    pub scripts_path: String,
+    // Every .toml file in here is read for item and mobile templates.
+    pub templates_path: String,
+    // How long corpses last before they rot away, along with whatever is
+    // still in them.
+    pub corpse_decay_minutes: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            command_prefix: String::from("["),
+            audit_log_path: String::from("logs/commands.log"),
+            auto_create_accounts: false,
+            storage: Backend::FlatFile,
+            save_path: String::from("saves/world.bin"),
+            autosave_interval_minutes: 30,
+            autosave_warning_seconds: 10,
+            saves_per_snapshot: 12,
+            backup_path: String::from("saves/backups"),
+            backups_kept: 5,
+            hourly_backups_kept: 24,
+            daily_backups_kept: 7,
+            tick_ms: 50,
+            timer_workers: 4,
+            timer_panic_retries: 0,
+            timer_panic_retry_ms: 1000,
+            scripts_path: String::from("scripts"),
+            templates_path: String::from("templates"),
+            corpse_decay_minutes: 7,
+        }
+    }
+}
+
+impl Config {
+    // The save and timer settings are only read at startup. Puts back any
+    // that differ from the `running` config's, returning their names, as
+    // changing them takes a restart.
+    pub fn keep_startup_settings(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut kept = vec![];
+
+        if self.storage != running.storage {
+            self.storage = running.storage;
+            kept.push("storage");
+        }
+        if self.save_path != running.save_path {
+            self.save_path = running.save_path.clone();
+            kept.push("save_path");
+        }
+        if self.timer_workers != running.timer_workers {
+            self.timer_workers = running.timer_workers;
+            kept.push("timer_workers");
+        }
+        if self.timer_panic_retries != running.timer_panic_retries {
+            self.timer_panic_retries = running.timer_panic_retries;
+            kept.push("timer_panic_retries");
+        }
+        if self.timer_panic_retry_ms != running.timer_panic_retry_ms {
+            self.timer_panic_retry_ms = running.timer_panic_retry_ms;
+            kept.push("timer_panic_retry_ms");
+        }
+
+        kept
+    }
+}
+
+// Reads the config file at `path`, falling back to the defaults for any
+// setting it doesn't mention. A missing file means "use all the defaults".
+pub fn load(path: &str) -> Result<Config, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(format!("{}: {}", path, e)),
+    };
+
+    toml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_uses_defaults_for_missing_settings() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.command_prefix, "[");
+    }
+
+    #[test]
+    fn it_reads_the_command_prefix() {
+        let config: Config = toml::from_str("command_prefix = \".\"").unwrap();
+        assert_eq!(config.command_prefix, ".");
+    }
+
+    #[test]
+    fn it_reads_the_storage_backend() {
+        let config: Config = toml::from_str("storage = \"sqlite\"").unwrap();
+        assert_eq!(config.storage, Backend::Sqlite);
+    }
+
+    #[test]
+    fn it_keeps_the_settings_only_read_at_startup() {
+        let running = Config::default();
+        let mut reloaded: Config =
+            toml::from_str("save_path = \"elsewhere.db\"\ntimer_workers = 8\ntick_ms = 25")
+                .unwrap();
+
+        assert_eq!(
+            reloaded.keep_startup_settings(&running),
+            vec!["save_path", "timer_workers"]
+        );
+        assert_eq!(reloaded.save_path, running.save_path);
+        assert_eq!(reloaded.timer_workers, running.timer_workers);
+        assert_eq!(reloaded.tick_ms, 25);
+    }
+
+    #[test]
+    fn it_rejects_unknown_settings() {
+        assert!(toml::from_str::<Config>("comand_prefix = \".\"").is_err());
+    }
+}
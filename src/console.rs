@@ -0,0 +1,24 @@
+use std::io::{self, BufRead};
+
+use crate::game_loop::{Inbox, Message};
+
+// Hands commands typed into the server's terminal to the game loop, which
+// runs them with full access. Returns once stdin is closed, e.g. when the
+// server has been started in the background, but the server keeps running.
+pub fn run(inbox: Inbox) {
+    println!("Console ready. Type help for a list of commands.");
+
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                println!("Error reading from console: {}", e);
+                break;
+            }
+        };
+
+        inbox.send(Message::Console(line));
+    }
+
+    println!("Console input closed");
+}
@@ -0,0 +1,522 @@
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::entity_timers::{self, Action, EntityTimer, Event};
+use crate::events::{self, Death};
+use crate::location::Location;
+use crate::sessions::SessionId;
+use crate::shard::Shard;
+use crate::state::{MobileChange, MobileProperty, State, StateDelta};
+use crate::tcp::packets;
+use crate::templates::Ai;
+use crate::timer::Repeat;
+use crate::world::{Mobile, Serial, World};
+
+// Corpses keep the body of whoever they were in their amount, which is how
+// clients know what to draw.
+const CORPSE_GRAPHIC: u16 = 0x2006;
+
+const MALE_BODY: u16 = 0x190;
+const FEMALE_BODY: u16 = 0x191;
+const MALE_GHOST: u16 = 0x192;
+const FEMALE_GHOST: u16 = 0x193;
+
+const ANKH_GRAPHICS: [u16; 6] = [0x0002, 0x0003, 0x0004, 0x0005, 0x1D97, 0x1D98];
+
+// How close a ghost has to be to a healer or an ankh to be resurrected.
+const RESURRECTION_RANGE: u16 = 3;
+
+// How long after hurting a mobile something still shares in killing it, in
+// milliseconds.
+const CREDIT_WINDOW: i64 = 2 * 60 * 1000;
+
+// Tells the client's answer to the resurrection gump apart from any other.
+const RESURRECTION_GUMP: u32 = 0x2C01;
+const ACCEPT_BUTTON: u32 = 1;
+
+const RESURRECTION_LAYOUT: &str = "{ page 0 }\
+    { resizepic 0 0 2600 400 300 }\
+    { text 50 40 0 0 }\
+    { text 50 80 0 1 }\
+    { text 50 110 0 2 }\
+    { button 50 200 4005 4007 1 0 1 }\
+    { text 90 200 0 3 }\
+    { button 50 230 4005 4007 1 0 2 }\
+    { text 90 230 0 4 }";
+
+const RESURRECTION_TEXT: [&str; 5] = [
+    "Resurrection",
+    "It is possible for you to be resurrected now.",
+    "Do you wish to try?",
+    "Yes, let me live again.",
+    "No, I will stay a ghost.",
+];
+
+#[derive(Default)]
+pub struct Deaths {
+    // When each mobile was last hurt by each other mobile.
+    attackers: HashMap<Serial, HashMap<Serial, i64>>,
+    // Ghosts that have been offered resurrection and haven't answered yet.
+    offers: HashSet<Serial>,
+}
+
+// Remembers who hurt a mobile, so they share the credit if it dies.
+pub fn record_attack(shard: &mut Shard, target: Serial, source: Serial) {
+    let now = shard.timers.now();
+    let attackers = shard.deaths.attackers.entry(target).or_default();
+    attackers.retain(|_, &mut when| now - when <= CREDIT_WINDOW);
+    attackers.insert(source, now);
+}
+
+// Kills every mobile whose hits have dropped to 0 since the last tick,
+// however that happened: a blow, a command, a script or a timer. Whoever
+// hurt it last is the killer. The game loop calls this at the end of every
+// tick, before the changes are sent out.
+pub fn reap(shard: &mut Shard) {
+    for serial in shard.world.touched_mobiles() {
+        // Looking at the changes isn't a change to save, so this goes around
+        // World::mobile_mut.
+        let Some(mobile) = shard.world.mobiles.get_mut(&serial) else {
+            continue;
+        };
+        // Ghosts are already dead, whatever their hits are set to.
+        if mobile.hits > 0 || is_ghost(mobile) || !mobile.dirty().contains(MobileChange::Hits) {
+            continue;
+        }
+
+        let killer = shard.deaths.attackers.get(&serial).and_then(|attackers| {
+            attackers
+                .iter()
+                .max_by_key(|&(_, &when)| when)
+                .map(|(&attacker, _)| attacker)
+        });
+        let mut death = Death {
+            mobile: serial,
+            killer,
+        };
+        events::publish(shard, &mut death);
+    }
+}
+
+// Turns a mobile whose hits have run out into a corpse holding everything
+// it had. Players' characters are left behind as ghosts; everything else
+// is removed.
+pub fn die(shard: &mut Shard, serial: Serial, killer: Option<Serial>) {
+    credit_killers(shard, serial, killer);
+
+    let Some(mobile) = shard.world.mobile(serial) else {
+        return;
+    };
+    let name = mobile.name.clone();
+    let (body, hue, location) = (mobile.body, mobile.hue, mobile.location);
+
+    let corpse = shard.world.add_item(CORPSE_GRAPHIC, location);
+    if let Some(item) = shard.world.item_mut(corpse) {
+        item.name = Some(format!("corpse of {}", name));
+        item.amount = body;
+        item.hue = hue;
+        item.decays_at = Some(decay_time(shard.config.corpse_decay_minutes));
+    }
+    for item in shard.world.contents(serial) {
+        let _ = shard.world.place_in(item, corpse);
+    }
+    start_decay(shard, corpse);
+
+    if !is_player_character(shard, serial) {
+        shard.world.remove(serial);
+        return;
+    }
+
+    let Some(mobile) = shard.world.mobile_mut(serial) else {
+        return;
+    };
+    mobile.set_body(if body == FEMALE_BODY {
+        FEMALE_GHOST
+    } else {
+        MALE_GHOST
+    });
+    if mobile.war_mode {
+        mobile.set_war_mode(false);
+    }
+    if let Some(session) = shard.mobile_session(serial) {
+        session.send(packets::death_status_packet(true));
+    }
+}
+
+// Everything that hurt the mobile recently shares the kill, along with
+// whatever dealt the last blow.
+fn credit_killers(shard: &mut Shard, serial: Serial, killer: Option<Serial>) {
+    let now = shard.timers.now();
+    let mut killers: HashSet<Serial> = shard
+        .deaths
+        .attackers
+        .remove(&serial)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|&(_, when)| now - when <= CREDIT_WINDOW)
+        .map(|(attacker, _)| attacker)
+        .collect();
+    killers.extend(killer);
+    killers.remove(&serial);
+
+    for killer in killers {
+        if let Some(mobile) = shard.world.mobile_mut(killer) {
+            mobile.kills += 1;
+        }
+    }
+}
+
+// Rots the item away, along with whatever is still in it, once its decay
+// time comes.
+fn start_decay(shard: &mut Shard, serial: Serial) {
+    let Some(decays_at) = shard
+        .world
+        .items
+        .get(&serial)
+        .and_then(|item| item.decays_at)
+    else {
+        return;
+    };
+    entity_timers::start(
+        shard,
+        EntityTimer {
+            serial,
+            repeat: Repeat::Once,
+            interval: 0,
+            delay: (decays_at - Utc::now().timestamp_millis()).max(0),
+            action: Action::Event(Arc::new(Decay)),
+        },
+    );
+}
+
+struct Decay;
+
+impl Event for Decay {
+    fn fire(&self, world: &mut World, serial: Serial) {
+        world.remove(serial);
+    }
+}
+
+// Timers don't outlive the server, so anything that was decaying when it
+// stopped starts again at boot. Whatever was due while the server was down
+// goes straight away. Corpses from saves older than decay times start
+// rotting from scratch.
+pub fn start_decay_all(shard: &mut Shard) {
+    let undated: Vec<Serial> = shard
+        .world
+        .items
+        .iter()
+        .filter(|(_, item)| {
+            item.graphic == CORPSE_GRAPHIC && item.parent.is_none() && item.decays_at.is_none()
+        })
+        .map(|(&serial, _)| serial)
+        .collect();
+    let decays_at = decay_time(shard.config.corpse_decay_minutes);
+    for serial in undated {
+        if let Some(item) = shard.world.item_mut(serial) {
+            item.decays_at = Some(decays_at);
+        }
+    }
+
+    let decaying: Vec<Serial> = shard
+        .world
+        .items
+        .iter()
+        .filter(|(_, item)| item.decays_at.is_some())
+        .map(|(&serial, _)| serial)
+        .collect();
+    for serial in decaying {
+        start_decay(shard, serial);
+    }
+}
+
+fn decay_time(minutes: u64) -> i64 {
+    Utc::now().timestamp_millis() + minutes as i64 * 60 * 1000
+}
+
+// Ghosts who come close to a healer are offered resurrection.
+pub fn approach(shard: &mut Shard, serial: Serial, location: Location) {
+    let Some(ghost) = shard.world.mobile(serial) else {
+        return;
+    };
+    if ghost.hits > 0 {
+        return;
+    }
+
+    let near_healer = shard.world.mobiles.values().any(|mobile| {
+        mobile.hits > 0
+            && is_healer(shard, mobile)
+            && mobile.location.in_range(&location, RESURRECTION_RANGE)
+    });
+    if near_healer {
+        offer(shard, serial);
+    }
+}
+
+// Ghosts can also resurrect themselves by using an ankh.
+pub fn use_ankh(shard: &mut Shard, serial: Serial, target: Serial) {
+    let (Some(ghost), Some(ankh)) = (shard.world.mobile(serial), shard.world.items.get(&target))
+    else {
+        return;
+    };
+    if ghost.hits > 0
+        || !ANKH_GRAPHICS.contains(&ankh.graphic)
+        || ankh.parent.is_some()
+        || !ankh.location.in_range(&ghost.location, RESURRECTION_RANGE)
+    {
+        return;
+    }
+
+    offer(shard, serial);
+}
+
+// Asks the ghost's player whether they want to be resurrected, unless
+// they've already been asked.
+fn offer(shard: &mut Shard, serial: Serial) {
+    if shard.mobile_session(serial).is_none() || !shard.deaths.offers.insert(serial) {
+        return;
+    }
+
+    if let Some(session) = shard.mobile_session(serial) {
+        session.send(packets::gump_packet(
+            serial,
+            RESURRECTION_GUMP,
+            100,
+            100,
+            RESURRECTION_LAYOUT,
+            &RESURRECTION_TEXT,
+        ));
+    }
+}
+
+// A player closed a gump. Only the resurrection gump does anything so far.
+pub fn answer_gump(shard: &mut Shard, session_id: SessionId, gump_id: u32, button: u32) {
+    if gump_id != RESURRECTION_GUMP {
+        return;
+    }
+    let Some(serial) = shard
+        .sessions
+        .get(session_id)
+        .and_then(|session| session.mobile)
+    else {
+        return;
+    };
+    // Only ghosts who were asked get to answer.
+    if !shard.deaths.offers.remove(&serial) {
+        return;
+    }
+
+    if button == ACCEPT_BUTTON {
+        resurrect(shard, serial);
+    }
+}
+
+// Brings a ghost back to life with a tenth of its hits. Returns false if
+// it wasn't dead.
+pub fn resurrect(shard: &mut Shard, serial: Serial) -> bool {
+    let Some(mobile) = shard.world.mobile_mut(serial) else {
+        return false;
+    };
+    if mobile.hits > 0 {
+        return false;
+    }
+
+    mobile.set_body(if mobile.body == FEMALE_GHOST {
+        FEMALE_BODY
+    } else {
+        MALE_BODY
+    });
+    let hits = (mobile.max_hits / 10).max(1);
+    mobile.update_state(&[StateDelta::new(MobileProperty::Hits, hits as i32)]);
+    shard.deaths.offers.remove(&serial);
+
+    if let Some(session) = shard.mobile_session(serial) {
+        session.send(packets::death_status_packet(false));
+    }
+
+    true
+}
+
+fn is_healer(shard: &Shard, mobile: &Mobile) -> bool {
+    mobile
+        .template
+        .as_deref()
+        .and_then(|id| shard.templates.mobile(id))
+        .is_some_and(|template| template.ai == Ai::Healer)
+}
+
+fn is_ghost(mobile: &Mobile) -> bool {
+    mobile.body == MALE_GHOST || mobile.body == FEMALE_GHOST
+}
+
+fn is_player_character(shard: &Shard, serial: Serial) -> bool {
+    shard
+        .accounts
+        .iter()
+        .any(|account| account.character == Some(serial))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::AccessLevel;
+    use crate::combat;
+    use crate::commands::{Caller, Commands};
+    use crate::game_loop;
+    use crate::location::START_LOCATION;
+    use crate::persistence;
+    use crate::shard::tests::{shard, shard_with_clock};
+    use crate::templates;
+    use async_std::channel::{self, Receiver};
+    use std::fs;
+
+    // Bob, played by someone who is online.
+    fn player(shard: &mut Shard) -> (Serial, SessionId, Receiver<Vec<u8>>) {
+        let (outgoing_tx, outgoing_rx) = channel::unbounded();
+        let session_id = shard.sessions.add(outgoing_tx);
+        let bob = shard.world.add_mobile("Bob", FEMALE_BODY, START_LOCATION);
+        shard.sessions.get_mut(session_id).unwrap().mobile = Some(bob);
+        shard
+            .accounts
+            .create("bob", "secret", AccessLevel::Player)
+            .unwrap()
+            .character = Some(bob);
+        (bob, session_id, outgoing_rx)
+    }
+
+    #[test]
+    fn it_leaves_players_as_ghosts_beside_their_corpses() {
+        let (mut shard, _) = shard("death-player");
+        let (bob, _, outgoing_rx) = player(&mut shard);
+        let robe = shard.world.add_item(0x1F03, START_LOCATION);
+        shard.world.place_in(robe, bob).unwrap();
+        let orc = shard.world.add_mobile("an orc", 0x11, START_LOCATION);
+        let other_orc = shard.world.add_mobile("an orc", 0x11, START_LOCATION);
+
+        combat::damage(&mut shard, bob, Some(other_orc), 50);
+        combat::damage(&mut shard, bob, Some(orc), 50);
+        reap(&mut shard);
+
+        let mobile = shard.world.mobile(bob).unwrap();
+        assert_eq!((mobile.hits, mobile.body), (0, FEMALE_GHOST));
+        let corpse = shard.world.items[&robe].parent.unwrap();
+        let corpse = &shard.world.items[&corpse];
+        assert_eq!(
+            (corpse.graphic, corpse.amount),
+            (CORPSE_GRAPHIC, FEMALE_BODY)
+        );
+        assert_eq!(corpse.name.as_deref(), Some("corpse of Bob"));
+        assert_eq!(shard.world.mobile(orc).unwrap().kills, 1);
+        assert_eq!(shard.world.mobile(other_orc).unwrap().kills, 1);
+        assert!(std::iter::from_fn(|| outgoing_rx.try_recv().ok())
+            .any(|packet| packet == packets::death_status_packet(true)));
+
+        assert!(resurrect(&mut shard, bob));
+        let mobile = shard.world.mobile(bob).unwrap();
+        assert_eq!((mobile.hits, mobile.body), (10, FEMALE_BODY));
+        assert!(!resurrect(&mut shard, bob));
+    }
+
+    #[test]
+    fn it_removes_dead_creatures_and_rots_their_corpses() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("death-creature");
+        let commands = Commands::new();
+        let orc = shard.world.add_mobile("an orc", 0x11, START_LOCATION);
+        let dagger = shard.world.add_item(0x0F51, START_LOCATION);
+        shard.world.place_in(dagger, orc).unwrap();
+
+        combat::damage(&mut shard, orc, None, 100);
+        reap(&mut shard);
+        assert!(shard.world.mobile(orc).is_none());
+        let corpse = shard.world.items[&dagger].parent.unwrap();
+
+        clock.advance(7 * 60 * 1000);
+        shard.timers.flush();
+        game_loop::tick(&mut shard, &messages_rx, &commands);
+        assert!(!shard.world.exists(corpse));
+        assert!(!shard.world.exists(dagger));
+    }
+
+    #[test]
+    fn it_carries_on_decaying_after_a_restart() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("death-restart");
+        let overdue = shard.world.add_item(CORPSE_GRAPHIC, START_LOCATION);
+        shard.world.item_mut(overdue).unwrap().decays_at = Some(Utc::now().timestamp_millis());
+        // Saved before corpses had decay times.
+        let undated = shard.world.add_item(CORPSE_GRAPHIC, START_LOCATION);
+
+        start_decay_all(&mut shard);
+        clock.advance(1);
+        shard.timers.flush();
+        game_loop::tick(&mut shard, &messages_rx, &Commands::new());
+
+        assert!(!shard.world.exists(overdue));
+        assert!(shard.world.items[&undated].decays_at.is_some());
+    }
+
+    #[test]
+    fn it_kills_mobiles_however_their_hits_run_out() {
+        let (mut shard, _) = shard("death-anyhow");
+        let commands = Commands::new();
+        let (bob, _, _) = player(&mut shard);
+        let orc = shard.world.add_mobile("an orc", 0x11, START_LOCATION);
+        let command_line = format!("set 0x{:08X} hits 0", orc);
+        commands.execute(&mut shard, Caller::Console, &command_line);
+        shard
+            .world
+            .mobile_mut(bob)
+            .unwrap()
+            .update_state(&[StateDelta::new(MobileProperty::Hits, -100)]);
+
+        reap(&mut shard);
+        assert!(shard.world.mobile(orc).is_none());
+        assert_eq!(shard.world.mobile(bob).unwrap().body, FEMALE_GHOST);
+
+        // Ghosts don't die again.
+        let items = shard.world.items.len();
+        shard
+            .world
+            .mobile_mut(bob)
+            .unwrap()
+            .set_property("hits", "0")
+            .unwrap();
+        reap(&mut shard);
+        assert_eq!(shard.world.items.len(), items);
+    }
+
+    #[test]
+    fn it_only_resurrects_ghosts_who_were_offered_it() {
+        let (mut shard, _) = shard("death-offers");
+        let directory = persistence::tests::empty_directory("death-offers-templates");
+        fs::write(
+            directory.join("mobiles.toml"),
+            "[mobile.healer]\nname = \"a healer\"\nbody = 0x190\nai = \"healer\"",
+        )
+        .unwrap();
+        shard.templates = templates::load(directory.to_str().unwrap()).unwrap();
+        let mut location = START_LOCATION;
+        location.x += 10;
+        shard
+            .templates
+            .create_mobile(&mut shard.world, "healer", location)
+            .unwrap();
+        let (bob, session_id, _) = player(&mut shard);
+        combat::damage(&mut shard, bob, None, 100);
+        reap(&mut shard);
+
+        answer_gump(&mut shard, session_id, RESURRECTION_GUMP, ACCEPT_BUTTON);
+        assert_eq!(shard.world.mobile(bob).unwrap().hits, 0);
+
+        // Walking up to the healer brings up the gump.
+        approach(&mut shard, bob, START_LOCATION);
+        assert!(shard.deaths.offers.is_empty());
+        approach(&mut shard, bob, location);
+        answer_gump(&mut shard, session_id, RESURRECTION_GUMP, 2);
+        assert_eq!(shard.world.mobile(bob).unwrap().hits, 0);
+
+        approach(&mut shard, bob, location);
+        answer_gump(&mut shard, session_id, RESURRECTION_GUMP, ACCEPT_BUTTON);
+        assert_eq!(shard.world.mobile(bob).unwrap().hits, 10);
+    }
+}
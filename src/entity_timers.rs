@@ -0,0 +1,239 @@
+use std::sync::{Arc, OnceLock};
+
+use crate::events::{self, TimerExpired};
+use crate::shard::Shard;
+use crate::state::{ItemProperty, MobileProperty, State, StateDelta};
+use crate::timer::{Late, Repeat, Timer, TimerHandle};
+use crate::world::{self, Serial, World};
+
+// Something that happens to a mobile or item when its timer fires. It's
+// handed the whole world, as some events do more than change the entity,
+// e.g. removing it along with its contents.
+pub trait Event: Send + Sync {
+    fn fire(&self, world: &mut World, serial: Serial);
+}
+
+// Each firing hands a copy of the action to the game loop, so events are
+// shared rather than copied.
+#[derive(Clone)]
+pub enum Action {
+    MobileDeltas(Vec<StateDelta<MobileProperty>>),
+    // Nothing in the game changes an item's properties on a timer yet.
+    #[allow(dead_code)]
+    ItemDeltas(Vec<StateDelta<ItemProperty>>),
+    Event(Arc<dyn Event>),
+}
+
+// A timer that acts on whichever entity has `serial` in the world at the
+// time it fires, rather than on a copy of it.
+pub struct EntityTimer {
+    pub serial: Serial,
+    pub repeat: Repeat,
+    pub interval: i64,
+    pub delay: i64,
+    pub action: Action,
+}
+
+// Starts a timer for an entity, which is cancelled along with any others
+// the entity has when the entity is removed. Returns None if nothing has
+// the serial, or if the deltas are for the other kind of entity.
+pub fn start(shard: &mut Shard, timer: EntityTimer) -> Option<TimerHandle> {
+    let applies = match timer.action {
+        Action::MobileDeltas(_) => shard.world.mobile(timer.serial).is_some(),
+        Action::ItemDeltas(_) => shard.world.items.contains_key(&timer.serial),
+        Action::Event(_) => shard.world.exists(timer.serial),
+    };
+    if !applies {
+        return None;
+    }
+
+    let serial = timer.serial;
+    let action = timer.action;
+    let inbox = shard.inbox.clone();
+    // Only known once the timer is registered, which is always before the
+    // game loop gets around to running a firing.
+    let registered = Arc::new(OnceLock::<TimerHandle>::new());
+    let firing_handle = Arc::clone(&registered);
+
+    let handle = shard.timers.register(Timer {
+        repeat: timer.repeat,
+        late: Late::Skip,
+        interval: timer.interval,
+        next: shard.timers.now() + timer.delay,
+        callback: Box::new(move || {
+            let action = action.clone();
+            let handle = Arc::clone(&firing_handle);
+            inbox.run(move |shard| {
+                // It may have been cancelled while the firing was queued.
+                let Some(handle) = handle.get().filter(|handle| !handle.is_cancelled()) else {
+                    return;
+                };
+                let mut expired = TimerExpired {
+                    timer: handle.id(),
+                    serial,
+                };
+                if events::publish(shard, &mut expired) {
+                    deliver(&mut shard.world, serial, &action);
+                }
+            });
+        }),
+    });
+    let _ = registered.set(handle.clone());
+
+    shard.world.own_timer(serial, handle.clone());
+    Some(handle)
+}
+
+fn deliver(world: &mut World, serial: Serial, action: &Action) {
+    if world::is_item(serial) {
+        if !world.items.contains_key(&serial) {
+            return;
+        }
+    } else if world.mobile(serial).is_none_or(|mobile| mobile.hits == 0) {
+        // The dead's stats stay as they are until they're resurrected.
+        return;
+    }
+
+    match action {
+        Action::MobileDeltas(deltas) => {
+            if let Some(mobile) = world.mobile_mut(serial) {
+                mobile.update_state(deltas);
+            }
+        }
+        Action::ItemDeltas(deltas) => {
+            if let Some(item) = world.item_mut(serial) {
+                item.update_state(deltas);
+            }
+        }
+        Action::Event(event) => event.fire(world, serial),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Commands;
+    use crate::game_loop;
+    use crate::location::START_LOCATION;
+    use crate::shard::tests::shard_with_clock;
+
+    struct Rename;
+
+    impl Event for Rename {
+        fn fire(&self, world: &mut World, serial: Serial) {
+            world.mobile_mut(serial).unwrap().name.push('!');
+        }
+    }
+
+    #[test]
+    fn it_changes_the_live_entity() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("entity-timers-live");
+        let mobile = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let gold = shard.world.add_item(0x0EED, START_LOCATION);
+        let deltas = EntityTimer {
+            serial: mobile,
+            repeat: Repeat::Times(2),
+            interval: 1000,
+            delay: 1000,
+            action: Action::MobileDeltas(vec![StateDelta::new(MobileProperty::Hits, -1)]),
+        };
+        let event = EntityTimer {
+            serial: mobile,
+            repeat: Repeat::Once,
+            interval: 0,
+            delay: 500,
+            action: Action::Event(Arc::new(Rename)),
+        };
+        let item_deltas = EntityTimer {
+            serial: gold,
+            repeat: Repeat::Once,
+            interval: 0,
+            delay: 1000,
+            action: Action::ItemDeltas(vec![StateDelta::new(ItemProperty::Amount, 4)]),
+        };
+        assert!(start(&mut shard, deltas).is_some());
+        assert!(start(&mut shard, event).is_some());
+        assert!(start(&mut shard, item_deltas).is_some());
+
+        // The timers only hand their work to the game loop.
+        let commands = Commands::new();
+        for _ in 0..4 {
+            clock.advance(500);
+            shard.timers.flush();
+            game_loop::tick(&mut shard, &messages_rx, &commands);
+        }
+
+        let mobile = shard.world.mobile(mobile).unwrap();
+        assert_eq!(mobile.hits, 98);
+        assert_eq!(mobile.name, "Bob!");
+        assert_eq!(shard.world.items[&gold].amount, 5);
+    }
+
+    #[test]
+    fn it_drops_firings_for_cancelled_timers_and_the_dead() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("entity-timers-stale");
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let orc = shard.world.add_mobile("an orc", 0x11, START_LOCATION);
+        shard.world.mobile_mut(bob).unwrap().hits = 50;
+        shard.world.mobile_mut(orc).unwrap().hits = 0;
+        let heal = |serial| EntityTimer {
+            serial,
+            repeat: Repeat::Forever,
+            interval: 1000,
+            delay: 1000,
+            action: Action::MobileDeltas(vec![StateDelta::new(MobileProperty::Hits, 1)]),
+        };
+        let handle = start(&mut shard, heal(bob)).unwrap();
+        start(&mut shard, heal(orc)).unwrap();
+
+        // The firing is queued for the game loop, then the timer cancelled.
+        clock.advance(1000);
+        shard.timers.flush();
+        handle.cancel();
+        game_loop::tick(&mut shard, &messages_rx, &Commands::new());
+
+        assert_eq!(shard.world.mobile(bob).unwrap().hits, 50);
+        assert_eq!(shard.world.mobile(orc).unwrap().hits, 0);
+    }
+
+    #[test]
+    fn it_cancels_an_entitys_timers_when_it_is_removed() {
+        let (mut shard, _, _) = shard_with_clock("entity-timers-removed");
+        let backpack = shard.world.add_item(0x0E75, START_LOCATION);
+        let gold = shard.world.add_item(0x0EED, START_LOCATION);
+        shard.world.place_in(gold, backpack).unwrap();
+
+        let handle = start(
+            &mut shard,
+            EntityTimer {
+                serial: gold,
+                repeat: Repeat::Times(10),
+                interval: 1000,
+                delay: 1000,
+                action: Action::ItemDeltas(vec![StateDelta::new(ItemProperty::Amount, 1)]),
+            },
+        )
+        .unwrap();
+        shard.world.remove(backpack);
+
+        assert!(!handle.is_active());
+    }
+
+    #[test]
+    fn it_only_starts_timers_for_entities_that_exist() {
+        let (mut shard, _, _) = shard_with_clock("entity-timers-missing");
+        let mobile = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let timer = |serial, action| EntityTimer {
+            serial,
+            repeat: Repeat::Once,
+            interval: 0,
+            delay: 0,
+            action,
+        };
+
+        let missing = timer(0x40000123, Action::ItemDeltas(vec![]));
+        assert!(start(&mut shard, missing).is_none());
+        let wrong_kind = timer(mobile, Action::ItemDeltas(vec![]));
+        assert!(start(&mut shard, wrong_kind).is_none());
+    }
+}
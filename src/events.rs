@@ -0,0 +1,204 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::combat;
+use crate::death;
+use crate::shard::Shard;
+use crate::skills;
+
+mod kinds;
+
+pub use kinds::*;
+
+// Something that happens in the game that other parts of the server might
+// want to react to. Events that haven't happened yet, like a mobile about
+// to take damage, can be cancelled by their subscribers.
+pub trait GameEvent: 'static {
+    const CANCELLABLE: bool;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    Continue,
+    // Stops the event happening, and any later subscribers hearing about
+    // it. Ignored for events that can't be cancelled.
+    Cancel,
+}
+
+// Subscribers run in order of priority, lowest first, with 0 as the usual
+// priority. Ties run in the order they subscribed. Subscribers that only
+// watch run late, to see what the others decided.
+pub const LATE: i32 = 100;
+
+type Handler<E> = Arc<dyn Fn(&mut Shard, &mut E) -> Flow + Send + Sync>;
+
+// Identifies a subscriber, for unsubscribing it.
+pub type SubscriptionId = u64;
+
+struct Subscriber {
+    id: SubscriptionId,
+    priority: i32,
+    // A Handler<E> for the event this subscriber is listed under.
+    handler: Box<dyn Any + Send + Sync>,
+}
+
+#[derive(Default)]
+pub struct Events {
+    subscribers: HashMap<TypeId, Vec<Subscriber>>,
+    next_id: SubscriptionId,
+}
+
+impl Events {
+    pub fn subscribe<E: GameEvent>(
+        &mut self,
+        priority: i32,
+        handler: impl Fn(&mut Shard, &mut E) -> Flow + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let subscribers = self.subscribers.entry(TypeId::of::<E>()).or_default();
+        let handler: Handler<E> = Arc::new(handler);
+        let index = subscribers.partition_point(|subscriber| subscriber.priority <= priority);
+        subscribers.insert(
+            index,
+            Subscriber {
+                id,
+                priority,
+                handler: Box::new(handler),
+            },
+        );
+
+        id
+    }
+
+    // An event that's being published when its subscriber is removed still
+    // goes to it.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        for subscribers in self.subscribers.values_mut() {
+            if let Some(index) = subscribers
+                .iter()
+                .position(|subscriber| subscriber.id == id)
+            {
+                subscribers.remove(index);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn handlers<E: GameEvent>(&self) -> Vec<Handler<E>> {
+        self.subscribers
+            .get(&TypeId::of::<E>())
+            .into_iter()
+            .flatten()
+            .filter_map(|subscriber| subscriber.handler.downcast_ref::<Handler<E>>())
+            .cloned()
+            .collect()
+    }
+}
+
+// Tells the event's subscribers about it. Subscribers can change the event,
+// e.g. to lower the damage, so callers should read it back afterwards.
+// Returns false if a subscriber cancelled it.
+//
+// Subscribers get the whole shard, so they can subscribe others or publish
+// events of their own. Those only hear about later events.
+pub fn publish<E: GameEvent>(shard: &mut Shard, event: &mut E) -> bool {
+    for handler in shard.events.handlers::<E>() {
+        if handler(shard, event) == Flow::Cancel && E::CANCELLABLE {
+            return false;
+        }
+    }
+
+    true
+}
+
+// How the server itself reacts to events.
+pub fn subscribe_builtins(events: &mut Events) {
+    events.subscribe(LATE, |shard, login: &mut Login| {
+        if let Some(mobile) = shard.world.mobile(login.mobile) {
+            println!("{} entered the world", mobile.name);
+        }
+        skills::send_skill_list(shard, login.session_id);
+        Flow::Continue
+    });
+    events.subscribe(LATE, |shard, damage: &mut Damage| {
+        if let Some(source) = damage.source {
+            death::record_attack(shard, damage.target, source);
+        }
+        Flow::Continue
+    });
+    events.subscribe(LATE, |shard, death: &mut Death| {
+        combat::stop_all(shard, death.mobile);
+        death::die(shard, death.mobile, death.killer);
+        Flow::Continue
+    });
+    events.subscribe(LATE, |shard, move_: &mut Move| {
+        death::approach(shard, move_.mobile, move_.to);
+        Flow::Continue
+    });
+    events.subscribe(LATE, |shard, use_: &mut Use| {
+        death::use_ankh(shard, use_.mobile, use_.target);
+        Flow::Continue
+    });
+    events.subscribe(LATE, |shard, logout: &mut Logout| {
+        if let Some(mobile) = shard.world.mobile(logout.mobile) {
+            println!("{} left the world", mobile.name);
+        }
+        Flow::Continue
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+    use crate::shard::tests::shard;
+
+    #[test]
+    fn it_runs_subscribers_in_priority_order() {
+        let (mut shard, _) = shard("events-priority");
+        let mobile = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+
+        shard.events.subscribe(LATE, |_, damage: &mut Damage| {
+            damage.amount += 1;
+            Flow::Continue
+        });
+        shard.events.subscribe(-100, |_, damage: &mut Damage| {
+            damage.amount *= 2;
+            Flow::Continue
+        });
+
+        let mut damage = Damage {
+            target: mobile,
+            source: None,
+            amount: 5,
+        };
+        assert!(publish(&mut shard, &mut damage));
+        assert_eq!(damage.amount, 11);
+    }
+
+    #[test]
+    fn it_only_cancels_cancellable_events() {
+        let (mut shard, _) = shard("events-cancel");
+        let mobile = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+
+        shard.events.subscribe(0, |_, _: &mut Damage| Flow::Cancel);
+        shard.events.subscribe(0, |_, _: &mut Death| Flow::Cancel);
+
+        let mut damage = Damage {
+            target: mobile,
+            source: None,
+            amount: 5,
+        };
+        assert!(!publish(&mut shard, &mut damage));
+        let mut death = Death {
+            mobile,
+            killer: None,
+        };
+        assert!(publish(&mut shard, &mut death));
+    }
+}
@@ -0,0 +1,134 @@
+use super::GameEvent;
+use crate::location::Location;
+use crate::sessions::SessionId;
+use crate::skills::SkillId;
+use crate::speech;
+use crate::timer::TimerId;
+use crate::world::Serial;
+
+// A player's character has entered the world.
+pub struct Login {
+    pub session_id: SessionId,
+    pub mobile: Serial,
+}
+
+impl GameEvent for Login {
+    const CANCELLABLE: bool = false;
+}
+
+// A player's connection has closed. Their character stays in the world.
+pub struct Logout {
+    pub mobile: Serial,
+}
+
+impl GameEvent for Logout {
+    const CANCELLABLE: bool = false;
+}
+
+// A mobile is about to move. Subscribers can change where it ends up.
+pub struct Move {
+    pub mobile: Serial,
+    pub from: Location,
+    pub to: Location,
+}
+
+impl GameEvent for Move {
+    const CANCELLABLE: bool = true;
+}
+
+// A mobile is about to say something. Commands aren't speech, so they don't
+// get here.
+pub struct Speech {
+    pub speaker: Serial,
+    pub speech: speech::Speech,
+}
+
+impl GameEvent for Speech {
+    const CANCELLABLE: bool = true;
+}
+
+// A mobile is about to start fighting another.
+pub struct Attack {
+    pub attacker: Serial,
+    pub defender: Serial,
+}
+
+impl GameEvent for Attack {
+    const CANCELLABLE: bool = true;
+}
+
+// A mobile is about to lose `amount` hits.
+pub struct Damage {
+    pub target: Serial,
+    pub source: Option<Serial>,
+    pub amount: u16,
+}
+
+impl GameEvent for Damage {
+    const CANCELLABLE: bool = true;
+}
+
+// A mobile's hits have reached 0.
+pub struct Death {
+    pub mobile: Serial,
+    pub killer: Option<Serial>,
+}
+
+impl GameEvent for Death {
+    const CANCELLABLE: bool = false;
+}
+
+// A player is trying to lift `amount` of an item.
+pub struct PickUpItem {
+    pub mobile: Serial,
+    pub item: Serial,
+    pub amount: u16,
+}
+
+impl GameEvent for PickUpItem {
+    const CANCELLABLE: bool = true;
+}
+
+// A player is trying to put down the item they're holding, on the ground
+// or into a container at `location` within it.
+pub struct DropItem {
+    pub mobile: Serial,
+    pub item: Serial,
+    pub location: Location,
+    pub container: Option<Serial>,
+}
+
+impl GameEvent for DropItem {
+    const CANCELLABLE: bool = true;
+}
+
+// A player has double-clicked something, to open it, put it on or use it.
+pub struct Use {
+    pub mobile: Serial,
+    pub target: Serial,
+}
+
+impl GameEvent for Use {
+    const CANCELLABLE: bool = true;
+}
+
+// A player has used a skill from the skill list or a macro. Subscribers
+// make it do something.
+pub struct UseSkill {
+    pub mobile: Serial,
+    pub skill: SkillId,
+}
+
+impl GameEvent for UseSkill {
+    const CANCELLABLE: bool = true;
+}
+
+// An entity timer has fired and is about to act on its entity.
+pub struct TimerExpired {
+    pub timer: TimerId,
+    pub serial: Serial,
+}
+
+impl GameEvent for TimerExpired {
+    const CANCELLABLE: bool = true;
+}
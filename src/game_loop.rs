@@ -0,0 +1,206 @@
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use async_std::channel;
+
+use crate::commands::{Caller, Commands};
+use crate::death;
+use crate::events::{self, Logout};
+use crate::regeneration;
+use crate::sessions::SessionId;
+use crate::shard::Shard;
+use crate::tcp;
+use crate::updates;
+
+// Something to do to the shard on the game loop, e.g. what a timer does
+// when it fires.
+pub type Job = Box<dyn FnOnce(&mut Shard) + Send>;
+
+// Everything the game loop hears about from other threads. Messages are
+// handled in the order they arrive, once a tick.
+pub enum Message {
+    // A new connection, which is sent the ID of its session.
+    Connected {
+        outgoing: channel::Sender<Vec<u8>>,
+        session_tx: channel::Sender<SessionId>,
+    },
+    Received(SessionId, Vec<u8>),
+    Disconnected(SessionId),
+    Console(String),
+    Run(Job),
+    Stop,
+}
+
+// Where other threads send the game loop messages.
+#[derive(Clone)]
+pub struct Inbox {
+    messages_tx: mpsc::Sender<Message>,
+}
+
+impl Inbox {
+    // Messages sent once the game loop has stopped are dropped.
+    pub fn send(&self, message: Message) {
+        let _ = self.messages_tx.send(message);
+    }
+
+    pub fn run(&self, job: impl FnOnce(&mut Shard) + Send + 'static) {
+        self.send(Message::Run(Box::new(job)));
+    }
+
+    // Runs `job` on the game loop and waits for what it returns. Returns
+    // None if the game loop has stopped.
+    pub fn call<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut Shard) -> T + Send + 'static,
+    ) -> Option<T> {
+        let (result_tx, result_rx) = mpsc::channel();
+        self.run(move |shard| {
+            let _ = result_tx.send(job(shard));
+        });
+        result_rx.recv().ok()
+    }
+}
+
+pub fn channel() -> (Inbox, mpsc::Receiver<Message>) {
+    let (messages_tx, messages_rx) = mpsc::channel();
+    (Inbox { messages_tx }, messages_rx)
+}
+
+pub struct GameLoop {
+    inbox: Inbox,
+    thread: JoinHandle<Shard>,
+}
+
+impl GameLoop {
+    pub fn inbox(&self) -> Inbox {
+        self.inbox.clone()
+    }
+
+    // Handles everything sent before this, then hands back the shard. Fails
+    // if the game loop panicked, taking the shard with it.
+    pub fn stop(self) -> thread::Result<Shard> {
+        self.inbox.send(Message::Stop);
+        self.thread.join()
+    }
+}
+
+// Gives the shard to a thread of its own, which is the only one to touch it
+// from then on. Everything else sends it messages through the inbox.
+pub fn start(shard: Shard, messages_rx: mpsc::Receiver<Message>, commands: Commands) -> GameLoop {
+    let inbox = shard.inbox.clone();
+    let thread = thread::spawn(move || run(shard, messages_rx, commands));
+    GameLoop { inbox, thread }
+}
+
+fn run(mut shard: Shard, messages_rx: mpsc::Receiver<Message>, commands: Commands) -> Shard {
+    let mut next_tick = Instant::now();
+
+    while tick(&mut shard, &messages_rx, &commands) {
+        // Read every tick, so a reloaded config takes effect straight away.
+        next_tick += Duration::from_millis(shard.config.tick_ms.max(1));
+
+        // A tick that overran starts the next one straight away, rather than
+        // running several short ones to catch up.
+        let now = Instant::now();
+        if next_tick > now {
+            thread::sleep(next_tick - now);
+        } else {
+            next_tick = now;
+        }
+    }
+
+    shard
+}
+
+// Runs timers that are due on the game loop, handles everything in the
+// inbox, then catches regeneration up with and tells clients what changed.
+// Returns false once told to stop.
+pub fn tick(shard: &mut Shard, messages_rx: &mpsc::Receiver<Message>, commands: &Commands) -> bool {
+    shard.timers.run_due();
+
+    while let Ok(message) = messages_rx.try_recv() {
+        match message {
+            Message::Connected {
+                outgoing,
+                session_tx,
+            } => {
+                let session_id = shard.sessions.add(outgoing);
+                let _ = session_tx.try_send(session_id);
+            }
+            Message::Received(session_id, bytes) => {
+                tcp::parse_packets(&bytes, session_id, shard, commands);
+            }
+            Message::Disconnected(session_id) => {
+                let session = shard.sessions.remove(session_id);
+                if let Some(mobile) = session.and_then(|session| session.mobile) {
+                    events::publish(shard, &mut Logout { mobile });
+                }
+            }
+            Message::Console(line) => commands.execute(shard, Caller::Console, &line),
+            Message::Run(job) => job(shard),
+            Message::Stop => {
+                end_tick(shard);
+                return false;
+            }
+        }
+    }
+
+    end_tick(shard);
+    true
+}
+
+fn end_tick(shard: &mut Shard) {
+    death::reap(shard);
+    let touched = shard.world.touched_mobiles();
+    regeneration::wake(shard, touched);
+    updates::flush(shard);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+    use crate::shard::tests::shard;
+
+    #[test]
+    fn it_handles_messages_in_the_order_they_arrive() {
+        let (mut shard, messages_rx) = shard("game-loop-order");
+        let inbox = shard.inbox.clone();
+        let (outgoing, _outgoing_rx) = channel::unbounded();
+        let (session_tx, session_rx) = channel::bounded(1);
+
+        inbox.send(Message::Connected {
+            outgoing,
+            session_tx,
+        });
+        inbox.run(|shard| {
+            shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        });
+        inbox.send(Message::Console(String::from("set 0x00000001 name Robert")));
+        assert!(tick(&mut shard, &messages_rx, &Commands::new()));
+
+        let session_id = session_rx.try_recv().unwrap();
+        assert!(shard.sessions.get(session_id).is_some());
+        assert_eq!(shard.world.mobile(1).unwrap().name, "Robert");
+
+        inbox.send(Message::Disconnected(session_id));
+        inbox.send(Message::Stop);
+        assert!(!tick(&mut shard, &messages_rx, &Commands::new()));
+        assert!(shard.sessions.get(session_id).is_none());
+    }
+
+    #[test]
+    fn it_hands_back_the_shard_when_stopped() {
+        let (shard, messages_rx) = shard("game-loop-stop");
+        let game_loop = start(shard, messages_rx, Commands::new());
+
+        let mobile = game_loop
+            .inbox()
+            .call(|shard| shard.world.add_mobile("Bob", 0x190, START_LOCATION))
+            .unwrap();
+
+        let shard = game_loop.stop().unwrap();
+        assert!(shard.world.mobile(mobile).is_some());
+    }
+}
@@ -3,6 +3,8 @@ pub struct CompressorBuffer {
     compressed_bit_count: u8,
 }
 
+// Each step of the bit buffer is spelled out in full.
+#[allow(clippy::assign_op_pattern)]
 impl CompressorBuffer {
     pub fn new() -> Self {
         Self {
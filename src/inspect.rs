@@ -0,0 +1,114 @@
+use crate::config::Config;
+use crate::persistence;
+use crate::world::{self, Serial, World};
+
+const USAGE: &str =
+    "Usage: rust-uo-server inspect [summary | accounts | show <serial> | contents <serial>]";
+
+// Looks inside the save without starting the server, e.g.
+// `rust-uo-server inspect show 0x40000001`. SQLite saves can also be queried
+// directly with the sqlite3 tool. Returns the process exit code.
+pub fn run(config: &Config, args: &[String]) -> i32 {
+    let loaded =
+        persistence::open(config.storage, &config.save_path).and_then(|mut storage| storage.load());
+    let (accounts, world) = match loaded {
+        Ok(Some(saved)) => saved,
+        Ok(None) => {
+            println!("There's no save at {}", config.save_path);
+            return 1;
+        }
+        Err(e) => {
+            println!("Error loading {}: {}", config.save_path, e);
+            return 1;
+        }
+    };
+
+    let serial = args
+        .get(1)
+        .and_then(|arg| world::parse_number::<Serial>(arg));
+
+    match (args.first().map(String::as_str), serial) {
+        (None | Some("summary"), _) => {
+            let (next_mobile_serial, next_item_serial) = world.next_serials();
+            println!("Accounts: {}", accounts.iter().count());
+            println!("Mobiles: {}", world.mobiles.len());
+            println!("Items: {}", world.items.len());
+            println!(
+                "Next serials: 0x{:08X} (mobile), 0x{:08X} (item)",
+                next_mobile_serial, next_item_serial
+            );
+        }
+        (Some("accounts"), _) => {
+            let mut accounts: Vec<_> = accounts.iter().collect();
+            accounts.sort_by(|a, b| a.username.cmp(&b.username));
+            for account in accounts {
+                let character = account
+                    .character
+                    .map(|serial| format!("0x{:08X}", serial))
+                    .unwrap_or_else(|| String::from("no character"));
+                println!(
+                    "{} ({:?}) {}",
+                    account.username, account.access_level, character
+                );
+            }
+        }
+        (Some("show"), Some(serial)) => {
+            if !show(&world, serial) {
+                println!("Nothing has serial 0x{:08X}", serial);
+                return 1;
+            }
+        }
+        (Some("contents"), Some(serial)) => {
+            let mut contents = world.contents(serial);
+            contents.sort();
+            for serial in contents {
+                show(&world, serial);
+            }
+        }
+        _ => {
+            println!("{}", USAGE);
+            return 1;
+        }
+    }
+
+    0
+}
+
+// Returns false if there's nothing with the serial.
+fn show(world: &World, serial: Serial) -> bool {
+    if let Some(mobile) = world.mobile(serial) {
+        println!(
+            "0x{:08X} mobile {:?} body 0x{:04X} hue 0x{:04X} at {}, {}, {} hits {}/{}",
+            serial,
+            mobile.name,
+            mobile.body,
+            mobile.hue,
+            mobile.location.x,
+            mobile.location.y,
+            mobile.location.z,
+            mobile.hits,
+            mobile.max_hits
+        );
+    } else if let Some(item) = world.items.get(&serial) {
+        let parent = item
+            .parent
+            .map(|parent| format!(" in 0x{:08X}", parent))
+            .unwrap_or_default();
+        println!(
+            "0x{:08X} item {:?} graphic 0x{:04X} hue 0x{:04X} amount {} at {}, {}, {}{}",
+            serial,
+            item.name.as_deref().unwrap_or_default(),
+            item.graphic,
+            item.hue,
+            item.amount,
+            item.location.x,
+            item.location.y,
+            item.location.z,
+            parent
+        );
+    } else {
+        return false;
+    }
+
+    true
+}
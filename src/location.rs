@@ -0,0 +1,58 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub x: u16,
+    pub y: u16,
+    pub z: i8,
+}
+
+// Where new characters appear: outside The Wayfarer's Inn in Britain, the
+// only city sent in the character list packet.
+pub const START_LOCATION: Location = Location {
+    x: 1602,
+    y: 1591,
+    z: 20,
+};
+
+impl Location {
+    // UO measures range as a square around the mobile, not a circle, so
+    // this is the larger of the x and y distances. Height is ignored.
+    pub fn in_range(&self, other: &Location, range: u16) -> bool {
+        self.x.abs_diff(other.x) <= range && self.y.abs_diff(other.y) <= range
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_treats_range_as_a_square() {
+        let origin = Location {
+            x: 100,
+            y: 100,
+            z: 0,
+        };
+        let corner = Location {
+            x: 112,
+            y: 88,
+            z: 0,
+        };
+        assert!(origin.in_range(&corner, 12));
+        assert!(!origin.in_range(&corner, 11));
+    }
+
+    #[test]
+    fn it_ignores_height() {
+        let origin = Location {
+            x: 100,
+            y: 100,
+            z: 0,
+        };
+        let above = Location {
+            x: 100,
+            y: 100,
+            z: 90,
+        };
+        assert!(origin.in_range(&above, 0));
+    }
+}
@@ -1,22 +1,127 @@
+use std::env;
+use std::process;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
 
+mod accounts;
+mod autosave;
+mod combat;
+mod commands;
+mod config;
+mod console;
+mod death;
+mod entity_timers;
+mod events;
+mod game_loop;
 mod huffman;
+mod inspect;
+mod location;
+mod persistence;
+mod regeneration;
+mod scripts;
+mod sessions;
+mod shard;
+mod shutdown;
+mod skills;
+mod speech;
 mod state;
 mod tcp;
-mod test_timers;
-mod ticks;
+mod templates;
 mod timer;
+mod updates;
+mod world;
 
 fn main() {
-    let timer_register_tx = timer::start();
-    test_timers::start(timer_register_tx);
+    let config = match config::load(config::CONFIG_PATH) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error loading config: {}", e);
+            process::exit(1);
+        }
+    };
 
-    if let Err(e) = tcp::start() {
-        println!("Error from TCP: {:?}", e);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("inspect") {
+        process::exit(inspect::run(&config, &args[1..]));
     }
 
-    loop {
-        thread::sleep(Duration::from_secs(60));
+    // Load the world before accepting any connections, and refuse to start
+    // with an empty world if the save is unreadable, as the next save would
+    // overwrite it.
+    let loaded = persistence::open(config.storage, &config.save_path)
+        .and_then(|mut storage| Ok((storage.load()?, storage)));
+    let (accounts, world, storage) = match loaded {
+        Ok((Some((accounts, world)), storage)) => {
+            println!("Loaded the world from {}", config.save_path);
+            (accounts, world, storage)
+        }
+        Ok((None, storage)) => {
+            println!("No save at {}, starting a new world", config.save_path);
+            (accounts::Accounts::new(), world::World::new(), storage)
+        }
+        Err(e) => {
+            println!("Error loading the world from {}: {}", config.save_path, e);
+            process::exit(1);
+        }
+    };
+
+    let saver = persistence::Saver::start(storage, &config.save_path, &accounts, &world);
+    let timers = timer::start(
+        Arc::new(timer::MonotonicClock::start()),
+        timer::Options::from_config(&config),
+    );
+    let (shutdown, shutdown_requests) = shutdown::channel();
+    shutdown::handle_signals(shutdown.clone());
+    let (inbox, messages) = game_loop::channel();
+
+    let mut shard = shard::Shard::new(config, accounts, world, saver, timers, shutdown, inbox);
+    autosave::start(&mut shard);
+
+    // Broken templates and scripts are refused at startup, as they would be
+    // by a reload.
+    match templates::load(&shard.config.templates_path) {
+        Ok(templates) => {
+            println!("Loaded {} templates", templates.len());
+            shard.templates = templates;
+        }
+        Err(errors) => {
+            for error in errors {
+                println!("Error loading templates {}", error);
+            }
+            process::exit(1);
+        }
+    }
+    match shard.scripts.load(&shard.config.scripts_path) {
+        Ok(loaded) => {
+            println!("Loaded {} scripts", loaded.len());
+            scripts::install(&mut shard, loaded);
+        }
+        Err(errors) => {
+            for error in errors {
+                println!("Error loading script {}", error);
+            }
+            process::exit(1);
+        }
     }
+    // Anyone saved while hurt picks up regenerating where they left off.
+    let serials: Vec<_> = shard.world.mobiles.keys().copied().collect();
+    regeneration::wake(&mut shard, serials);
+    death::start_decay_all(&mut shard);
+    let game_loop = game_loop::start(shard, messages, commands::Commands::new());
+
+    let listener = match tcp::start("127.0.0.1:2593", game_loop.inbox()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Error from TCP: {:?}", e);
+            process::exit(1);
+        }
+    };
+
+    {
+        let inbox = game_loop.inbox();
+        thread::spawn(move || console::run(inbox));
+    }
+
+    let exit_code = shutdown::run(shutdown_requests, game_loop, listener);
+    process::exit(exit_code);
 }
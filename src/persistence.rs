@@ -0,0 +1,173 @@
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::accounts::Accounts;
+use crate::world::{Changes, World};
+
+pub mod backups;
+mod binary;
+mod flat_file;
+mod saver;
+mod sqlite;
+
+pub use saver::{Policy, Saver};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    FlatFile,
+    Sqlite,
+}
+
+// Somewhere to keep the world between runs.
+pub trait Storage: Send {
+    // Returns None if nothing has been saved yet, i.e. this is a brand new
+    // shard.
+    fn load(&mut self) -> io::Result<Option<(Accounts, World)>>;
+
+    // Writes the changes since the last save, or everything in the world if
+    // `full` is set. Either way the changes must be written all or nothing.
+    // `world` already has the changes applied.
+    fn save(
+        &mut self,
+        accounts: &Accounts,
+        world: &World,
+        changes: &Changes,
+        full: bool,
+    ) -> io::Result<Written>;
+
+    // Copies the latest save to `path`.
+    fn back_up(&mut self, path: &Path) -> io::Result<()>;
+}
+
+pub struct Written {
+    // A storage may write everything even when only asked for the changes.
+    pub full: bool,
+    // The size of what was written, or for a database the size of the
+    // whole database.
+    pub size: usize,
+}
+
+pub fn open(backend: Backend, path: &str) -> io::Result<Box<dyn Storage>> {
+    match backend {
+        Backend::FlatFile => Ok(Box::new(flat_file::FlatFile::new(path))),
+        Backend::Sqlite => Ok(Box::new(sqlite::Sqlite::open(path)?)),
+    }
+}
+
+pub struct SaveReport {
+    pub full: bool,
+    pub size: usize,
+    pub duration: Duration,
+}
+
+impl fmt::Display for SaveReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} bytes in {} ms",
+            if self.full { "everything" } else { "changes" },
+            self.size,
+            self.duration.as_millis()
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::accounts::AccessLevel;
+    use crate::location::START_LOCATION;
+    use crate::skills::{self, SkillLock};
+    use crate::world::Notoriety;
+    use std::fs;
+    use std::path::PathBuf;
+
+    pub fn populated_world() -> (Accounts, World) {
+        let mut accounts = Accounts::new();
+        let mut world = World::new();
+
+        let character = world.add_mobile("Bob", 0x190, START_LOCATION);
+        let mobile = world.mobile_mut(character).unwrap();
+        mobile.notoriety = Notoriety::Murderer;
+        mobile.mana = 40;
+        mobile.template = Some(String::from("townsperson"));
+        mobile.set_skill(skills::by_name("magery").unwrap(), 505);
+        mobile.set_skill_lock(skills::by_name("tactics").unwrap(), SkillLock::Locked);
+        mobile.skills.total_cap = 7200;
+        mobile.food = 5;
+        mobile.strength = 80;
+        mobile.kills = 3;
+        accounts
+            .create("bob", "secret", AccessLevel::Owner)
+            .unwrap()
+            .character = Some(character);
+        accounts
+            .create("alice", "hunter2", AccessLevel::Player)
+            .unwrap();
+
+        let backpack = world.add_item(0x0E75, START_LOCATION);
+        let gold = world.add_item(0x0EED, START_LOCATION);
+        world.item_mut(gold).unwrap().amount = 500;
+        world.item_mut(gold).unwrap().name = Some(String::from("Gold"));
+        world.item_mut(gold).unwrap().template = Some(String::from("gold"));
+        world.item_mut(backpack).unwrap().decays_at = Some(1_700_000_000_000);
+        world.place_in(gold, backpack).unwrap();
+        world.place_in(backpack, character).unwrap();
+
+        let removed = world.add_item(0x0F51, START_LOCATION);
+        world.remove(removed);
+
+        (accounts, world)
+    }
+
+    pub fn empty_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn it_saves_in_the_background_and_backs_up_full_saves() {
+        let directory = empty_directory("rust-uo-server-test-saver");
+        let path = directory.join("world.bin");
+        let path = path.to_str().unwrap();
+
+        let (accounts, mut world) = populated_world();
+        let storage = open(Backend::FlatFile, path).unwrap();
+        let saver = Saver::start(storage, path, &Accounts::new(), &World::new());
+        let save = |world: &mut World| {
+            let policy = Policy {
+                saves_per_snapshot: 2,
+                backup_path: directory.join("backups").to_string_lossy().into_owned(),
+                retention: backups::Retention {
+                    recent: 5,
+                    hourly: 0,
+                    daily: 0,
+                },
+            };
+            saver
+                .save(accounts.clone(), world.take_changes(), false, policy)
+                .recv()
+                .unwrap()
+                .unwrap()
+                .full
+        };
+
+        // Every third save is a full one, counting the first.
+        let fulls: Vec<bool> = (0..4).map(|_| save(&mut world)).collect();
+        assert_eq!(fulls, vec![true, false, false, true]);
+
+        let (_, loaded_world) = open(Backend::FlatFile, path)
+            .unwrap()
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded_world.items.len(), 2);
+        assert!(fs::read_dir(directory.join("backups")).unwrap().count() >= 1);
+    }
+}
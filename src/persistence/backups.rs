@@ -0,0 +1,179 @@
+use chrono::{NaiveDateTime, Timelike};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::Storage;
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+pub struct Retention {
+    pub recent: usize,
+    pub hourly: usize,
+    pub daily: usize,
+}
+
+// Has the storage copy the save into the backup directory, named after the
+// save and when it was taken, e.g. world-20240101-120000.bin, then deletes
+// whichever backups the retention policy no longer needs. Returns how many
+// backups are left.
+pub fn back_up(
+    storage: &mut dyn Storage,
+    save_path: &str,
+    backup_directory: &str,
+    retention: &Retention,
+    now: NaiveDateTime,
+) -> io::Result<usize> {
+    let save_path = Path::new(save_path);
+    let stem = save_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("world");
+    let extension = save_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("bin");
+    let backup_directory = Path::new(backup_directory);
+
+    fs::create_dir_all(backup_directory)?;
+    storage.back_up(&backup_path(backup_directory, stem, extension, now))?;
+
+    let mut backups = vec![];
+    for entry in fs::read_dir(backup_directory)? {
+        let name = entry?.file_name();
+        if let Some(taken) = name
+            .to_str()
+            .and_then(|name| parse_name(name, stem, extension))
+        {
+            backups.push(taken);
+        }
+    }
+
+    let expired = expired(&backups, retention);
+    for &taken in &expired {
+        fs::remove_file(backup_path(backup_directory, stem, extension, taken))?;
+    }
+
+    Ok(backups.len() - expired.len())
+}
+
+fn backup_path(directory: &Path, stem: &str, extension: &str, taken: NaiveDateTime) -> PathBuf {
+    directory.join(format!(
+        "{}-{}.{}",
+        stem,
+        taken.format(TIMESTAMP_FORMAT),
+        extension
+    ))
+}
+
+// Anything in the backup directory that doesn't look like one of our
+// backups is left alone.
+fn parse_name(name: &str, stem: &str, extension: &str) -> Option<NaiveDateTime> {
+    let timestamp = name
+        .strip_prefix(stem)?
+        .strip_prefix('-')?
+        .strip_suffix(extension)?
+        .strip_suffix('.')?;
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()
+}
+
+// Works out which backups to delete. Going from newest to oldest, a backup
+// is kept if it's one of the most recent, or the newest one of an hour or
+// day that is still within the retention period.
+fn expired(backups: &[NaiveDateTime], retention: &Retention) -> Vec<NaiveDateTime> {
+    let mut backups = backups.to_vec();
+    backups.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut hours = HashSet::new();
+    let mut days = HashSet::new();
+    let mut expired = vec![];
+
+    for (index, taken) in backups.into_iter().enumerate() {
+        let hour = (taken.date(), taken.hour());
+        let day = taken.date();
+
+        let keep_for_hour = hours.len() < retention.hourly && hours.insert(hour);
+        let keep_for_day = days.len() < retention.daily && days.insert(day);
+
+        if index >= retention.recent && !keep_for_hour && !keep_for_day {
+            expired.push(taken);
+        }
+    }
+
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate};
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn it_keeps_the_most_recent_backups() {
+        let backups: Vec<NaiveDateTime> = (0..5).map(|minute| at(1, 12, minute)).collect();
+        let retention = Retention {
+            recent: 3,
+            hourly: 0,
+            daily: 0,
+        };
+
+        assert_eq!(
+            expired(&backups, &retention),
+            vec![at(1, 12, 1), at(1, 12, 0)]
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_newest_backup_of_each_hour_and_day() {
+        // Every half hour for three days.
+        let backups: Vec<NaiveDateTime> = (0..144)
+            .map(|n| at(1, 0, 0) + Duration::minutes(30 * n))
+            .collect();
+        let retention = Retention {
+            recent: 1,
+            hourly: 2,
+            daily: 3,
+        };
+
+        let expired = expired(&backups, &retention);
+        let kept: Vec<&NaiveDateTime> = backups
+            .iter()
+            .filter(|taken| !expired.contains(taken))
+            .collect();
+
+        assert_eq!(
+            kept,
+            vec![
+                &at(1, 23, 30),
+                &at(2, 23, 30),
+                &at(3, 22, 30),
+                &at(3, 23, 30)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_only_reads_its_own_backup_names() {
+        assert_eq!(
+            parse_name("world-20240101-120000.bin", "world", "bin"),
+            Some(at(1, 12, 0))
+        );
+        assert_eq!(
+            parse_name("world-20240101-120000.tmp", "world", "bin"),
+            None
+        );
+        assert_eq!(
+            parse_name("other-20240101-120000.bin", "world", "bin"),
+            None
+        );
+        assert_eq!(parse_name("notes.txt", "world", "bin"), None);
+    }
+}
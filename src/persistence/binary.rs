@@ -0,0 +1,66 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::location::Location;
+
+// Helpers for the parts of the save format that byteorder doesn't cover.
+// Writes go to a Vec so they only fail on strings too long for their
+// length prefix; reads can run off the end of a truncated save and return
+// an error.
+
+pub fn write_string(output: &mut Vec<u8>, string: &str) -> io::Result<()> {
+    let length = u16::try_from(string.len()).map_err(|_| {
+        invalid_data(&format!(
+            "A string of {} bytes is too long to save",
+            string.len()
+        ))
+    })?;
+    output.write_u16::<LittleEndian>(length).unwrap();
+    output.write_all(string.as_bytes()).unwrap();
+    Ok(())
+}
+
+pub fn read_string(input: &mut &[u8]) -> io::Result<String> {
+    let length = input.read_u16::<LittleEndian>()?;
+    let mut bytes = vec![0; length.into()];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| invalid_data(&e.to_string()))
+}
+
+pub fn write_optional_string(output: &mut Vec<u8>, string: Option<&str>) -> io::Result<()> {
+    match string {
+        Some(string) => {
+            output.write_u8(1).unwrap();
+            write_string(output, string)
+        }
+        None => {
+            output.write_u8(0).unwrap();
+            Ok(())
+        }
+    }
+}
+
+pub fn read_optional_string(input: &mut &[u8]) -> io::Result<Option<String>> {
+    match input.read_u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(read_string(input)?)),
+    }
+}
+
+pub fn write_location(output: &mut Vec<u8>, location: &Location) {
+    output.write_u16::<LittleEndian>(location.x).unwrap();
+    output.write_u16::<LittleEndian>(location.y).unwrap();
+    output.write_i8(location.z).unwrap();
+}
+
+pub fn read_location(input: &mut &[u8]) -> io::Result<Location> {
+    Ok(Location {
+        x: input.read_u16::<LittleEndian>()?,
+        y: input.read_u16::<LittleEndian>()?,
+        z: input.read_i8()?,
+    })
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,765 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use super::binary::*;
+use super::{Storage, Written};
+use crate::accounts::{hash_password, AccessLevel, Account, Accounts};
+use crate::skills::{SkillLock, Skills, SKILL_COUNT};
+use crate::world::{Changes, Item, Mobile, Notoriety, Serial, World};
+
+// A save is a snapshot of the whole world plus a delta file for each save
+// since, holding only what changed. Each snapshot has a new generation
+// number, and deltas are named after the generation they apply to, e.g.
+// world-3-12.delta, so deltas left over from older snapshots are ignored.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"UOWS";
+const DELTA_MAGIC: &[u8; 4] = b"UOWD";
+
+// Bump this whenever the format changes, and teach the readers below to
+// handle saves written by older versions, e.g. `if version >= 2 { ... }`.
+pub const VERSION: u32 = 10;
+
+// Saves can't use serial 0, so it stands in for "nothing".
+const NO_SERIAL: Serial = 0;
+
+// Saved as the decay time of items that don't decay.
+const NEVER: i64 = 0;
+
+pub struct FlatFile {
+    path: PathBuf,
+    // Generation 0 means there's no snapshot yet.
+    generation: u64,
+    deltas: u32,
+}
+
+impl FlatFile {
+    pub fn new(path: &str) -> Self {
+        FlatFile {
+            path: PathBuf::from(path),
+            generation: 0,
+            deltas: 0,
+        }
+    }
+
+    fn write_snapshot(&mut self, accounts: &Accounts, world: &World) -> io::Result<usize> {
+        // A failed snapshot may or may not have reached the disk, so never
+        // reuse its generation.
+        self.generation += 1;
+
+        let bytes = serialize(accounts, world, self.generation)?;
+        write_atomically(&self.path, &bytes)?;
+        self.deltas = 0;
+
+        self.remove_old_deltas();
+
+        Ok(bytes.len())
+    }
+
+    fn write_delta(&mut self, accounts: &Accounts, changes: &Changes) -> io::Result<usize> {
+        let sequence = self.deltas + 1;
+
+        let bytes = serialize_delta(accounts, changes)?;
+        write_atomically(&delta_path(&self.path, self.generation, sequence), &bytes)?;
+        self.deltas = sequence;
+
+        Ok(bytes.len())
+    }
+
+    // The new snapshot already holds everything in the old deltas. Any that
+    // can't be removed are harmless, as they're ignored when loading.
+    fn remove_old_deltas(&self) {
+        let result = delta_paths(&self.path).and_then(|deltas| {
+            for (generation, _, path) in deltas {
+                if generation != self.generation {
+                    fs::remove_file(path)?;
+                }
+            }
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            println!("Error removing old deltas: {}", e);
+        }
+    }
+}
+
+impl Storage for FlatFile {
+    // Loads the latest snapshot and applies the deltas saved since.
+    fn load(&mut self) -> io::Result<Option<(Accounts, World)>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let (mut accounts, mut world, generation) = deserialize(&bytes)?;
+
+        let mut deltas: Vec<(u32, PathBuf)> = delta_paths(&self.path)?
+            .into_iter()
+            .filter(|&(delta_generation, _, _)| delta_generation == generation)
+            .map(|(_, sequence, path)| (sequence, path))
+            .collect();
+        deltas.sort();
+
+        let mut applied = 0;
+        for (sequence, delta_path) in deltas {
+            if sequence != applied + 1 {
+                return Err(invalid_data(&format!(
+                    "Delta {} is missing, so the changes after it can't be loaded",
+                    applied + 1
+                )));
+            }
+
+            let (delta_accounts, changes) = deserialize_delta(&fs::read(&delta_path)?)?;
+            accounts = delta_accounts;
+            world.apply(&changes);
+            applied = sequence;
+        }
+
+        self.generation = generation;
+        self.deltas = applied;
+
+        Ok(Some((accounts, world)))
+    }
+
+    fn save(
+        &mut self,
+        accounts: &Accounts,
+        world: &World,
+        changes: &Changes,
+        full: bool,
+    ) -> io::Result<Written> {
+        // Deltas need a snapshot to apply to.
+        let full = full || self.generation == 0;
+
+        let size = if full {
+            self.write_snapshot(accounts, world)?
+        } else {
+            self.write_delta(accounts, changes)?
+        };
+
+        Ok(Written { full, size })
+    }
+
+    fn back_up(&mut self, path: &Path) -> io::Result<()> {
+        fs::copy(&self.path, path).map(|_| ())
+    }
+}
+
+fn delta_path(path: &Path, generation: u64, sequence: u32) -> PathBuf {
+    path.with_file_name(format!(
+        "{}-{}-{}.delta",
+        file_stem(path),
+        generation,
+        sequence
+    ))
+}
+
+// Lists the delta files next to the save, as (generation, sequence, path).
+fn delta_paths(path: &Path) -> io::Result<Vec<(u64, u32, PathBuf)>> {
+    let prefix = format!("{}-", file_stem(path));
+    let directory = save_directory(path);
+
+    let mut deltas = vec![];
+    for entry in fs::read_dir(directory)? {
+        let name = entry?.file_name();
+        let Some(numbers) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|name| name.strip_suffix(".delta"))
+        else {
+            continue;
+        };
+
+        if let Some((generation, sequence)) = numbers.split_once('-') {
+            if let (Ok(generation), Ok(sequence)) = (generation.parse(), sequence.parse()) {
+                deltas.push((generation, sequence, directory.join(&name)));
+            }
+        }
+    }
+
+    Ok(deltas)
+}
+
+fn file_stem(path: &Path) -> &str {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("world")
+}
+
+fn save_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    }
+}
+
+// Writes to a temporary file first and only renames it over the old file
+// once it's safely on disk, so a crash or error mid-save can't leave a
+// half-written file behind.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let directory = save_directory(path);
+    fs::create_dir_all(directory)?;
+
+    let temp_path = path.with_extension("tmp");
+    let written = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    fs::rename(&temp_path, path)?;
+
+    // The rename only survives a power cut once the directory is synced too.
+    File::open(directory)?.sync_all()
+}
+
+fn write_header(output: &mut Vec<u8>, magic: &[u8; 4]) {
+    output.extend_from_slice(magic);
+    output.write_u32::<LittleEndian>(VERSION).unwrap();
+}
+
+// Returns the version the file was written with.
+fn read_header(input: &mut &[u8], magic: &[u8; 4]) -> io::Result<u32> {
+    let mut actual = [0; 4];
+    io::Read::read_exact(input, &mut actual)?;
+    if &actual != magic {
+        return Err(invalid_data("This isn't a world save"));
+    }
+
+    let version = input.read_u32::<LittleEndian>()?;
+    if version > VERSION {
+        return Err(invalid_data(&format!(
+            "The save is version {} but this server only understands up to version {}",
+            version, VERSION
+        )));
+    }
+
+    Ok(version)
+}
+
+fn write_accounts(output: &mut Vec<u8>, accounts: &Accounts) -> io::Result<()> {
+    let accounts: Vec<&Account> = accounts.iter().collect();
+    output
+        .write_u32::<LittleEndian>(accounts.len() as u32)
+        .unwrap();
+    for account in accounts {
+        write_account(output, account)?;
+    }
+    Ok(())
+}
+
+fn read_accounts(input: &mut &[u8], version: u32) -> io::Result<Accounts> {
+    let mut accounts = Accounts::new();
+    for _ in 0..input.read_u32::<LittleEndian>()? {
+        accounts.insert(read_account(input, version)?);
+    }
+    Ok(accounts)
+}
+
+fn serialize(accounts: &Accounts, world: &World, generation: u64) -> io::Result<Vec<u8>> {
+    let mut output = vec![];
+
+    write_header(&mut output, SNAPSHOT_MAGIC);
+    output.write_u64::<LittleEndian>(generation).unwrap();
+
+    let (next_mobile_serial, next_item_serial) = world.next_serials();
+    output
+        .write_u32::<LittleEndian>(next_mobile_serial)
+        .unwrap();
+    output.write_u32::<LittleEndian>(next_item_serial).unwrap();
+
+    write_accounts(&mut output, accounts)?;
+
+    output
+        .write_u32::<LittleEndian>(world.mobiles.len() as u32)
+        .unwrap();
+    for mobile in world.mobiles.values() {
+        write_mobile(&mut output, mobile)?;
+    }
+
+    output
+        .write_u32::<LittleEndian>(world.items.len() as u32)
+        .unwrap();
+    for (serial, item) in &world.items {
+        write_item(&mut output, *serial, item)?;
+    }
+
+    Ok(output)
+}
+
+// Returns the snapshot's generation along with what it holds.
+fn deserialize(mut input: &[u8]) -> io::Result<(Accounts, World, u64)> {
+    let input = &mut input;
+
+    let version = read_header(input, SNAPSHOT_MAGIC)?;
+
+    // Version 1 saves were always a whole snapshot, with no deltas.
+    let generation = if version >= 2 {
+        input.read_u64::<LittleEndian>()?
+    } else {
+        1
+    };
+
+    let next_mobile_serial = input.read_u32::<LittleEndian>()?;
+    let next_item_serial = input.read_u32::<LittleEndian>()?;
+
+    let accounts = read_accounts(input, version)?;
+
+    let mut mobiles = HashMap::new();
+    for _ in 0..input.read_u32::<LittleEndian>()? {
+        let mobile = read_mobile(input, version)?;
+        mobiles.insert(mobile.serial, mobile);
+    }
+
+    let mut items = HashMap::new();
+    for _ in 0..input.read_u32::<LittleEndian>()? {
+        let (serial, item) = read_item(input, version)?;
+        items.insert(serial, item);
+    }
+
+    if !input.is_empty() {
+        return Err(invalid_data("The save has unexpected data at the end"));
+    }
+
+    let world = World::restore(next_mobile_serial, next_item_serial, mobiles, items);
+
+    Ok((accounts, world, generation))
+}
+
+// Accounts are few and small, so every delta carries all of them.
+fn serialize_delta(accounts: &Accounts, changes: &Changes) -> io::Result<Vec<u8>> {
+    let mut output = vec![];
+
+    write_header(&mut output, DELTA_MAGIC);
+    output
+        .write_u32::<LittleEndian>(changes.next_mobile_serial)
+        .unwrap();
+    output
+        .write_u32::<LittleEndian>(changes.next_item_serial)
+        .unwrap();
+
+    write_accounts(&mut output, accounts)?;
+
+    output
+        .write_u32::<LittleEndian>(changes.mobiles.len() as u32)
+        .unwrap();
+    for mobile in &changes.mobiles {
+        write_mobile(&mut output, mobile)?;
+    }
+
+    output
+        .write_u32::<LittleEndian>(changes.items.len() as u32)
+        .unwrap();
+    for (serial, item) in &changes.items {
+        write_item(&mut output, *serial, item)?;
+    }
+
+    output
+        .write_u32::<LittleEndian>(changes.removed.len() as u32)
+        .unwrap();
+    for serial in &changes.removed {
+        output.write_u32::<LittleEndian>(*serial).unwrap();
+    }
+
+    Ok(output)
+}
+
+fn deserialize_delta(mut input: &[u8]) -> io::Result<(Accounts, Changes)> {
+    let input = &mut input;
+
+    let version = read_header(input, DELTA_MAGIC)?;
+
+    let next_mobile_serial = input.read_u32::<LittleEndian>()?;
+    let next_item_serial = input.read_u32::<LittleEndian>()?;
+
+    let accounts = read_accounts(input, version)?;
+
+    let mut mobiles = vec![];
+    for _ in 0..input.read_u32::<LittleEndian>()? {
+        mobiles.push(read_mobile(input, version)?);
+    }
+
+    let mut items = vec![];
+    for _ in 0..input.read_u32::<LittleEndian>()? {
+        items.push(read_item(input, version)?);
+    }
+
+    let mut removed = vec![];
+    for _ in 0..input.read_u32::<LittleEndian>()? {
+        removed.push(input.read_u32::<LittleEndian>()?);
+    }
+
+    if !input.is_empty() {
+        return Err(invalid_data("The delta has unexpected data at the end"));
+    }
+
+    let changes = Changes {
+        next_mobile_serial,
+        next_item_serial,
+        mobiles,
+        items,
+        removed,
+    };
+
+    Ok((accounts, changes))
+}
+
+fn write_account(output: &mut Vec<u8>, account: &Account) -> io::Result<()> {
+    write_string(output, &account.username)?;
+    write_string(output, &account.password_hash)?;
+    output.write_u8(account.access_level as u8).unwrap();
+    output
+        .write_u32::<LittleEndian>(account.character.unwrap_or(NO_SERIAL))
+        .unwrap();
+    Ok(())
+}
+
+fn read_account(input: &mut &[u8], version: u32) -> io::Result<Account> {
+    let username = read_string(input)?;
+    // Passwords were kept as they were typed before version 9.
+    let password_hash = if version >= 9 {
+        read_string(input)?
+    } else {
+        hash_password(&read_string(input)?)
+    };
+
+    Ok(Account {
+        username,
+        password_hash,
+        access_level: read_access_level(input)?,
+        character: read_optional_serial(input)?,
+    })
+}
+
+fn read_access_level(input: &mut &[u8]) -> io::Result<AccessLevel> {
+    let value = input.read_u8()?;
+    AccessLevel::from_u8(value)
+        .ok_or_else(|| invalid_data(&format!("Unknown access level {}", value)))
+}
+
+fn read_optional_serial(input: &mut &[u8]) -> io::Result<Option<Serial>> {
+    match input.read_u32::<LittleEndian>()? {
+        NO_SERIAL => Ok(None),
+        serial => Ok(Some(serial)),
+    }
+}
+
+fn write_mobile(output: &mut Vec<u8>, mobile: &Mobile) -> io::Result<()> {
+    output.write_u32::<LittleEndian>(mobile.serial).unwrap();
+    write_string(output, &mobile.name)?;
+    output.write_u16::<LittleEndian>(mobile.body).unwrap();
+    output.write_u16::<LittleEndian>(mobile.hue).unwrap();
+    write_location(output, &mobile.location);
+    output.write_u16::<LittleEndian>(mobile.hits).unwrap();
+    output.write_u16::<LittleEndian>(mobile.max_hits).unwrap();
+    output.write_u8(mobile.direction).unwrap();
+    output.write_u8(mobile.notoriety.to_u8()).unwrap();
+    output.write_u16::<LittleEndian>(mobile.stamina).unwrap();
+    output
+        .write_u16::<LittleEndian>(mobile.max_stamina)
+        .unwrap();
+    output.write_u16::<LittleEndian>(mobile.mana).unwrap();
+    output.write_u16::<LittleEndian>(mobile.max_mana).unwrap();
+    write_optional_string(output, mobile.template.as_deref())?;
+    write_skills(output, &mobile.skills);
+    output.write_u8(mobile.food).unwrap();
+    output.write_u16::<LittleEndian>(mobile.strength).unwrap();
+    output.write_u16::<LittleEndian>(mobile.dexterity).unwrap();
+    output
+        .write_u16::<LittleEndian>(mobile.intelligence)
+        .unwrap();
+    output.write_u32::<LittleEndian>(mobile.kills).unwrap();
+    Ok(())
+}
+
+fn read_mobile(input: &mut &[u8], version: u32) -> io::Result<Mobile> {
+    let serial = input.read_u32::<LittleEndian>()?;
+    let name = read_string(input)?;
+    let body = input.read_u16::<LittleEndian>()?;
+    let hue = input.read_u16::<LittleEndian>()?;
+    let location = read_location(input)?;
+
+    let mut mobile = Mobile::new(serial, &name, body, location);
+    mobile.hue = hue;
+    mobile.hits = input.read_u16::<LittleEndian>()?;
+    mobile.max_hits = input.read_u16::<LittleEndian>()?;
+
+    // Older saves leave these at their defaults.
+    if version >= 3 {
+        mobile.direction = input.read_u8()?;
+        mobile.notoriety = read_notoriety(input)?;
+        mobile.stamina = input.read_u16::<LittleEndian>()?;
+        mobile.max_stamina = input.read_u16::<LittleEndian>()?;
+        mobile.mana = input.read_u16::<LittleEndian>()?;
+        mobile.max_mana = input.read_u16::<LittleEndian>()?;
+    }
+    if version >= 4 {
+        mobile.template = read_optional_string(input)?;
+    }
+    if version >= 5 {
+        mobile.skills = read_skills(input)?;
+    }
+    if version >= 6 {
+        mobile.food = input.read_u8()?;
+    }
+    if version >= 7 {
+        mobile.strength = input.read_u16::<LittleEndian>()?;
+        mobile.dexterity = input.read_u16::<LittleEndian>()?;
+        mobile.intelligence = input.read_u16::<LittleEndian>()?;
+    }
+    if version >= 8 {
+        mobile.kills = input.read_u32::<LittleEndian>()?;
+    }
+
+    Ok(mobile)
+}
+
+// Every skill is written, in ID order, after how many there are, so saves
+// from before a skill was added can still be read.
+fn write_skills(output: &mut Vec<u8>, skills: &Skills) {
+    output.write_u16::<LittleEndian>(skills.total_cap).unwrap();
+    output.write_u8(SKILL_COUNT as u8).unwrap();
+    for (_, skill) in skills.iter() {
+        output.write_u16::<LittleEndian>(skill.base).unwrap();
+        output.write_u16::<LittleEndian>(skill.cap).unwrap();
+        output.write_u8(skill.lock.to_u8()).unwrap();
+    }
+}
+
+fn read_skills(input: &mut &[u8]) -> io::Result<Skills> {
+    let mut skills = Skills::default();
+    skills.total_cap = input.read_u16::<LittleEndian>()?;
+    let count = input.read_u8()?;
+    if count as usize > SKILL_COUNT {
+        return Err(invalid_data(&format!(
+            "The save has {} skills but this server only knows {}",
+            count, SKILL_COUNT
+        )));
+    }
+
+    for id in 0..count {
+        let skill = skills.get_mut(id);
+        skill.base = input.read_u16::<LittleEndian>()?;
+        skill.cap = input.read_u16::<LittleEndian>()?;
+        let lock = input.read_u8()?;
+        skill.lock = SkillLock::from_u8(lock)
+            .ok_or_else(|| invalid_data(&format!("Unknown skill lock {}", lock)))?;
+    }
+
+    Ok(skills)
+}
+
+fn read_notoriety(input: &mut &[u8]) -> io::Result<Notoriety> {
+    let value = input.read_u8()?;
+    Notoriety::from_u8(value).ok_or_else(|| invalid_data(&format!("Unknown notoriety {}", value)))
+}
+
+fn write_item(output: &mut Vec<u8>, serial: Serial, item: &Item) -> io::Result<()> {
+    output.write_u32::<LittleEndian>(serial).unwrap();
+    write_optional_string(output, item.name.as_deref())?;
+    output.write_u16::<LittleEndian>(item.graphic).unwrap();
+    output.write_u16::<LittleEndian>(item.hue).unwrap();
+    output.write_u16::<LittleEndian>(item.amount).unwrap();
+    write_location(output, &item.location);
+    output
+        .write_u32::<LittleEndian>(item.parent.unwrap_or(NO_SERIAL))
+        .unwrap();
+    write_optional_string(output, item.template.as_deref())?;
+    output
+        .write_i64::<LittleEndian>(item.decays_at.unwrap_or(NEVER))
+        .unwrap();
+    Ok(())
+}
+
+fn read_item(input: &mut &[u8], version: u32) -> io::Result<(Serial, Item)> {
+    let serial = input.read_u32::<LittleEndian>()?;
+    let name = read_optional_string(input)?;
+    let graphic = input.read_u16::<LittleEndian>()?;
+    let hue = input.read_u16::<LittleEndian>()?;
+    let amount = input.read_u16::<LittleEndian>()?;
+    let location = read_location(input)?;
+
+    let mut item = Item::new(graphic, location);
+    item.name = name;
+    item.hue = hue;
+    item.amount = amount;
+    item.parent = read_optional_serial(input)?;
+    if version >= 4 {
+        item.template = read_optional_string(input)?;
+    }
+    if version >= 10 {
+        let decays_at = input.read_i64::<LittleEndian>()?;
+        item.decays_at = (decays_at != NEVER).then_some(decays_at);
+    }
+
+    Ok((serial, item))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+    use crate::persistence::tests::{empty_directory, populated_world};
+
+    #[test]
+    fn it_round_trips_accounts_and_the_world() {
+        let (accounts, world) = populated_world();
+
+        let (mut loaded_accounts, loaded_world, generation) =
+            deserialize(&serialize(&accounts, &world, 7).unwrap()).unwrap();
+
+        assert_eq!(generation, 7);
+        let bob = loaded_accounts.get_mut("bob").unwrap();
+        assert!(bob.check_password("secret"));
+        assert_eq!(bob.access_level, AccessLevel::Owner);
+        let character = bob.character.unwrap();
+        let mobile = loaded_world.mobile(character).unwrap();
+        assert_eq!(mobile.name, "Bob");
+        assert_eq!(mobile.notoriety, Notoriety::Murderer);
+        assert_eq!(mobile.mana, 40);
+        assert_eq!(mobile.template.as_deref(), Some("townsperson"));
+        assert_eq!(mobile.skills, world.mobile(character).unwrap().skills);
+        assert_eq!(mobile.food, 5);
+        assert_eq!(mobile.strength, 80);
+        assert_eq!(mobile.kills, 3);
+        assert!(loaded_accounts
+            .get_mut("alice")
+            .unwrap()
+            .character
+            .is_none());
+
+        let backpack = loaded_world.contents(character)[0];
+        let gold = loaded_world.contents(backpack)[0];
+        assert_eq!(loaded_world.items[&gold].amount, 500);
+        assert_eq!(loaded_world.items[&gold].name.as_deref(), Some("Gold"));
+        assert_eq!(loaded_world.items[&gold].template.as_deref(), Some("gold"));
+        assert_eq!(loaded_world.items[&gold].decays_at, None);
+        assert_eq!(
+            loaded_world.items[&backpack].decays_at,
+            Some(1_700_000_000_000)
+        );
+
+        assert_eq!(loaded_world.next_serials(), world.next_serials());
+    }
+
+    #[test]
+    fn it_round_trips_changes() {
+        let (accounts, mut world) = populated_world();
+
+        let (_, changes) =
+            deserialize_delta(&serialize_delta(&accounts, &world.take_changes()).unwrap()).unwrap();
+
+        assert_eq!(changes.mobiles.len(), 1);
+        assert_eq!(changes.items.len(), 2);
+        assert_eq!(changes.removed.len(), 1);
+    }
+
+    #[test]
+    fn it_hashes_passwords_saved_before_they_were_hashed() {
+        let mut bytes = vec![];
+        write_string(&mut bytes, "bob").unwrap();
+        write_string(&mut bytes, "secret").unwrap();
+        bytes.push(AccessLevel::Player as u8);
+        bytes.extend_from_slice(&NO_SERIAL.to_le_bytes());
+
+        let bob = read_account(&mut &bytes[..], 8).unwrap();
+
+        assert_ne!(bob.password_hash, "secret");
+        assert!(bob.check_password("secret"));
+    }
+
+    #[test]
+    fn it_rejects_files_that_arent_saves() {
+        assert!(deserialize(b"not a save").is_err());
+        let (accounts, mut world) = populated_world();
+        assert!(deserialize(&serialize_delta(&accounts, &world.take_changes()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn it_rejects_saves_from_newer_versions() {
+        let (accounts, world) = populated_world();
+        let mut bytes = serialize(&accounts, &world, 1).unwrap();
+        bytes[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+
+        assert!(deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn it_rejects_truncated_saves() {
+        let (accounts, world) = populated_world();
+        let bytes = serialize(&accounts, &world, 1).unwrap();
+
+        assert!(deserialize(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn it_refuses_to_save_strings_too_long_for_their_length() {
+        let (accounts, mut world) = populated_world();
+        world.add_mobile(&"a".repeat(70_000), 0x190, START_LOCATION);
+
+        assert!(serialize(&accounts, &world, 1).is_err());
+    }
+
+    #[test]
+    fn it_keeps_the_last_good_save_when_writing_fails() {
+        let directory = empty_directory("rust-uo-server-test-failed-save");
+        let path = directory.join("world.bin");
+
+        let (accounts, world) = populated_world();
+        write_atomically(&path, &serialize(&accounts, &world, 1).unwrap()).unwrap();
+
+        // A directory where the temporary file should go makes the write fail.
+        fs::create_dir(directory.join("world.tmp")).unwrap();
+        let empty = serialize(&Accounts::new(), &World::new(), 2).unwrap();
+        assert!(write_atomically(&path, &empty).is_err());
+
+        let mut storage = FlatFile::new(path.to_str().unwrap());
+        let (_, loaded_world) = storage.load().unwrap().unwrap();
+        assert_eq!(loaded_world.items.len(), 2);
+    }
+
+    #[test]
+    fn it_loads_the_latest_snapshot_and_the_deltas_since() {
+        let directory = empty_directory("rust-uo-server-test-flat-file");
+        let path = directory.join("world.bin");
+        let path = path.to_str().unwrap();
+
+        let mut storage = FlatFile::new(path);
+        assert!(storage.load().unwrap().is_none());
+
+        let (accounts, mut world) = populated_world();
+        let mut save = |world: &mut World, full| {
+            let changes = world.take_changes();
+            storage.save(&accounts, world, &changes, full).unwrap().full
+        };
+
+        // The first save has nothing to build on, so it's a snapshot.
+        assert!(save(&mut world, false));
+        let gold = world.add_item(0x0EED, START_LOCATION);
+        assert!(!save(&mut world, false));
+        world.remove(gold);
+        assert!(!save(&mut world, false));
+
+        let mut loaded = FlatFile::new(path);
+        let (_, loaded_world) = loaded.load().unwrap().unwrap();
+        assert_eq!((loaded.generation, loaded.deltas), (1, 2));
+        assert_eq!(loaded_world.items.len(), 2);
+        assert_eq!(loaded_world.next_serials(), world.next_serials());
+
+        // A new snapshot replaces the deltas.
+        world.add_item(0x0EED, START_LOCATION);
+        assert!(save(&mut world, true));
+
+        let mut loaded = FlatFile::new(path);
+        let (_, loaded_world) = loaded.load().unwrap().unwrap();
+        assert_eq!((loaded.generation, loaded.deltas), (2, 0));
+        assert_eq!(loaded_world.items.len(), 3);
+        assert!(delta_paths(Path::new(path)).unwrap().is_empty());
+    }
+}
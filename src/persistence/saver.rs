@@ -0,0 +1,154 @@
+use chrono::Local;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use super::backups::{self, Retention};
+use super::{SaveReport, Storage};
+use crate::accounts::Accounts;
+use crate::world::{Changes, World};
+
+// Settings read from the config for each save, so a reload takes effect on
+// the next save.
+pub struct Policy {
+    pub saves_per_snapshot: u32,
+    pub backup_path: String,
+    pub retention: Retention,
+}
+
+struct Request {
+    accounts: Accounts,
+    changes: Changes,
+    full: bool,
+    policy: Policy,
+    done_tx: mpsc::Sender<io::Result<SaveReport>>,
+}
+
+// Writes saves on a background thread, so the game only has to hold its
+// lock long enough to copy out what changed.
+pub struct Saver {
+    request_tx: mpsc::Sender<Request>,
+}
+
+impl Saver {
+    // `save_path` is only used to name backups.
+    pub fn start(
+        storage: Box<dyn Storage>,
+        save_path: &str,
+        accounts: &Accounts,
+        world: &World,
+    ) -> Saver {
+        let (request_tx, request_rx) = mpsc::channel();
+
+        let mut saver = SaverThread {
+            storage,
+            save_path: String::from(save_path),
+            accounts: accounts.clone(),
+            world: world.clone(),
+            saves_since_full: 0,
+            needs_full: false,
+        };
+        thread::spawn(move || {
+            for request in request_rx {
+                saver.save(request);
+            }
+        });
+
+        Saver { request_tx }
+    }
+
+    // Queues a save of the changes. The outcome is reported on the console;
+    // wait on the returned receiver to find out when it's done.
+    pub fn save(
+        &self,
+        accounts: Accounts,
+        changes: Changes,
+        full: bool,
+        policy: Policy,
+    ) -> mpsc::Receiver<io::Result<SaveReport>> {
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let request = Request {
+            accounts,
+            changes,
+            full,
+            policy,
+            done_tx,
+        };
+        if let Err(mpsc::SendError(request)) = self.request_tx.send(request) {
+            let error = io::Error::other("The save thread has stopped");
+            let _ = request.done_tx.send(Err(error));
+        }
+
+        done_rx
+    }
+}
+
+// The save thread's own copy of the world, kept up to date by applying each
+// save's changes, so full saves can be written without touching the game.
+struct SaverThread {
+    storage: Box<dyn Storage>,
+    save_path: String,
+    accounts: Accounts,
+    world: World,
+    saves_since_full: u32,
+    needs_full: bool,
+}
+
+impl SaverThread {
+    fn save(&mut self, request: Request) {
+        let started = Instant::now();
+
+        // Once a save has failed its changes are only in memory, so the
+        // next save has to write everything to get them onto disk.
+        let full = request.full
+            || self.needs_full
+            || self.saves_since_full >= request.policy.saves_per_snapshot;
+
+        self.accounts = request.accounts;
+        self.world.apply(&request.changes);
+
+        let result = self
+            .storage
+            .save(&self.accounts, &self.world, &request.changes, full);
+        self.needs_full = result.is_err();
+
+        if let Ok(written) = &result {
+            if written.full {
+                self.saves_since_full = 0;
+                self.back_up(&request.policy);
+            } else {
+                self.saves_since_full += 1;
+            }
+        }
+
+        let result = result.map(|written| SaveReport {
+            full: written.full,
+            size: written.size,
+            duration: started.elapsed(),
+        });
+        match &result {
+            Ok(report) => println!("World saved ({})", report),
+            Err(e) => println!("Error saving the world: {}", e),
+        }
+
+        let _ = request.done_tx.send(result);
+    }
+
+    // Only full saves are backed up. A failed backup is only reported, as
+    // the save itself has succeeded by then.
+    fn back_up(&mut self, policy: &Policy) {
+        let result = backups::back_up(
+            self.storage.as_mut(),
+            &self.save_path,
+            &policy.backup_path,
+            &policy.retention,
+            Local::now().naive_local(),
+        );
+
+        if let Err(e) = result {
+            println!("Error backing up the save: {}", e);
+        }
+    }
+}
@@ -0,0 +1,596 @@
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use super::{Storage, Written};
+use crate::accounts::{hash_password, AccessLevel, Account, Accounts};
+use crate::location::Location;
+use crate::skills::{Skill, SkillId, SkillLock, SKILL_COUNT};
+use crate::world::{Changes, Item, Mobile, Notoriety, Serial, World};
+
+// Each migration moves the schema up one version, which SQLite keeps for us
+// in `user_version`. Never change a migration once it has shipped; add a new
+// one to the end instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: The original schema.
+    "CREATE TABLE serials (
+         id INTEGER PRIMARY KEY CHECK (id = 0),
+         next_mobile INTEGER NOT NULL,
+         next_item INTEGER NOT NULL
+     );
+     CREATE TABLE accounts (
+         username TEXT NOT NULL,
+         password TEXT NOT NULL,
+         access_level INTEGER NOT NULL,
+         character INTEGER
+     );
+     CREATE UNIQUE INDEX accounts_username ON accounts (username);
+     CREATE TABLE mobiles (
+         serial INTEGER PRIMARY KEY,
+         name TEXT NOT NULL,
+         body INTEGER NOT NULL,
+         hue INTEGER NOT NULL,
+         x INTEGER NOT NULL,
+         y INTEGER NOT NULL,
+         z INTEGER NOT NULL,
+         hits INTEGER NOT NULL,
+         max_hits INTEGER NOT NULL
+     );
+     CREATE TABLE items (
+         serial INTEGER PRIMARY KEY,
+         name TEXT,
+         graphic INTEGER NOT NULL,
+         hue INTEGER NOT NULL,
+         amount INTEGER NOT NULL,
+         x INTEGER NOT NULL,
+         y INTEGER NOT NULL,
+         z INTEGER NOT NULL,
+         parent INTEGER
+     );
+     CREATE INDEX items_parent ON items (parent);",
+    // 2: Direction, notoriety, stamina and mana for mobiles.
+    "ALTER TABLE mobiles ADD COLUMN direction INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE mobiles ADD COLUMN notoriety INTEGER NOT NULL DEFAULT 1;
+     ALTER TABLE mobiles ADD COLUMN stamina INTEGER NOT NULL DEFAULT 100;
+     ALTER TABLE mobiles ADD COLUMN max_stamina INTEGER NOT NULL DEFAULT 100;
+     ALTER TABLE mobiles ADD COLUMN mana INTEGER NOT NULL DEFAULT 100;
+     ALTER TABLE mobiles ADD COLUMN max_mana INTEGER NOT NULL DEFAULT 100;",
+    // 3: The template each mobile and item was made from.
+    "ALTER TABLE mobiles ADD COLUMN template TEXT;
+     ALTER TABLE items ADD COLUMN template TEXT;",
+    // 4: Skills, with a row for each skill that isn't at its defaults.
+    "CREATE TABLE skills (
+         mobile INTEGER NOT NULL,
+         skill INTEGER NOT NULL,
+         base INTEGER NOT NULL,
+         cap INTEGER NOT NULL,
+         lock INTEGER NOT NULL,
+         PRIMARY KEY (mobile, skill)
+     );
+     ALTER TABLE mobiles ADD COLUMN total_skill_cap INTEGER NOT NULL DEFAULT 7000;",
+    // 5: How fed each mobile is.
+    "ALTER TABLE mobiles ADD COLUMN food INTEGER NOT NULL DEFAULT 20;",
+    // 6: Strength, dexterity and intelligence for mobiles.
+    "ALTER TABLE mobiles ADD COLUMN strength INTEGER NOT NULL DEFAULT 50;
+     ALTER TABLE mobiles ADD COLUMN dexterity INTEGER NOT NULL DEFAULT 50;
+     ALTER TABLE mobiles ADD COLUMN intelligence INTEGER NOT NULL DEFAULT 50;",
+    // 7: How many mobiles each mobile has helped kill.
+    "ALTER TABLE mobiles ADD COLUMN kills INTEGER NOT NULL DEFAULT 0;",
+    // 8: Salted password hashes. `hash_passwords` fills them in from the
+    // passwords as they were typed...
+    "ALTER TABLE accounts ADD COLUMN password_hash TEXT NOT NULL DEFAULT '';",
+    // 9: ...which are then dropped.
+    "ALTER TABLE accounts DROP COLUMN password;",
+    // 10: When items rot away, in milliseconds since the Unix epoch.
+    "ALTER TABLE items ADD COLUMN decays_at INTEGER;",
+];
+
+// The migration after which `hash_passwords` runs.
+const PASSWORD_HASH_VERSION: usize = 8;
+
+// Keeps the world in an SQLite database, one row per entity, so it can be
+// queried with the sqlite3 tool while the server is running or not.
+pub struct Sqlite {
+    path: PathBuf,
+    connection: Connection,
+}
+
+impl Sqlite {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(directory) = path.parent() {
+            if !directory.as_os_str().is_empty() {
+                fs::create_dir_all(directory)?;
+            }
+        }
+
+        let mut connection = Connection::open(&path).map_err(sql_error)?;
+        // Lets other tools read the database while the server writes to it.
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(sql_error)?;
+        migrate(&mut connection)?;
+
+        Ok(Sqlite { path, connection })
+    }
+
+    fn read(&self) -> rusqlite::Result<Option<(Accounts, World)>> {
+        let serials = self
+            .connection
+            .query_row("SELECT next_mobile, next_item FROM serials", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+        let Some((next_mobile_serial, next_item_serial)) = serials else {
+            return Ok(None);
+        };
+
+        let mut accounts = Accounts::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT username, password_hash, access_level, character FROM accounts")?;
+        let rows = statement.query_map([], |row| {
+            let access_level: u8 = row.get(2)?;
+            Ok(Account {
+                username: row.get(0)?,
+                password_hash: row.get(1)?,
+                access_level: AccessLevel::from_u8(access_level).ok_or(
+                    rusqlite::Error::IntegralValueOutOfRange(2, access_level.into()),
+                )?,
+                character: row.get(3)?,
+            })
+        })?;
+        for account in rows {
+            accounts.insert(account?);
+        }
+
+        let mut mobiles = HashMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT serial, name, body, hue, x, y, z, hits, max_hits,
+                        direction, notoriety, stamina, max_stamina, mana, max_mana,
+                        template, total_skill_cap, food, strength, dexterity,
+                        intelligence, kills
+                 FROM mobiles",
+        )?;
+        let rows = statement.query_map([], |row| {
+            let name: String = row.get(1)?;
+            let location = Location {
+                x: row.get(4)?,
+                y: row.get(5)?,
+                z: row.get(6)?,
+            };
+
+            let mut mobile = Mobile::new(row.get(0)?, &name, row.get(2)?, location);
+            mobile.hue = row.get(3)?;
+            mobile.hits = row.get(7)?;
+            mobile.max_hits = row.get(8)?;
+            mobile.direction = row.get(9)?;
+            let notoriety: u8 = row.get(10)?;
+            mobile.notoriety = Notoriety::from_u8(notoriety).ok_or(
+                rusqlite::Error::IntegralValueOutOfRange(10, notoriety.into()),
+            )?;
+            mobile.stamina = row.get(11)?;
+            mobile.max_stamina = row.get(12)?;
+            mobile.mana = row.get(13)?;
+            mobile.max_mana = row.get(14)?;
+            mobile.template = row.get(15)?;
+            mobile.skills.total_cap = row.get(16)?;
+            mobile.food = row.get(17)?;
+            mobile.strength = row.get(18)?;
+            mobile.dexterity = row.get(19)?;
+            mobile.intelligence = row.get(20)?;
+            mobile.kills = row.get(21)?;
+            Ok(mobile)
+        })?;
+        for mobile in rows {
+            let mobile = mobile?;
+            mobiles.insert(mobile.serial, mobile);
+        }
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT mobile, skill, base, cap, lock FROM skills")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let serial: Serial = row.get(0)?;
+            let Some(mobile) = mobiles.get_mut(&serial) else {
+                continue;
+            };
+            let id: usize = row.get(1)?;
+            if id >= SKILL_COUNT {
+                return Err(rusqlite::Error::IntegralValueOutOfRange(1, id as i64));
+            }
+            let skill = mobile.skills.get_mut(id as SkillId);
+            skill.base = row.get(2)?;
+            skill.cap = row.get(3)?;
+            let lock: u8 = row.get(4)?;
+            skill.lock = SkillLock::from_u8(lock)
+                .ok_or(rusqlite::Error::IntegralValueOutOfRange(4, lock.into()))?;
+        }
+        drop(rows);
+
+        let mut items = HashMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT serial, name, graphic, hue, amount, x, y, z, parent, template, decays_at
+             FROM items",
+        )?;
+        let rows = statement.query_map([], |row| {
+            let location = Location {
+                x: row.get(5)?,
+                y: row.get(6)?,
+                z: row.get(7)?,
+            };
+
+            let mut item = Item::new(row.get(2)?, location);
+            item.name = row.get(1)?;
+            item.hue = row.get(3)?;
+            item.amount = row.get(4)?;
+            item.parent = row.get(8)?;
+            item.template = row.get(9)?;
+            item.decays_at = row.get(10)?;
+            Ok((row.get(0)?, item))
+        })?;
+        for item in rows {
+            let (serial, item) = item?;
+            items.insert(serial, item);
+        }
+
+        let world = World::restore(next_mobile_serial, next_item_serial, mobiles, items);
+
+        Ok(Some((accounts, world)))
+    }
+
+    fn write(
+        &mut self,
+        accounts: &Accounts,
+        world: &World,
+        changes: &Changes,
+        full: bool,
+    ) -> rusqlite::Result<()> {
+        // Nothing is visible to anyone else until the commit, and nothing is
+        // written at all if any of it fails.
+        let transaction = self.connection.transaction()?;
+
+        let (next_mobile_serial, next_item_serial) = world.next_serials();
+        transaction.execute(
+            "INSERT OR REPLACE INTO serials (id, next_mobile, next_item) VALUES (0, ?1, ?2)",
+            params![next_mobile_serial, next_item_serial],
+        )?;
+
+        // Accounts are few and small, so they're always written in full.
+        transaction.execute("DELETE FROM accounts", [])?;
+        let mut statement = transaction.prepare(
+            "INSERT INTO accounts (username, password_hash, access_level, character)
+             VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for account in accounts.iter() {
+            statement.execute(params![
+                account.username,
+                account.password_hash,
+                account.access_level as u8,
+                account.character
+            ])?;
+        }
+        drop(statement);
+
+        if full {
+            transaction.execute("DELETE FROM mobiles", [])?;
+            transaction.execute("DELETE FROM items", [])?;
+            transaction.execute("DELETE FROM skills", [])?;
+            write_mobiles(&transaction, world.mobiles.values())?;
+            write_items(
+                &transaction,
+                world.items.iter().map(|(&serial, item)| (serial, item)),
+            )?;
+        } else {
+            write_mobiles(&transaction, changes.mobiles.iter())?;
+            write_items(
+                &transaction,
+                changes.items.iter().map(|(serial, item)| (*serial, item)),
+            )?;
+
+            let mut statement = transaction.prepare("DELETE FROM mobiles WHERE serial = ?1")?;
+            for serial in &changes.removed {
+                statement.execute([serial])?;
+            }
+            let mut statement = transaction.prepare("DELETE FROM items WHERE serial = ?1")?;
+            for serial in &changes.removed {
+                statement.execute([serial])?;
+            }
+            let mut statement = transaction.prepare("DELETE FROM skills WHERE mobile = ?1")?;
+            for serial in &changes.removed {
+                statement.execute([serial])?;
+            }
+        }
+
+        transaction.commit()
+    }
+}
+
+impl Storage for Sqlite {
+    fn load(&mut self) -> io::Result<Option<(Accounts, World)>> {
+        self.read().map_err(sql_error)
+    }
+
+    fn save(
+        &mut self,
+        accounts: &Accounts,
+        world: &World,
+        changes: &Changes,
+        full: bool,
+    ) -> io::Result<Written> {
+        self.write(accounts, world, changes, full)
+            .map_err(sql_error)?;
+
+        let size = fs::metadata(&self.path)?.len() as usize;
+
+        Ok(Written { full, size })
+    }
+
+    fn back_up(&mut self, path: &Path) -> io::Result<()> {
+        // VACUUM INTO won't overwrite a file, e.g. a backup taken earlier in
+        // the same second.
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        self.connection
+            .execute("VACUUM INTO ?1", [path.to_string_lossy()])
+            .map(|_| ())
+            .map_err(sql_error)
+    }
+}
+
+fn migrate(connection: &mut Connection) -> io::Result<()> {
+    let version: usize = connection
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(sql_error)?;
+
+    if version > MIGRATIONS.len() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "The database is schema version {} but this server only understands up to version {}",
+                version,
+                MIGRATIONS.len()
+            ),
+        ));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction().map_err(sql_error)?;
+        transaction.execute_batch(migration).map_err(sql_error)?;
+        if index + 1 == PASSWORD_HASH_VERSION {
+            hash_passwords(&transaction).map_err(sql_error)?;
+        }
+        transaction
+            .pragma_update(None, "user_version", index + 1)
+            .map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)?;
+    }
+
+    Ok(())
+}
+
+fn write_mobiles<'a>(
+    transaction: &Transaction,
+    mobiles: impl Iterator<Item = &'a Mobile>,
+) -> rusqlite::Result<()> {
+    let mut statement = transaction.prepare(
+        "INSERT OR REPLACE INTO mobiles (serial, name, body, hue, x, y, z, hits, max_hits,
+                                         direction, notoriety, stamina, max_stamina, mana,
+                                         max_mana, template, total_skill_cap, food,
+                                         strength, dexterity, intelligence, kills)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                 ?18, ?19, ?20, ?21, ?22)",
+    )?;
+    let mut delete_skills = transaction.prepare("DELETE FROM skills WHERE mobile = ?1")?;
+    let mut insert_skill = transaction.prepare(
+        "INSERT INTO skills (mobile, skill, base, cap, lock) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+
+    for mobile in mobiles {
+        statement.execute(params![
+            mobile.serial,
+            mobile.name,
+            mobile.body,
+            mobile.hue,
+            mobile.location.x,
+            mobile.location.y,
+            mobile.location.z,
+            mobile.hits,
+            mobile.max_hits,
+            mobile.direction,
+            mobile.notoriety.to_u8(),
+            mobile.stamina,
+            mobile.max_stamina,
+            mobile.mana,
+            mobile.max_mana,
+            mobile.template,
+            mobile.skills.total_cap,
+            mobile.food,
+            mobile.strength,
+            mobile.dexterity,
+            mobile.intelligence,
+            mobile.kills
+        ])?;
+
+        delete_skills.execute([mobile.serial])?;
+        for (id, skill) in mobile.skills.iter() {
+            if *skill != Skill::default() {
+                insert_skill.execute(params![
+                    mobile.serial,
+                    id,
+                    skill.base,
+                    skill.cap,
+                    skill.lock.to_u8()
+                ])?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_items<'a>(
+    transaction: &Transaction,
+    items: impl Iterator<Item = (Serial, &'a Item)>,
+) -> rusqlite::Result<()> {
+    let mut statement = transaction.prepare(
+        "INSERT OR REPLACE INTO items (serial, name, graphic, hue, amount, x, y, z, parent,
+                                       template, decays_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    )?;
+
+    for (serial, item) in items {
+        statement.execute(params![
+            serial,
+            item.name,
+            item.graphic,
+            item.hue,
+            item.amount,
+            item.location.x,
+            item.location.y,
+            item.location.z,
+            item.parent,
+            item.template,
+            item.decays_at
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn hash_passwords(transaction: &Transaction) -> rusqlite::Result<()> {
+    let mut statement = transaction.prepare("SELECT username, password FROM accounts")?;
+    let passwords = statement
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (username, password) in passwords {
+        transaction.execute(
+            "UPDATE accounts SET password_hash = ?1 WHERE username = ?2",
+            params![hash_password(&password), username],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+    use crate::persistence::tests::{empty_directory, populated_world};
+
+    #[test]
+    fn it_saves_everything_then_just_the_changes() {
+        let directory = empty_directory("rust-uo-server-test-sqlite");
+        let path = directory.join("world.db");
+        let path = path.to_str().unwrap();
+
+        let mut storage = Sqlite::open(path).unwrap();
+        assert!(storage.load().unwrap().is_none());
+
+        let (accounts, mut world) = populated_world();
+        let changes = world.take_changes();
+        storage.save(&accounts, &world, &changes, true).unwrap();
+
+        let gold = world.add_item(0x0EED, START_LOCATION);
+        world.item_mut(gold).unwrap().amount = 100;
+        let changes = world.take_changes();
+        storage.save(&accounts, &world, &changes, false).unwrap();
+
+        let (mut loaded_accounts, loaded_world) =
+            Sqlite::open(path).unwrap().load().unwrap().unwrap();
+        assert!(loaded_accounts
+            .get_mut("bob")
+            .unwrap()
+            .check_password("secret"));
+        assert_eq!(loaded_world.items.len(), 3);
+        assert_eq!(loaded_world.items[&gold].amount, 100);
+        assert!(loaded_world
+            .items
+            .values()
+            .any(|item| item.decays_at == Some(1_700_000_000_000)));
+        assert_eq!(loaded_world.next_serials(), world.next_serials());
+        let mobile = loaded_world.mobiles.values().next().unwrap();
+        assert_eq!(mobile.notoriety, Notoriety::Murderer);
+        assert_eq!(mobile.mana, 40);
+        assert_eq!(mobile.template.as_deref(), Some("townsperson"));
+        assert_eq!(mobile.skills, world.mobiles[&mobile.serial].skills);
+        assert_eq!(mobile.food, 5);
+        assert_eq!(mobile.strength, 80);
+        assert_eq!(mobile.kills, 3);
+
+        world.remove(gold);
+        let changes = world.take_changes();
+        storage.save(&accounts, &world, &changes, false).unwrap();
+
+        let (_, loaded_world) = Sqlite::open(path).unwrap().load().unwrap().unwrap();
+        assert_eq!(loaded_world.items.len(), 2);
+    }
+
+    #[test]
+    fn it_migrates_new_databases_to_the_latest_schema() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        // Running the migrations again leaves the schema alone.
+        migrate(&mut connection).unwrap();
+    }
+
+    #[test]
+    fn it_hashes_passwords_saved_before_they_were_hashed() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        let transaction = connection.transaction().unwrap();
+        for migration in &MIGRATIONS[..PASSWORD_HASH_VERSION - 1] {
+            transaction.execute_batch(migration).unwrap();
+        }
+        transaction
+            .pragma_update(None, "user_version", PASSWORD_HASH_VERSION - 1)
+            .unwrap();
+        transaction
+            .execute(
+                "INSERT INTO accounts (username, password, access_level) VALUES ('bob', 'secret', 0)",
+                [],
+            )
+            .unwrap();
+        transaction.commit().unwrap();
+
+        migrate(&mut connection).unwrap();
+
+        let password_hash: String = connection
+            .query_row("SELECT password_hash FROM accounts", [], |row| row.get(0))
+            .unwrap();
+        let bob = Account {
+            password_hash,
+            ..Account::new("bob", "", AccessLevel::Player)
+        };
+        assert!(bob.check_password("secret"));
+    }
+
+    #[test]
+    fn it_refuses_databases_from_newer_versions() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+
+        assert!(migrate(&mut connection).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use crate::entity_timers::{self, Action, EntityTimer};
+use crate::shard::Shard;
+use crate::skills;
+use crate::state::{MobileProperty, StateDelta};
+use crate::timer::{Repeat, TimerHandle};
+use crate::world::{Mobile, Serial, MAX_FOOD};
+
+// How long a mobile with 100 of a stat takes to get a point of it back, in
+// milliseconds. Bigger pools refill proportionally faster, so every mobile
+// takes about as long to go from empty to full.
+const HITS_INTERVAL: f64 = 11_000.0;
+const STAMINA_INTERVAL: f64 = 7_000.0;
+const MANA_INTERVAL: f64 = 7_000.0;
+const SHORTEST_INTERVAL: i64 = 100;
+
+const STATS: [MobileProperty; 3] = [
+    MobileProperty::Hits,
+    MobileProperty::Stamina,
+    MobileProperty::Mana,
+];
+
+// The regeneration timer for each stat of each mobile that's below its
+// maximum, along with the interval it was started with.
+#[derive(Default)]
+pub struct Regeneration {
+    timers: HashMap<(Serial, MobileProperty), (TimerHandle, i64)>,
+}
+
+// Starts, stops or changes the pace of the regeneration timers of the
+// given mobiles, to match how they are now. The game loop calls this every
+// tick for the mobiles that changed, so stats stop regenerating when
+// they're full and start again when they aren't.
+pub fn wake(shard: &mut Shard, serials: impl IntoIterator<Item = Serial>) {
+    // The timers of removed mobiles were cancelled along with them.
+    shard
+        .regeneration
+        .timers
+        .retain(|_, (timer, _)| timer.is_active());
+
+    for serial in serials {
+        for stat in STATS {
+            let wanted = shard
+                .world
+                .mobile(serial)
+                .and_then(|mobile| interval(mobile, stat));
+            let running = shard.regeneration.timers.get(&(serial, stat));
+            if running.map(|&(_, interval)| interval) == wanted {
+                continue;
+            }
+
+            if let Some((timer, _)) = shard.regeneration.timers.remove(&(serial, stat)) {
+                timer.cancel();
+            }
+            let Some(interval) = wanted else {
+                continue;
+            };
+            let timer = entity_timers::start(
+                shard,
+                EntityTimer {
+                    serial,
+                    repeat: Repeat::Forever,
+                    interval,
+                    delay: interval,
+                    action: Action::MobileDeltas(vec![StateDelta::new(stat, 1)]),
+                },
+            );
+            if let Some(timer) = timer {
+                shard
+                    .regeneration
+                    .timers
+                    .insert((serial, stat), (timer, interval));
+            }
+        }
+    }
+}
+
+// How often the mobile gets back a point of the stat, or None if it
+// shouldn't, because the stat is full or the mobile is dead.
+fn interval(mobile: &Mobile, stat: MobileProperty) -> Option<i64> {
+    if mobile.hits == 0 {
+        return None;
+    }
+
+    // Hunger slows hits and stamina, down to half speed when starving.
+    let fed = 0.5 + 0.5 * mobile.food as f64 / MAX_FOOD as f64;
+    let skill = |name| skills::to_points(mobile.skills.get(skills::by_name(name).unwrap()).value());
+
+    let (current, max, interval, pace) = match stat {
+        MobileProperty::Hits => (mobile.hits, mobile.max_hits, HITS_INTERVAL, fed),
+        // Focus doubles it at 100.
+        MobileProperty::Stamina => (
+            mobile.stamina,
+            mobile.max_stamina,
+            STAMINA_INTERVAL,
+            fed * (1.0 + skill("focus") / 100.0),
+        ),
+        // Meditation triples it at 100.
+        MobileProperty::Mana => (
+            mobile.mana,
+            mobile.max_mana,
+            MANA_INTERVAL,
+            1.0 + 2.0 * skill("meditation") / 100.0,
+        ),
+        _ => return None,
+    };
+    if current >= max {
+        return None;
+    }
+
+    let size = max as f64 / 100.0;
+    Some(((interval / (size * pace)) as i64).max(SHORTEST_INTERVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Commands;
+    use crate::game_loop;
+    use crate::location::START_LOCATION;
+    use crate::shard::tests::shard_with_clock;
+    use crate::state::State;
+
+    #[test]
+    fn it_regenerates_until_full_then_stops() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("regeneration");
+        let commands = Commands::new();
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let mobile = shard.world.mobile_mut(bob).unwrap();
+        mobile.update_state(&[StateDelta::new(MobileProperty::Hits, -2)]);
+        game_loop::tick(&mut shard, &messages_rx, &commands);
+        assert_eq!(shard.regeneration.timers.len(), 1);
+
+        for expected in [99, 100] {
+            clock.advance(11_000);
+            shard.timers.flush();
+            game_loop::tick(&mut shard, &messages_rx, &commands);
+            assert_eq!(shard.world.mobile(bob).unwrap().hits, expected);
+        }
+        assert!(shard.regeneration.timers.is_empty());
+    }
+
+    #[test]
+    fn it_paces_regeneration_by_pool_size_food_and_skills() {
+        let mut mobile = Mobile::new(1, "Bob", 0x190, START_LOCATION);
+        assert_eq!(interval(&mobile, MobileProperty::Hits), None);
+
+        mobile.hits = 50;
+        mobile.stamina = 50;
+        mobile.mana = 50;
+        assert_eq!(interval(&mobile, MobileProperty::Hits), Some(11_000));
+        mobile.max_hits = 200;
+        assert_eq!(interval(&mobile, MobileProperty::Hits), Some(5_500));
+
+        mobile.food = 0;
+        assert_eq!(interval(&mobile, MobileProperty::Hits), Some(11_000));
+        assert_eq!(interval(&mobile, MobileProperty::Stamina), Some(14_000));
+        mobile.set_skill(skills::by_name("focus").unwrap(), 1000);
+        assert_eq!(interval(&mobile, MobileProperty::Stamina), Some(7_000));
+
+        mobile.set_skill(skills::by_name("meditation").unwrap(), 1000);
+        assert_eq!(interval(&mobile, MobileProperty::Mana), Some(2_333));
+
+        // The dead don't regenerate.
+        mobile.hits = 0;
+        assert_eq!(interval(&mobile, MobileProperty::Mana), None);
+    }
+}
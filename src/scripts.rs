@@ -0,0 +1,464 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::ptr;
+use std::sync::Arc;
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+
+use crate::accounts::AccessLevel;
+use crate::events::SubscriptionId;
+use crate::shard::Shard;
+use crate::timer::TimerHandle;
+
+mod api;
+mod events;
+
+// How much work a script can do each time it's called before it's stopped,
+// so a script stuck in a loop can't hold up the game loop.
+const MAX_OPERATIONS: u64 = 100_000;
+
+// A function in one of the scripts. It's looked up by name each time it's
+// called, so whatever holds on to one, e.g. a timer, calls the latest
+// version after a reload.
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub script: String,
+    pub name: String,
+}
+
+// A command written in a script. Builtin commands win if the names clash.
+#[derive(Clone)]
+pub struct ScriptCommand {
+    pub name: String,
+    pub access_level: AccessLevel,
+    pub usage: String,
+    pub description: String,
+    pub function: Function,
+}
+
+// An event handler a script registered, waiting for the script to be
+// installed.
+struct Subscription {
+    subscribe: events::Subscribe,
+    priority: i32,
+    function: Function,
+}
+
+// Scripts that have been compiled and run, along with everything they
+// registered, ready to be installed.
+#[derive(Default)]
+pub struct Loaded {
+    scripts: HashMap<String, Arc<AST>>,
+    subscriptions: Vec<Subscription>,
+    commands: Vec<ScriptCommand>,
+}
+
+impl Loaded {
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    fn has_function(&self, script: &str, name: &str) -> bool {
+        self.scripts
+            .get(script)
+            .is_some_and(|ast| ast.iter_functions().any(|function| function.name == name))
+    }
+}
+
+// The script engine and the scripts installed in it. Scripts can only get
+// at the server through the functions in `api`.
+pub struct Scripts {
+    engine: Arc<Engine>,
+    installed: Loaded,
+    // The event subscriptions made for the installed scripts, for taking
+    // them back out when they're replaced.
+    subscriptions: Vec<SubscriptionId>,
+    timers: Vec<TimerHandle>,
+}
+
+impl Scripts {
+    pub fn new() -> Self {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(64 * 1024)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000)
+            // Scripts can't read other files, or run code they've built
+            // out of strings.
+            .set_module_resolver(DummyModuleResolver::new())
+            .disable_symbol("eval");
+        api::register(&mut engine);
+
+        Scripts {
+            engine: Arc::new(engine),
+            installed: Loaded::default(),
+            subscriptions: vec![],
+            timers: vec![],
+        }
+    }
+
+    pub fn command(&self, name: &str) -> Option<&ScriptCommand> {
+        self.installed
+            .commands
+            .iter()
+            .find(|command| command.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = &ScriptCommand> {
+        self.installed.commands.iter()
+    }
+
+    // Loads every .rhai file in `directory`, in name order, without
+    // touching the installed scripts. A missing directory means no scripts.
+    // Fails with every error found if any script fails to load.
+    pub fn load(&self, directory: &str) -> Result<Loaded, Vec<String>> {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Loaded::default()),
+            Err(e) => return Err(vec![format!("{}: {}", directory, e)]),
+        };
+
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "rhai")
+            })
+            .collect();
+        paths.sort();
+
+        let mut errors = vec![];
+        let sources = paths.iter().filter_map(|path| {
+            let name = path.to_string_lossy().into_owned();
+            match fs::read_to_string(path) {
+                Ok(source) => Some((name, source)),
+                Err(e) => {
+                    errors.push(format!("{}: {}", name, e));
+                    None
+                }
+            }
+        });
+        let loaded = self.load_sources(sources.collect());
+
+        match loaded {
+            Ok(loaded) if errors.is_empty() => Ok(loaded),
+            Ok(_) => Err(errors),
+            Err(mut load_errors) => {
+                errors.append(&mut load_errors);
+                Err(errors)
+            }
+        }
+    }
+
+    // Compiles each script and runs its top level, which is where it
+    // registers event handlers and commands. The world can't be reached from
+    // there, so loading changes nothing until the scripts are installed.
+    fn load_sources(&self, sources: Vec<(String, String)>) -> Result<Loaded, Vec<String>> {
+        let mut errors = vec![];
+        LOADING.set(Some(Loaded::default()));
+
+        for (path, source) in sources {
+            let mut ast = match self.engine.compile(&source) {
+                Ok(ast) => ast,
+                Err(e) => {
+                    errors.push(format!("{}: {}", path, e));
+                    continue;
+                }
+            };
+            ast.set_source(path.as_str());
+            let ast = Arc::new(ast);
+            LOADING.with_borrow_mut(|loading| {
+                if let Some(loading) = loading {
+                    loading.scripts.insert(path.clone(), Arc::clone(&ast));
+                }
+            });
+
+            if let Err(e) = self.engine.run_ast_with_scope(&mut Scope::new(), &ast) {
+                errors.push(describe(&path, &e));
+            }
+        }
+
+        let loaded = LOADING.take().unwrap_or_default();
+        if errors.is_empty() {
+            Ok(loaded)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Swaps the loaded scripts in for the installed ones, all at once. Timers
+// the old scripts started carry on, and call the new versions of their
+// functions when they fire.
+pub fn install(shard: &mut Shard, loaded: Loaded) {
+    for id in shard.scripts.subscriptions.drain(..) {
+        shard.events.unsubscribe(id);
+    }
+
+    shard.scripts.subscriptions = loaded
+        .subscriptions
+        .iter()
+        .map(|subscription| {
+            (subscription.subscribe)(
+                &mut shard.events,
+                subscription.priority,
+                subscription.function.clone(),
+            )
+        })
+        .collect();
+    shard.scripts.installed = loaded;
+}
+
+// Calls a script function, with `this` bound to `this` if it's given.
+// Errors are reported on the console rather than passed on, so a broken
+// script can't take anything else down with it. Returns None if it failed.
+pub fn call(
+    shard: &mut Shard,
+    function: &Function,
+    this: Option<&mut Dynamic>,
+    args: Vec<Dynamic>,
+) -> Option<Dynamic> {
+    let engine = Arc::clone(&shard.scripts.engine);
+    let Some(ast) = shard
+        .scripts
+        .installed
+        .scripts
+        .get(&function.script)
+        .cloned()
+    else {
+        println!(
+            "Error in script {}: it isn't loaded any more, so {} can't be called",
+            function.script, function.name
+        );
+        return None;
+    };
+
+    // The top level only runs when the script is loaded.
+    let mut options = CallFnOptions::new().eval_ast(false);
+    if let Some(this) = this {
+        options = options.bind_this_ptr(this);
+    }
+
+    let result = lend(shard, || {
+        engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &ast,
+            &function.name,
+            args,
+        )
+    });
+
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            println!("Error in script {}", describe(&function.script, &e));
+            None
+        }
+    }
+}
+
+// Rhai puts the line and position at the end of the message.
+fn describe(path: &str, error: &EvalAltResult) -> String {
+    format!("{}: {}", path, error)
+}
+
+thread_local! {
+    // What the scripts being loaded on this thread have registered so far.
+    static LOADING: RefCell<Option<Loaded>> = const { RefCell::new(None) };
+
+    // The shard, while a script is running on this thread.
+    static SHARD: Cell<*mut Shard> = const { Cell::new(ptr::null_mut()) };
+}
+
+// Gives `f` what the scripts being loaded have registered so far.
+fn with_loading<T>(
+    f: impl FnOnce(&mut Loaded) -> Result<T, Box<EvalAltResult>>,
+) -> Result<T, Box<EvalAltResult>> {
+    LOADING.with_borrow_mut(|loading| match loading {
+        Some(loading) => f(loading),
+        None => Err("That can only be done at the top level of a script".into()),
+    })
+}
+
+// Puts the cell back how it was, even if the script panicked.
+struct Restore(*mut Shard);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        SHARD.set(self.0);
+    }
+}
+
+// Script functions have to be 'static, so they can't borrow the shard.
+// Instead it's lent to them through SHARD while `run` runs a script.
+fn lend<T>(shard: &mut Shard, run: impl FnOnce() -> T) -> T {
+    let _restore = Restore(SHARD.replace(shard));
+    run()
+}
+
+// Gives `f` the shard lent to the running script. The shard is taken out
+// of SHARD while `f` has it, so there's only ever one reference to it in
+// use. Anything `f` does that runs scripts lends them `f`'s reference.
+fn with_shard<T>(
+    f: impl FnOnce(&mut Shard) -> Result<T, Box<EvalAltResult>>,
+) -> Result<T, Box<EvalAltResult>> {
+    let shard = SHARD.replace(ptr::null_mut());
+    let _restore = Restore(shard);
+    if shard.is_null() {
+        return Err("The world can't be reached while scripts are loading".into());
+    }
+
+    // SAFETY: the pointer came from the `&mut Shard` given to `lend`, which
+    // lives until the script returns, and `lend` doesn't touch it while the
+    // script runs. Taking it out of SHARD above means nothing else can use
+    // it until `f` returns.
+    f(unsafe { &mut *shard })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combat;
+    use crate::commands::{Caller, Commands};
+    use crate::game_loop;
+    use crate::location::START_LOCATION;
+    use crate::shard::tests::{shard, shard_with_clock};
+
+    fn install_sources(shard: &mut Shard, sources: &[(&str, &str)]) -> Result<(), Vec<String>> {
+        let sources = sources
+            .iter()
+            .map(|&(path, source)| (String::from(path), String::from(source)))
+            .collect();
+        let loaded = shard.scripts.load_sources(sources)?;
+        install(shard, loaded);
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_errors_with_the_file_and_line() {
+        let (mut shard, _) = shard("scripts-errors");
+
+        let errors = install_sources(
+            &mut shard,
+            &[
+                ("broken.rhai", "let x = 1;\nx +"),
+                ("failing.rhai", "\nset(1, \"name\", \"Bob\");"),
+            ],
+        )
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("broken.rhai: "), "{}", errors[0]);
+        assert!(errors[0].contains("line 2"), "{}", errors[0]);
+        assert!(errors[1].starts_with("failing.rhai: "), "{}", errors[1]);
+        assert!(errors[1].contains("line 2"), "{}", errors[1]);
+    }
+
+    #[test]
+    fn it_stops_scripts_that_never_finish() {
+        let (mut shard, _) = shard("scripts-loop");
+
+        assert!(install_sources(&mut shard, &[("loop.rhai", "loop {}")]).is_err());
+    }
+
+    #[test]
+    fn it_lets_scripts_change_and_cancel_events() {
+        let (mut shard, _) = shard("scripts-events");
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let source = r#"
+            on("damage", "armour");
+            on("damage", -100, "invulnerable");
+
+            fn armour() {
+                this.amount /= 2;
+            }
+
+            fn invulnerable() {
+                if mobile(this.target).name == "Lord British" {
+                    return false;
+                }
+            }
+        "#;
+        install_sources(&mut shard, &[("damage.rhai", source)]).unwrap();
+
+        assert_eq!(combat::damage(&mut shard, bob, None, 10), 5);
+
+        shard.world.mobile_mut(bob).unwrap().name = String::from("Lord British");
+        assert_eq!(combat::damage(&mut shard, bob, None, 10), 0);
+        assert_eq!(shard.world.mobile(bob).unwrap().hits, 95);
+    }
+
+    #[test]
+    fn it_runs_commands_from_scripts() {
+        let (mut shard, _) = shard("scripts-commands");
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let source = r#"
+            command("rename", "gm", "<serial> <name>", "Renames a mobile.", "rename");
+
+            fn rename(caller, args) {
+                set(parse_int(args[0]), "name", args[1]);
+            }
+        "#;
+        install_sources(&mut shard, &[("rename.rhai", source)]).unwrap();
+
+        let command_line = format!("rename {} Robert", bob);
+        Commands::new().execute(&mut shard, Caller::Console, &command_line);
+
+        assert_eq!(shard.world.mobile(bob).unwrap().name, "Robert");
+    }
+
+    #[test]
+    fn it_keeps_the_old_scripts_if_any_fail_to_reload() {
+        let (mut shard, _) = shard("scripts-reload");
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let halve = r#"
+            on("damage", "halve");
+            fn halve() { this.amount /= 2; }
+        "#;
+        let add_one = r#"
+            on("damage", "add_one");
+            fn add_one() { this.amount += 1; }
+        "#;
+        install_sources(&mut shard, &[("damage.rhai", halve)]).unwrap();
+
+        let reload = [("damage.rhai", add_one), ("broken.rhai", "on(")];
+        assert!(install_sources(&mut shard, &reload).is_err());
+        assert_eq!(combat::damage(&mut shard, bob, None, 10), 5);
+
+        install_sources(&mut shard, &[("damage.rhai", add_one)]).unwrap();
+        assert_eq!(combat::damage(&mut shard, bob, None, 10), 11);
+    }
+
+    #[test]
+    fn it_rebinds_timers_to_reloaded_functions() {
+        let (mut shard, messages_rx, clock) = shard_with_clock("scripts-timers");
+        let commands = Commands::new();
+        let bob = shard.world.add_mobile("Bob", 0x190, START_LOCATION);
+        let version = |name: &str| {
+            format!(
+                r#"
+                command("later", "gm", "<serial>", "Renames a mobile later.", "later");
+                fn later(caller, args) {{ after(1000, "rename", parse_int(args[0])); }}
+                fn rename(serial) {{ set(serial, "name", "{}"); }}
+                "#,
+                name
+            )
+        };
+        install_sources(&mut shard, &[("later.rhai", &version("Old"))]).unwrap();
+        commands.execute(&mut shard, Caller::Console, &format!("later {}", bob));
+
+        install_sources(&mut shard, &[("later.rhai", &version("New"))]).unwrap();
+        clock.advance(1000);
+        shard.timers.flush();
+        game_loop::tick(&mut shard, &messages_rx, &commands);
+
+        assert_eq!(shard.world.mobile(bob).unwrap().name, "New");
+    }
+}
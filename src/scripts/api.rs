@@ -0,0 +1,555 @@
+// The functions scripts can call. Entities are passed around by serial, and
+// scripts read them as maps that are copies, so the only way to change the
+// world is through the functions here. A script's top level only registers
+// things; the world can't be reached until its functions are called.
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, Map, NativeCallContext, FLOAT, INT};
+
+use super::{events, with_loading, with_shard, Function, Loaded, ScriptCommand, Subscription};
+use crate::combat;
+use crate::events::{self as game_events, Move};
+use crate::location::Location;
+use crate::sessions::SessionId;
+use crate::shard::Shard;
+use crate::skills::{self, SkillId};
+use crate::speech;
+use crate::state::{ItemProperty, MobileProperty, State, StateDelta};
+use crate::timer::{Late, Repeat, Timer};
+use crate::world::{self, Item, Mobile, Serial};
+
+type Result<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+pub fn register(engine: &mut Engine) {
+    // The world
+    engine.register_fn("exists", exists);
+    engine.register_fn("mobile", mobile);
+    engine.register_fn("item", item);
+    engine.register_fn("mobiles_near", mobiles_near);
+    engine.register_fn("set", set);
+    engine.register_fn("adjust", adjust);
+    engine.register_fn("move_to", move_to);
+    engine.register_fn("add_mobile", add_mobile);
+    engine.register_fn("add_mobile", add_mobile_from_template);
+    engine.register_fn("add_item", add_item);
+    engine.register_fn("add_item", add_item_from_template);
+    engine.register_fn("remove", remove);
+    engine.register_fn("damage", |target: INT, amount: INT| {
+        damage(target, Dynamic::UNIT, amount)
+    });
+    engine.register_fn("damage", damage);
+
+    // Skills
+    engine.register_fn("skill", skill);
+    engine.register_fn("check_skill", check_skill);
+
+    // Events and commands
+    engine.register_fn(
+        "on",
+        |context: NativeCallContext, event: &str, function: &str| on(context, event, 0, function),
+    );
+    engine.register_fn("on", on);
+    engine.register_fn("command", command);
+
+    // Timers
+    engine.register_fn(
+        "after",
+        |context: NativeCallContext, delay: INT, function: &str| {
+            after(context, delay, function, None)
+        },
+    );
+    engine.register_fn(
+        "after",
+        |context: NativeCallContext, delay: INT, function: &str, arg: Dynamic| {
+            after(context, delay, function, Some(arg))
+        },
+    );
+    engine.register_fn("cancel_timer", cancel_timer);
+
+    // Talking to players
+    engine.register_fn("message", message);
+    engine.register_fn("broadcast", broadcast);
+    engine.register_fn("say", say);
+    engine.register_fn("send_packet", send_packet);
+}
+
+fn exists(serial: INT) -> Result<bool> {
+    with_shard(|shard| Ok(shard.world.exists(to_serial(serial)?)))
+}
+
+// Returns () if there's no such mobile.
+fn mobile(serial: INT) -> Result<Dynamic> {
+    with_shard(|shard| {
+        Ok(shard
+            .world
+            .mobile(to_serial(serial)?)
+            .map_or(Dynamic::UNIT, |mobile| mobile_map(mobile).into()))
+    })
+}
+
+// Returns () if there's no such item.
+fn item(serial: INT) -> Result<Dynamic> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        Ok(shard
+            .world
+            .items
+            .get(&serial)
+            .map_or(Dynamic::UNIT, |item| item_map(serial, item).into()))
+    })
+}
+
+// The serials of the other mobiles within `range` of a mobile.
+fn mobiles_near(serial: INT, range: INT) -> Result<Array> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        let range = to_number(range, "a range")?;
+        let Some(centre) = shard.world.mobile(serial) else {
+            return Err(no_entity(serial));
+        };
+
+        Ok(shard
+            .world
+            .mobiles
+            .values()
+            .filter(|mobile| mobile.serial != serial)
+            .filter(|mobile| mobile.location.in_range(&centre.location, range))
+            .map(|mobile| Dynamic::from(mobile.serial as INT))
+            .collect())
+    })
+}
+
+// Properties are the ones the set command knows, and values can be
+// anything that reads as one, e.g. 0x21 or "0x21".
+fn set(serial: INT, property: &str, value: Dynamic) -> Result<()> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        let value = value.to_string();
+        let result = if world::is_item(serial) {
+            shard
+                .world
+                .item_mut(serial)
+                .map(|item| item.set_property(property, &value))
+        } else {
+            shard
+                .world
+                .mobile_mut(serial)
+                .map(|mobile| mobile.set_property(property, &value))
+        };
+
+        match result {
+            Some(result) => result.map_err(Into::into),
+            None => Err(no_entity(serial)),
+        }
+    })
+}
+
+// Returns false if the property was already as far as it can go.
+fn adjust(serial: INT, property: &str, amount: INT) -> Result<bool> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        let delta = to_number(amount, "an amount")?;
+        let changed = if world::is_item(serial) {
+            let property: ItemProperty = property.parse()?;
+            shard
+                .world
+                .item_mut(serial)
+                .map(|item| item.update_state(&[StateDelta::new(property, delta)]).len())
+        } else {
+            let property: MobileProperty = property.parse()?;
+            shard.world.mobile_mut(serial).map(|mobile| {
+                mobile
+                    .update_state(&[StateDelta::new(property, delta)])
+                    .len()
+            })
+        };
+
+        changed
+            .map(|changed| changed > 0)
+            .ok_or_else(|| no_entity(serial))
+    })
+}
+
+// Moving a mobile can be stopped by a subscriber to the move, in which case
+// this returns false.
+fn move_to(serial: INT, x: INT, y: INT, z: INT) -> Result<bool> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        let to = to_location(x, y, z)?;
+        if world::is_item(serial) {
+            shard.world.place_on_ground(serial, to)?;
+            return Ok(true);
+        }
+
+        let Some(mobile) = shard.world.mobile(serial) else {
+            return Err(no_entity(serial));
+        };
+        let mut move_ = Move {
+            mobile: serial,
+            from: mobile.location,
+            to,
+        };
+        if !game_events::publish(shard, &mut move_) {
+            return Ok(false);
+        }
+
+        if let Some(mobile) = shard.world.mobile_mut(serial) {
+            mobile.move_to(move_.to);
+        }
+        Ok(true)
+    })
+}
+
+fn add_mobile(name: &str, body: INT, x: INT, y: INT, z: INT) -> Result<INT> {
+    with_shard(|shard| {
+        let body = to_number(body, "a body")?;
+        let serial = shard.world.add_mobile(name, body, to_location(x, y, z)?);
+        Ok(serial as INT)
+    })
+}
+
+fn add_item(graphic: INT, x: INT, y: INT, z: INT) -> Result<INT> {
+    with_shard(|shard| {
+        let graphic = to_number(graphic, "a graphic")?;
+        let serial = shard.world.add_item(graphic, to_location(x, y, z)?);
+        Ok(serial as INT)
+    })
+}
+
+// Makes a mobile from a template, with its equipment and loot, e.g. for
+// spawners.
+fn add_mobile_from_template(template: &str, x: INT, y: INT, z: INT) -> Result<INT> {
+    with_shard(|shard| {
+        let location = to_location(x, y, z)?;
+        let serial = shard
+            .templates
+            .create_mobile(&mut shard.world, template, location)?;
+        Ok(serial as INT)
+    })
+}
+
+fn add_item_from_template(template: &str, x: INT, y: INT, z: INT) -> Result<INT> {
+    with_shard(|shard| {
+        let location = to_location(x, y, z)?;
+        let serial = shard
+            .templates
+            .create_item(&mut shard.world, template, location)?;
+        Ok(serial as INT)
+    })
+}
+
+fn remove(serial: INT) -> Result<bool> {
+    with_shard(|shard| Ok(shard.world.remove(to_serial(serial)?)))
+}
+
+// Returns how many hits were taken. `source` is () for damage that doesn't
+// come from a mobile.
+fn damage(target: INT, source: Dynamic, amount: INT) -> Result<INT> {
+    with_shard(|shard| {
+        let target = to_serial(target)?;
+        let source = match source.as_int() {
+            Ok(source) => Some(to_serial(source)?),
+            Err(_) => None,
+        };
+        let amount = to_number(amount.max(0), "an amount").unwrap_or(u16::MAX);
+        Ok(combat::damage(shard, target, source, amount) as INT)
+    })
+}
+
+// Skills are named as in templates, e.g. "animal_lore", and their values
+// are in points, e.g. 50.5.
+fn skill(serial: INT, name: &str) -> Result<FLOAT> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        let id = to_skill(name)?;
+        let mobile = shard
+            .world
+            .mobile(serial)
+            .ok_or_else(|| no_entity(serial))?;
+        Ok(skills::to_points(mobile.skills.get(id).value()))
+    })
+}
+
+// Rolls for success at something that needs at least `min` of the skill
+// and always works with `max`, which might teach the mobile a little.
+fn check_skill(serial: INT, name: &str, min: Dynamic, max: Dynamic) -> Result<bool> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        let id = to_skill(name)?;
+        let (min, max) = (to_skill_value(min)?, to_skill_value(max)?);
+        if !shard.world.mobiles.contains_key(&serial) {
+            return Err(no_entity(serial));
+        }
+        Ok(skills::check_skill(shard, serial, id, min, max))
+    })
+}
+
+// Calls `function` with the event as `this` whenever the event happens.
+// Returning false from it cancels the event.
+fn on(context: NativeCallContext, event: &str, priority: INT, function: &str) -> Result<()> {
+    let priority = to_number(priority, "a priority")?;
+    let Some(subscribe) = events::subscriber(event) else {
+        return Err(format!("There's no {} event", event).into());
+    };
+
+    with_loading(|loading| {
+        let function = script_function(loading, &context, function)?;
+        loading.subscriptions.push(Subscription {
+            subscribe,
+            priority,
+            function,
+        });
+        Ok(())
+    })
+}
+
+// Registers a command that calls `function` with the caller's serial, or ()
+// from the console, and an array of the arguments. Whatever string it
+// returns is sent back to the caller.
+fn command(
+    context: NativeCallContext,
+    name: &str,
+    access_level: &str,
+    usage: &str,
+    description: &str,
+    function: &str,
+) -> Result<()> {
+    with_loading(|loading| {
+        let command = ScriptCommand {
+            name: name.to_lowercase(),
+            access_level: access_level.parse()?,
+            usage: String::from(usage),
+            description: String::from(description),
+            function: script_function(loading, &context, function)?,
+        };
+        if loading
+            .commands
+            .iter()
+            .any(|command| command.name.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("The {} command has been registered twice", name).into());
+        }
+
+        loading.commands.push(command);
+        Ok(())
+    })
+}
+
+// Calls `function` once, `delay` milliseconds from now, passing it `arg` if
+// there is one. Returns the timer's ID, for cancelling it.
+fn after(
+    context: NativeCallContext,
+    delay: INT,
+    function: &str,
+    arg: Option<Dynamic>,
+) -> Result<INT> {
+    with_shard(|shard| {
+        let function = script_function(&shard.scripts.installed, &context, function)?;
+        let inbox = shard.inbox.clone();
+        let handle = shard.timers.register(Timer {
+            repeat: Repeat::Once,
+            late: Late::Skip,
+            interval: 0,
+            next: shard.timers.now() + delay.max(0),
+            callback: Box::new(move || {
+                let function = function.clone();
+                let args = arg.iter().cloned().collect();
+                inbox.run(move |shard| {
+                    super::call(shard, &function, None, args);
+                });
+            }),
+        });
+
+        let id = handle.id();
+        let timers = &mut shard.scripts.timers;
+        timers.retain(|timer| timer.is_active());
+        timers.push(handle);
+        Ok(id as INT)
+    })
+}
+
+// Returns false if the timer had already fired or been cancelled.
+fn cancel_timer(id: INT) -> Result<bool> {
+    with_shard(|shard| {
+        let timers = &mut shard.scripts.timers;
+        let Some(index) = timers
+            .iter()
+            .position(|timer| timer.id() as INT == id && timer.is_active())
+        else {
+            return Ok(false);
+        };
+
+        timers.swap_remove(index).cancel();
+        Ok(true)
+    })
+}
+
+// Sends a system message to the player controlling a mobile. Returns false
+// if nobody is.
+fn message(serial: INT, text: &str) -> Result<bool> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        Ok(match player_session(shard, serial) {
+            Some(session_id) => {
+                speech::send_system_message(&shard.sessions, session_id, text);
+                true
+            }
+            None => false,
+        })
+    })
+}
+
+fn broadcast(text: &str) -> Result<()> {
+    with_shard(|shard| {
+        speech::broadcast_system_message(&shard.sessions, text);
+        Ok(())
+    })
+}
+
+// Makes a mobile say something to everyone in earshot.
+fn say(serial: INT, text: &str) -> Result<()> {
+    with_shard(|shard| {
+        speech::say(shard, to_serial(serial)?, text);
+        Ok(())
+    })
+}
+
+// Sends a packet as is to the player controlling a mobile, for anything the
+// functions above don't cover. Returns false if nobody is.
+fn send_packet(serial: INT, packet: Blob) -> Result<bool> {
+    with_shard(|shard| {
+        let serial = to_serial(serial)?;
+        let session = player_session(shard, serial).and_then(|id| shard.sessions.get(id));
+        Ok(match session {
+            Some(session) => {
+                session.send(packet);
+                true
+            }
+            None => false,
+        })
+    })
+}
+
+// A function in the script that's running, which has to exist so mistakes
+// are caught when the script registers it rather than when it's called.
+fn script_function(loaded: &Loaded, context: &NativeCallContext, name: &str) -> Result<Function> {
+    let Some(script) = context.call_source() else {
+        return Err("Only scripts loaded from a file can register functions".into());
+    };
+    if !loaded.has_function(script, name) {
+        return Err(format!("There's no function called {} in {}", name, script).into());
+    }
+
+    Ok(Function {
+        script: String::from(script),
+        name: String::from(name),
+    })
+}
+
+fn player_session(shard: &Shard, serial: Serial) -> Option<SessionId> {
+    shard
+        .sessions
+        .iter()
+        .find(|session| session.mobile == Some(serial))
+        .map(|session| session.id)
+}
+
+fn mobile_map(mobile: &Mobile) -> Map {
+    map([
+        ("serial", number(mobile.serial)),
+        ("name", mobile.name.clone().into()),
+        ("body", number(mobile.body)),
+        ("hue", number(mobile.hue)),
+        ("x", number(mobile.location.x)),
+        ("y", number(mobile.location.y)),
+        ("z", number(mobile.location.z)),
+        ("direction", number(mobile.direction)),
+        ("notoriety", number(mobile.notoriety.to_u8())),
+        ("hits", number(mobile.hits)),
+        ("maxhits", number(mobile.max_hits)),
+        ("stamina", number(mobile.stamina)),
+        ("maxstamina", number(mobile.max_stamina)),
+        ("mana", number(mobile.mana)),
+        ("maxmana", number(mobile.max_mana)),
+        ("food", number(mobile.food)),
+        ("kills", number(mobile.kills)),
+    ])
+}
+
+fn item_map(serial: Serial, item: &Item) -> Map {
+    map([
+        ("serial", number(serial)),
+        ("name", item.name.clone().map_or(Dynamic::UNIT, Into::into)),
+        ("graphic", number(item.graphic)),
+        ("hue", number(item.hue)),
+        ("amount", number(item.amount)),
+        ("x", number(item.location.x)),
+        ("y", number(item.location.y)),
+        ("z", number(item.location.z)),
+        ("container", optional_serial(item.parent)),
+    ])
+}
+
+pub fn location_map(location: Location) -> Map {
+    map([
+        ("x", number(location.x)),
+        ("y", number(location.y)),
+        ("z", number(location.z)),
+    ])
+}
+
+pub fn map<const N: usize>(fields: [(&str, Dynamic); N]) -> Map {
+    fields
+        .into_iter()
+        .map(|(name, value)| (name.into(), value))
+        .collect()
+}
+
+// Scripts only have the one kind of integer.
+pub fn number(value: impl Into<INT>) -> Dynamic {
+    Dynamic::from_int(value.into())
+}
+
+// Reads back a location made by `location_map`, which scripts may have
+// changed. Returns None if it's no longer a valid location.
+pub fn map_location(map: &Map) -> Option<Location> {
+    let field = |name: &str| map.get(name)?.as_int().ok();
+    to_location(field("x")?, field("y")?, field("z")?).ok()
+}
+
+pub fn optional_serial(serial: Option<Serial>) -> Dynamic {
+    serial.map_or(Dynamic::UNIT, number)
+}
+
+fn to_location(x: INT, y: INT, z: INT) -> Result<Location> {
+    Ok(Location {
+        x: to_number(x, "an x coordinate")?,
+        y: to_number(y, "a y coordinate")?,
+        z: to_number(z, "a z coordinate")?,
+    })
+}
+
+fn to_serial(value: INT) -> Result<Serial> {
+    to_number(value, "a serial")
+}
+
+fn to_number<T: TryFrom<INT>>(value: INT, what: &str) -> Result<T> {
+    T::try_from(value).map_err(|_| format!("{} isn't {}", value, what).into())
+}
+
+fn to_skill(name: &str) -> Result<SkillId> {
+    skills::by_name(name).ok_or_else(|| format!("There's no {} skill", name).into())
+}
+
+// Takes points as either a whole number or not, and returns tenths.
+fn to_skill_value(value: Dynamic) -> Result<u16> {
+    let points = match value.as_int() {
+        Ok(points) => points as FLOAT,
+        Err(_) => value
+            .as_float()
+            .map_err(|_| "A skill value must be a number")?,
+    };
+    skills::from_points(points).ok_or_else(|| format!("{} isn't a skill value", points).into())
+}
+
+fn no_entity(serial: Serial) -> Box<EvalAltResult> {
+    format!("Nothing has serial 0x{:08X}", serial).into()
+}
@@ -0,0 +1,203 @@
+use rhai::{Dynamic, Map, INT};
+
+use super::api::{location_map, map, map_location, number, optional_serial};
+use super::{call, Function};
+use crate::events::{
+    Attack, Damage, Death, DropItem, Events, Flow, GameEvent, Login, Logout, Move, PickUpItem,
+    Speech, SubscriptionId, TimerExpired, Use, UseSkill,
+};
+use crate::skills;
+
+// How an event looks to a script: a map of its fields, bound to `this` in
+// the handler.
+trait ScriptEvent: GameEvent {
+    fn to_map(&self) -> Map;
+
+    // Takes back the fields handlers are allowed to change. Anything else
+    // they change is ignored.
+    fn update(&mut self, _map: &Map) {}
+}
+
+// Subscribes a script function to an event at a priority.
+pub type Subscribe = fn(&mut Events, i32, Function) -> SubscriptionId;
+
+// Finds how to subscribe to an event by the name scripts know it by.
+pub fn subscriber(event: &str) -> Option<Subscribe> {
+    let subscribe: Subscribe = match event {
+        "login" => subscribe_as::<Login>,
+        "logout" => subscribe_as::<Logout>,
+        "move" => subscribe_as::<Move>,
+        "speech" => subscribe_as::<Speech>,
+        "attack" => subscribe_as::<Attack>,
+        "damage" => subscribe_as::<Damage>,
+        "death" => subscribe_as::<Death>,
+        "pick_up_item" => subscribe_as::<PickUpItem>,
+        "drop_item" => subscribe_as::<DropItem>,
+        "use" => subscribe_as::<Use>,
+        "use_skill" => subscribe_as::<UseSkill>,
+        "timer_expired" => subscribe_as::<TimerExpired>,
+        _ => return None,
+    };
+
+    Some(subscribe)
+}
+
+// A handler that returns false cancels the event. A handler that fails
+// lets it carry on, as though it hadn't been there.
+fn subscribe_as<E: ScriptEvent>(
+    events: &mut Events,
+    priority: i32,
+    function: Function,
+) -> SubscriptionId {
+    events.subscribe(priority, move |shard, event: &mut E| {
+        let mut this = Dynamic::from_map(event.to_map());
+        let result = call(shard, &function, Some(&mut this), vec![]);
+        if let Some(map) = this.try_cast::<Map>() {
+            event.update(&map);
+        }
+
+        match result.and_then(|result| result.as_bool().ok()) {
+            Some(false) => Flow::Cancel,
+            _ => Flow::Continue,
+        }
+    })
+}
+
+impl ScriptEvent for Login {
+    fn to_map(&self) -> Map {
+        map([("mobile", number(self.mobile))])
+    }
+}
+
+impl ScriptEvent for Logout {
+    fn to_map(&self) -> Map {
+        map([("mobile", number(self.mobile))])
+    }
+}
+
+impl ScriptEvent for Move {
+    fn to_map(&self) -> Map {
+        map([
+            ("mobile", number(self.mobile)),
+            ("from", location_map(self.from).into()),
+            ("to", location_map(self.to).into()),
+        ])
+    }
+
+    fn update(&mut self, map: &Map) {
+        if let Some(to) = map
+            .get("to")
+            .and_then(|to| to.read_lock::<Map>())
+            .and_then(|to| map_location(&to))
+        {
+            self.to = to;
+        }
+    }
+}
+
+impl ScriptEvent for Speech {
+    fn to_map(&self) -> Map {
+        map([
+            ("speaker", number(self.speaker)),
+            ("text", self.speech.text.clone().into()),
+            ("hue", number(self.speech.hue)),
+        ])
+    }
+
+    fn update(&mut self, map: &Map) {
+        if let Some(text) = map
+            .get("text")
+            .and_then(|text| text.clone().into_string().ok())
+        {
+            self.speech.text = text;
+        }
+        if let Some(hue) = map
+            .get("hue")
+            .and_then(|hue| u16::try_from(hue.as_int().ok()?).ok())
+        {
+            self.speech.hue = hue;
+        }
+    }
+}
+
+impl ScriptEvent for Attack {
+    fn to_map(&self) -> Map {
+        map([
+            ("attacker", number(self.attacker)),
+            ("defender", number(self.defender)),
+        ])
+    }
+}
+
+impl ScriptEvent for Damage {
+    fn to_map(&self) -> Map {
+        map([
+            ("target", number(self.target)),
+            ("source", optional_serial(self.source)),
+            ("amount", number(self.amount)),
+        ])
+    }
+
+    fn update(&mut self, map: &Map) {
+        if let Some(amount) = map.get("amount").and_then(|amount| amount.as_int().ok()) {
+            self.amount = amount.clamp(0, u16::MAX as INT) as u16;
+        }
+    }
+}
+
+impl ScriptEvent for Death {
+    fn to_map(&self) -> Map {
+        map([
+            ("mobile", number(self.mobile)),
+            ("killer", optional_serial(self.killer)),
+        ])
+    }
+}
+
+impl ScriptEvent for PickUpItem {
+    fn to_map(&self) -> Map {
+        map([
+            ("mobile", number(self.mobile)),
+            ("item", number(self.item)),
+            ("amount", number(self.amount)),
+        ])
+    }
+}
+
+impl ScriptEvent for DropItem {
+    fn to_map(&self) -> Map {
+        map([
+            ("mobile", number(self.mobile)),
+            ("item", number(self.item)),
+            ("location", location_map(self.location).into()),
+            ("container", optional_serial(self.container)),
+        ])
+    }
+}
+
+impl ScriptEvent for Use {
+    fn to_map(&self) -> Map {
+        map([
+            ("mobile", number(self.mobile)),
+            ("target", number(self.target)),
+        ])
+    }
+}
+
+impl ScriptEvent for UseSkill {
+    fn to_map(&self) -> Map {
+        map([
+            ("mobile", number(self.mobile)),
+            ("skill", skills::name(self.skill).into()),
+        ])
+    }
+}
+
+impl ScriptEvent for TimerExpired {
+    fn to_map(&self) -> Map {
+        map([
+            ("timer", (self.timer as INT).into()),
+            ("serial", number(self.serial)),
+        ])
+    }
+}
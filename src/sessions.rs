@@ -0,0 +1,106 @@
+use async_std::channel::Sender;
+use std::collections::HashMap;
+
+use crate::accounts::AccessLevel;
+use crate::huffman;
+use crate::world::Serial;
+
+pub type SessionId = u32;
+
+pub struct Session {
+    pub id: SessionId,
+    pub account: Option<String>,
+    pub access_level: AccessLevel,
+    pub mobile: Option<Serial>,
+    outgoing: Sender<Vec<u8>>,
+    compressed: bool,
+    // What the client has sent that doesn't make up a whole packet yet.
+    pub received: Vec<u8>,
+    // Whether the connection's seed has been read.
+    pub seeded: bool,
+}
+
+impl Session {
+    // Queues a packet for the connection's writer task. Once the client has
+    // moved on from the login server every packet must be compressed.
+    pub fn send(&self, packet: Vec<u8>) {
+        let packet = if self.compressed {
+            let mut output = Vec::new();
+            huffman::compress(packet, &mut output);
+            output
+        } else {
+            packet
+        };
+
+        if self.outgoing.try_send(packet).is_err() {
+            println!("Dropped packet for closed session {}", self.id);
+        }
+    }
+
+    // Closes the outgoing queue. The writer task sends whatever is still
+    // queued and then shuts the connection down.
+    pub fn disconnect(&self) {
+        self.outgoing.close();
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.outgoing.is_closed()
+    }
+
+    pub fn enable_compression(&mut self) {
+        self.compressed = true;
+    }
+}
+
+pub struct Sessions {
+    next_id: SessionId,
+    sessions: HashMap<SessionId, Session>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Sessions {
+            next_id: 1,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, outgoing: Sender<Vec<u8>>) -> SessionId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let session = Session {
+            id,
+            account: None,
+            access_level: AccessLevel::Player,
+            mobile: None,
+            outgoing,
+            compressed: false,
+            received: Vec::new(),
+            seeded: false,
+        };
+        self.sessions.insert(id, session);
+
+        id
+    }
+
+    pub fn remove(&mut self, id: SessionId) -> Option<Session> {
+        self.sessions.remove(&id)
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<&Session> {
+        self.sessions.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: SessionId) -> Option<&mut Session> {
+        self.sessions.get_mut(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Session> {
+        self.sessions.values_mut()
+    }
+}
@@ -0,0 +1,363 @@
+use std::io;
+use std::sync::mpsc;
+
+use crate::accounts::{self, AccessLevel, Account, Accounts, LoginError, Passwords};
+use crate::combat::Combat;
+use crate::config::Config;
+use crate::death::Deaths;
+use crate::events::{self, Events, Login};
+use crate::game_loop::Inbox;
+use crate::location::{Location, START_LOCATION};
+use crate::persistence::{backups, Policy, SaveReport, Saver};
+use crate::regeneration::Regeneration;
+use crate::scripts::Scripts;
+use crate::sessions::{Session, SessionId, Sessions};
+use crate::shutdown::Shutdown;
+use crate::templates::Templates;
+use crate::timer::{TimerHandle, Timers};
+use crate::world::{Mobile, Serial, World};
+
+// Everything the game is made of. Only the game loop thread touches it;
+// other threads send the game loop messages instead.
+pub struct Shard {
+    pub config: Config,
+    pub accounts: Accounts,
+    passwords: Passwords,
+    pub sessions: Sessions,
+    pub world: World,
+    pub events: Events,
+    pub scripts: Scripts,
+    pub templates: Templates,
+    pub regeneration: Regeneration,
+    pub combat: Combat,
+    pub deaths: Deaths,
+    saver: Saver,
+    pub timers: Timers,
+    // The timer for the next autosave, if autosave is on.
+    pub autosave: Option<TimerHandle>,
+    pub shutdown: Shutdown,
+    // For timer callbacks, which fire on other threads, to get back to the
+    // shard.
+    pub inbox: Inbox,
+}
+
+impl Shard {
+    pub fn new(
+        config: Config,
+        accounts: Accounts,
+        world: World,
+        saver: Saver,
+        timers: Timers,
+        shutdown: Shutdown,
+        inbox: Inbox,
+    ) -> Self {
+        let mut events = Events::default();
+        events::subscribe_builtins(&mut events);
+
+        Shard {
+            config,
+            accounts,
+            passwords: Passwords::start(),
+            sessions: Sessions::new(),
+            world,
+            events,
+            scripts: Scripts::new(),
+            templates: Templates::default(),
+            regeneration: Regeneration::default(),
+            combat: Combat::default(),
+            deaths: Deaths::default(),
+            saver,
+            timers,
+            autosave: None,
+            shutdown,
+            inbox,
+        }
+    }
+
+    // Hands what has changed since the last save to the save thread, which
+    // writes it out in the background. `full` asks for a snapshot of the
+    // whole world rather than just the changes.
+    pub fn save(&mut self, full: bool) -> mpsc::Receiver<io::Result<SaveReport>> {
+        let policy = Policy {
+            saves_per_snapshot: self.config.saves_per_snapshot,
+            backup_path: self.config.backup_path.clone(),
+            retention: backups::Retention {
+                recent: self.config.backups_kept,
+                hourly: self.config.hourly_backups_kept,
+                daily: self.config.daily_backups_kept,
+            },
+        };
+
+        self.saver.save(
+            self.accounts.clone(),
+            self.world.take_changes(),
+            full,
+            policy,
+        )
+    }
+
+    // The password is checked on the password thread, after which `then`
+    // is handed the outcome back on the game loop.
+    pub fn login(
+        &mut self,
+        session_id: SessionId,
+        username: &str,
+        password: &str,
+        then: impl FnOnce(&mut Shard, Result<(), LoginError>) + Send + 'static,
+    ) {
+        let inbox = self.inbox.clone();
+        let username = String::from(username);
+        let password = String::from(password);
+
+        let Some(account) = self.accounts.get(&username) else {
+            if !self.config.auto_create_accounts {
+                return self.logged_in(
+                    session_id,
+                    &username,
+                    Err(LoginError::UnknownAccount),
+                    then,
+                );
+            }
+            self.passwords.run(move || {
+                let password_hash = accounts::hash_password(&password);
+                inbox.run(move |shard| {
+                    // Someone may have taken the username in the meantime.
+                    if shard.accounts.get(&username).is_some() {
+                        return shard.login(session_id, &username, &password, then);
+                    }
+                    println!("Creating account: {}", username);
+                    shard.accounts.insert(Account {
+                        username: username.clone(),
+                        password_hash,
+                        access_level: AccessLevel::Player,
+                        character: None,
+                    });
+                    shard.logged_in(session_id, &username, Ok(()), then);
+                });
+            });
+            return;
+        };
+
+        let account = account.clone();
+        self.passwords.run(move || {
+            let correct = account.check_password(&password);
+            inbox.run(move |shard| {
+                // The password may have changed while it was being checked.
+                let unchanged = shard
+                    .accounts
+                    .get(&username)
+                    .is_some_and(|current| current.password_hash == account.password_hash);
+                if !unchanged {
+                    return shard.login(session_id, &username, &password, then);
+                }
+                let result = if correct {
+                    Ok(())
+                } else {
+                    Err(LoginError::IncorrectPassword)
+                };
+                shard.logged_in(session_id, &username, result, then);
+            });
+        });
+    }
+
+    fn logged_in(
+        &mut self,
+        session_id: SessionId,
+        username: &str,
+        result: Result<(), LoginError>,
+        then: impl FnOnce(&mut Shard, Result<(), LoginError>),
+    ) {
+        if let Err(e) = &result {
+            println!("Login failed for {}: {:?}", username, e);
+        } else if let Some(account) = self.accounts.get(username) {
+            if let Some(session) = self.sessions.get_mut(session_id) {
+                session.account = Some(account.username.clone());
+                session.access_level = account.access_level;
+            }
+        }
+        then(self, result);
+    }
+
+    // Puts the session's character into the world, creating one the first
+    // time the account plays.
+    pub fn enter_world(&mut self, session_id: SessionId) {
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return;
+        };
+        let Some(account) = session
+            .account
+            .as_deref()
+            .and_then(|username| self.accounts.get_mut(username))
+        else {
+            return;
+        };
+
+        let serial = match account.character {
+            Some(serial) if self.world.mobile(serial).is_some() => serial,
+            _ => {
+                let serial = self
+                    .world
+                    .add_mobile(&account.username, 0x190, START_LOCATION);
+                account.character = Some(serial);
+                serial
+            }
+        };
+
+        session.mobile = Some(serial);
+
+        let mut login = Login {
+            session_id,
+            mobile: serial,
+        };
+        events::publish(self, &mut login);
+    }
+
+    pub fn session_mobile(&self, session_id: SessionId) -> Option<&Mobile> {
+        let serial = self.sessions.get(session_id)?.mobile?;
+        self.world.mobile(serial)
+    }
+
+    // The session playing the mobile, if anyone is.
+    pub fn mobile_session(&self, serial: Serial) -> Option<&Session> {
+        self.sessions
+            .iter()
+            .find(|session| session.mobile == Some(serial))
+    }
+
+    pub fn sessions_in_range<'a>(
+        &'a self,
+        location: &'a Location,
+        range: u16,
+    ) -> impl Iterator<Item = &'a Session> + 'a {
+        self.sessions.iter().filter(move |session| {
+            session
+                .mobile
+                .and_then(|serial| self.world.mobile(serial))
+                .is_some_and(|mobile| mobile.location.in_range(location, range))
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::game_loop::{self, Message};
+    use crate::persistence::{self, Backend};
+    use crate::shutdown;
+    use crate::timer::{self, Clock, ManualClock, Options};
+    use std::sync::Arc;
+
+    // A shard with an empty world that saves to its own directory, whose
+    // timers only move on when told to. Also returns what's sent to its
+    // inbox.
+    pub fn shard(name: &str) -> (Shard, mpsc::Receiver<Message>) {
+        let (shard, messages_rx, _) = shard_with_clock(name);
+        (shard, messages_rx)
+    }
+
+    // The same, along with the clock that moves its timers on.
+    pub fn shard_with_clock(name: &str) -> (Shard, mpsc::Receiver<Message>, Arc<ManualClock>) {
+        let directory = persistence::tests::empty_directory(name);
+        let save_path = directory.join("world.bin");
+        let save_path = save_path.to_str().unwrap();
+        let storage = persistence::open(Backend::FlatFile, save_path).unwrap();
+        let saver = Saver::start(storage, save_path, &Accounts::new(), &World::new());
+        let config = Config {
+            audit_log_path: directory
+                .join("commands.log")
+                .to_string_lossy()
+                .into_owned(),
+            ..Config::default()
+        };
+        let (inbox, messages_rx) = game_loop::channel();
+        let clock = Arc::new(ManualClock::default());
+
+        let shard = Shard::new(
+            config,
+            Accounts::new(),
+            World::new(),
+            saver,
+            timer::start(
+                Arc::clone(&clock) as Arc<dyn Clock>,
+                Options::from_config(&Config::default()),
+            ),
+            shutdown::channel().0,
+            inbox,
+        );
+
+        (shard, messages_rx, clock)
+    }
+
+    // Logs in, running what the password thread sends back to the game
+    // loop until the login is done.
+    fn log_in(
+        shard: &mut Shard,
+        messages_rx: &mpsc::Receiver<Message>,
+        session_id: SessionId,
+        password: &str,
+    ) -> Result<(), LoginError> {
+        let (result_tx, result_rx) = mpsc::channel();
+        shard.login(session_id, "alice", password, move |_, result| {
+            let _ = result_tx.send(result);
+        });
+        finish_login(shard, messages_rx, &result_rx)
+    }
+
+    fn finish_login(
+        shard: &mut Shard,
+        messages_rx: &mpsc::Receiver<Message>,
+        result_rx: &mpsc::Receiver<Result<(), LoginError>>,
+    ) -> Result<(), LoginError> {
+        loop {
+            if let Ok(result) = result_rx.try_recv() {
+                return result;
+            }
+            if let Message::Run(job) = messages_rx.recv().unwrap() {
+                job(shard);
+            }
+        }
+    }
+
+    #[test]
+    fn it_only_creates_accounts_when_configured_to() {
+        let (mut shard, messages_rx) = shard("shard-auto-create");
+        assert_eq!(
+            log_in(&mut shard, &messages_rx, 0, "pass"),
+            Err(LoginError::UnknownAccount)
+        );
+
+        shard.config.auto_create_accounts = true;
+        assert_eq!(log_in(&mut shard, &messages_rx, 0, "pass"), Ok(()));
+        let alice = shard.accounts.get("alice").unwrap();
+        assert_eq!(alice.access_level, AccessLevel::Player);
+        assert!(alice.check_password("pass"));
+    }
+
+    #[test]
+    fn it_checks_passwords_off_the_game_loop() {
+        let (mut shard, messages_rx) = shard("shard-login");
+        shard
+            .accounts
+            .create("Alice", "pass", AccessLevel::GameMaster)
+            .unwrap();
+        let (outgoing_tx, _outgoing_rx) = async_std::channel::unbounded();
+        let session_id = shard.sessions.add(outgoing_tx);
+
+        assert_eq!(
+            log_in(&mut shard, &messages_rx, session_id, "wrong"),
+            Err(LoginError::IncorrectPassword)
+        );
+        assert!(shard.sessions.get(session_id).unwrap().account.is_none());
+
+        let (result_tx, result_rx) = mpsc::channel();
+        shard.login(session_id, "alice", "pass", move |_, result| {
+            let _ = result_tx.send(result);
+        });
+        // The game loop carries on while the password is checked.
+        assert!(result_rx.try_recv().is_err());
+        assert_eq!(finish_login(&mut shard, &messages_rx, &result_rx), Ok(()));
+        let session = shard.sessions.get(session_id).unwrap();
+        assert_eq!(session.account.as_deref(), Some("Alice"));
+        assert_eq!(session.access_level, AccessLevel::GameMaster);
+    }
+}
@@ -0,0 +1,170 @@
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::game_loop::{GameLoop, Inbox};
+use crate::speech;
+use crate::tcp::Listener;
+
+// How many seconds before shutting down players are warned, on top of the
+// warning sent when the shutdown is first requested.
+const WARNINGS: [u64; 10] = [300, 120, 60, 30, 10, 5, 4, 3, 2, 1];
+
+// How long to wait for connections to finish sending their queued packets.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct Shutdown {
+    request_tx: mpsc::Sender<Duration>,
+}
+
+impl Shutdown {
+    pub fn request(&self, delay: Duration) {
+        // Nothing is listening once the shutdown is under way, which is fine.
+        let _ = self.request_tx.send(delay);
+    }
+}
+
+pub fn channel() -> (Shutdown, mpsc::Receiver<Duration>) {
+    let (request_tx, request_rx) = mpsc::channel();
+    (Shutdown { request_tx }, request_rx)
+}
+
+// SIGINT and SIGTERM shut down straight away. A second signal gives up on
+// shutting down gracefully and exits immediately.
+pub fn handle_signals(shutdown: Shutdown) {
+    let signalled = AtomicBool::new(false);
+
+    let result = ctrlc::set_handler(move || {
+        if signalled.swap(true, Ordering::Relaxed) {
+            println!("Exiting without saving");
+            process::exit(1);
+        }
+
+        println!("Received signal, shutting down");
+        shutdown.request(Duration::ZERO);
+    });
+
+    if let Err(e) = result {
+        println!("Error setting up signal handling: {}", e);
+    }
+}
+
+// Waits for a shutdown to be requested, counts down to it and then shuts
+// everything down. Returns the process exit code.
+pub fn run(requests: mpsc::Receiver<Duration>, game_loop: GameLoop, listener: Listener) -> i32 {
+    let Ok(delay) = requests.recv() else {
+        return 0;
+    };
+
+    let inbox = game_loop.inbox();
+    let mut deadline = Instant::now() + delay;
+    warn(&inbox, delay);
+
+    loop {
+        let now = Instant::now();
+        let remaining = deadline.saturating_duration_since(now);
+
+        if remaining.is_zero() {
+            break;
+        }
+
+        let next_warning = WARNINGS
+            .iter()
+            .map(|&seconds| Duration::from_secs(seconds))
+            .find(|&warning| warning < remaining)
+            .unwrap_or(Duration::ZERO);
+
+        match requests.recv_timeout(remaining - next_warning) {
+            // A later request can only bring the shutdown forward.
+            Ok(delay) => {
+                if now + delay < deadline {
+                    deadline = now + delay;
+                    warn(&inbox, delay);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if !next_warning.is_zero() {
+                    warn(&inbox, next_warning);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    shut_down(game_loop, listener)
+}
+
+fn warn(inbox: &Inbox, remaining: Duration) {
+    let seconds = remaining.as_secs();
+
+    let message = match seconds {
+        0 => String::from("The server is shutting down."),
+        1 => String::from("The server will shut down in 1 second."),
+        s if s % 60 == 0 && s > 60 => format!("The server will shut down in {} minutes.", s / 60),
+        s => format!("The server will shut down in {} seconds.", s),
+    };
+
+    println!("{}", message);
+
+    inbox.run(move |shard| speech::broadcast_system_message(&shard.sessions, &message));
+}
+
+fn shut_down(game_loop: GameLoop, listener: Listener) -> i32 {
+    println!("Shutting down");
+
+    listener.stop();
+    println!("Stopped accepting connections");
+
+    let inbox = game_loop.inbox();
+    inbox.run(|shard| {
+        for session in shard.sessions.iter() {
+            session.disconnect();
+        }
+    });
+    wait_for_sessions_to_close(&inbox);
+
+    // Timer callbacks hand their work to the game loop, so it has to keep
+    // running until they're done. Regeneration and decay timers that aren't
+    // due yet start again at the next boot, from each mobile's stats and
+    // each item's decay time.
+    if let Some(timers) = inbox.call(|shard| shard.timers.clone()) {
+        let dropped = timers.stop();
+        println!("Stopped timers, {} pending timers were dropped", dropped);
+    }
+
+    let Ok(mut shard) = game_loop.stop() else {
+        println!("The game loop has crashed, so the world can't be saved");
+        return 1;
+    };
+
+    // Save everything, so the next start has no deltas to apply, and wait
+    // for the save thread to finish writing it.
+    let done = shard.save(true);
+    match done.recv() {
+        Ok(Ok(_)) => {
+            println!("Shutdown complete");
+            0
+        }
+        // The save thread has already reported what went wrong.
+        _ => 1,
+    }
+}
+
+fn wait_for_sessions_to_close(inbox: &Inbox) {
+    let started = Instant::now();
+
+    while started.elapsed() < FLUSH_TIMEOUT {
+        let closed = inbox.call(|shard| shard.sessions.iter().next().is_none());
+        if closed.unwrap_or(true) {
+            println!("All connections closed");
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    println!("Gave up waiting for connections to close");
+}
@@ -0,0 +1,422 @@
+use rand::Rng;
+
+use crate::events::{self, UseSkill};
+use crate::sessions::SessionId;
+use crate::shard::Shard;
+use crate::tcp::packets;
+use crate::world::{Mobile, Serial};
+
+// Skill values are in tenths of a point, as clients show them, so 50.0 is
+// 500.
+pub const DEFAULT_CAP: u16 = 1000;
+pub const DEFAULT_TOTAL_CAP: u16 = 7000;
+
+// Skills are numbered by where they are in this list, which is the order
+// clients know them in. The names are how templates, scripts and commands
+// refer to them.
+pub const SKILLS: [SkillInfo; 58] = [
+    SkillInfo::new("alchemy", false),
+    SkillInfo::new("anatomy", true),
+    SkillInfo::new("animal_lore", true),
+    SkillInfo::new("item_identification", true),
+    SkillInfo::new("arms_lore", true),
+    SkillInfo::new("parrying", false),
+    SkillInfo::new("begging", true),
+    SkillInfo::new("blacksmithy", false),
+    SkillInfo::new("bowcraft_fletching", false),
+    SkillInfo::new("peacemaking", true),
+    SkillInfo::new("camping", false),
+    SkillInfo::new("carpentry", false),
+    SkillInfo::new("cartography", false),
+    SkillInfo::new("cooking", false),
+    SkillInfo::new("detecting_hidden", true),
+    SkillInfo::new("discordance", true),
+    SkillInfo::new("evaluating_intelligence", true),
+    SkillInfo::new("healing", false),
+    SkillInfo::new("fishing", false),
+    SkillInfo::new("forensic_evaluation", true),
+    SkillInfo::new("herding", false),
+    SkillInfo::new("hiding", true),
+    SkillInfo::new("provocation", true),
+    SkillInfo::new("inscription", true),
+    SkillInfo::new("lockpicking", false),
+    SkillInfo::new("magery", false),
+    SkillInfo::new("resisting_spells", false),
+    SkillInfo::new("tactics", false),
+    SkillInfo::new("snooping", false),
+    SkillInfo::new("musicianship", false),
+    SkillInfo::new("poisoning", true),
+    SkillInfo::new("archery", false),
+    SkillInfo::new("spirit_speak", true),
+    SkillInfo::new("stealing", true),
+    SkillInfo::new("tailoring", false),
+    SkillInfo::new("animal_taming", true),
+    SkillInfo::new("taste_identification", true),
+    SkillInfo::new("tinkering", false),
+    SkillInfo::new("tracking", true),
+    SkillInfo::new("veterinary", false),
+    SkillInfo::new("swordsmanship", false),
+    SkillInfo::new("mace_fighting", false),
+    SkillInfo::new("fencing", false),
+    SkillInfo::new("wrestling", false),
+    SkillInfo::new("lumberjacking", false),
+    SkillInfo::new("mining", false),
+    SkillInfo::new("meditation", true),
+    SkillInfo::new("stealth", true),
+    SkillInfo::new("remove_trap", true),
+    SkillInfo::new("necromancy", false),
+    SkillInfo::new("focus", false),
+    SkillInfo::new("chivalry", false),
+    SkillInfo::new("bushido", false),
+    SkillInfo::new("ninjitsu", false),
+    SkillInfo::new("spellweaving", false),
+    SkillInfo::new("mysticism", false),
+    SkillInfo::new("imbuing", true),
+    SkillInfo::new("throwing", false),
+];
+
+pub const SKILL_COUNT: usize = SKILLS.len();
+
+// An index into SKILLS.
+pub type SkillId = u8;
+
+pub struct SkillInfo {
+    pub name: &'static str,
+    // Whether players can use it from the skill list or a macro, rather
+    // than it only being checked as a side effect of doing something else.
+    pub usable: bool,
+}
+
+impl SkillInfo {
+    const fn new(name: &'static str, usable: bool) -> Self {
+        SkillInfo { name, usable }
+    }
+}
+
+pub fn by_name(name: &str) -> Option<SkillId> {
+    SKILLS
+        .iter()
+        .position(|skill| skill.name == name)
+        .map(|id| id as SkillId)
+}
+
+pub fn name(id: SkillId) -> &'static str {
+    SKILLS[id as usize].name
+}
+
+// Converts points, e.g. 50.5, to tenths. Fails for anything that isn't a
+// skill value.
+pub fn from_points(points: f64) -> Option<u16> {
+    let tenths = (points * 10.0).round();
+    (0.0..=u16::MAX as f64)
+        .contains(&tenths)
+        .then_some(tenths as u16)
+}
+
+pub fn to_points(tenths: u16) -> f64 {
+    tenths as f64 / 10.0
+}
+
+// Which way a player wants a skill to go. Only skills set to go up gain,
+// and only skills set to go down are lowered to make room under the total
+// cap.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SkillLock {
+    #[default]
+    Up,
+    Down,
+    Locked,
+}
+
+impl SkillLock {
+    pub fn from_u8(value: u8) -> Option<SkillLock> {
+        match value {
+            0 => Some(SkillLock::Up),
+            1 => Some(SkillLock::Down),
+            2 => Some(SkillLock::Locked),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            SkillLock::Up => 0,
+            SkillLock::Down => 1,
+            SkillLock::Locked => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Skill {
+    // What's been learned.
+    pub base: u16,
+    // The most `base` can gain to.
+    pub cap: u16,
+    pub lock: SkillLock,
+}
+
+impl Default for Skill {
+    fn default() -> Self {
+        Skill {
+            base: 0,
+            cap: DEFAULT_CAP,
+            lock: SkillLock::Up,
+        }
+    }
+}
+
+impl Skill {
+    // What the skill counts as when it's used. Equipment and spells will add
+    // to this once there are any.
+    pub fn value(&self) -> u16 {
+        self.base
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Skills {
+    skills: [Skill; SKILL_COUNT],
+    // The most all the base values can add up to.
+    pub total_cap: u16,
+}
+
+impl Default for Skills {
+    fn default() -> Self {
+        Skills {
+            skills: [Skill::default(); SKILL_COUNT],
+            total_cap: DEFAULT_TOTAL_CAP,
+        }
+    }
+}
+
+impl Skills {
+    pub fn get(&self, id: SkillId) -> &Skill {
+        &self.skills[id as usize]
+    }
+
+    // Change skills through Mobile's methods, so clients are told.
+    pub fn get_mut(&mut self, id: SkillId) -> &mut Skill {
+        &mut self.skills[id as usize]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SkillId, &Skill)> {
+        self.skills
+            .iter()
+            .enumerate()
+            .map(|(id, skill)| (id as SkillId, skill))
+    }
+
+    pub fn total(&self) -> u32 {
+        self.skills.iter().map(|skill| skill.base as u32).sum()
+    }
+}
+
+// Rolls whether `mobile` succeeds at something that needs at least `min` of
+// the skill and can't be failed with `max` or more, both in tenths. Trying
+// something that could go either way might teach it a little.
+pub fn check(mobile: &mut Mobile, id: SkillId, min: u16, max: u16, rng: &mut impl Rng) -> bool {
+    let value = mobile.skills.get(id).value();
+    if value < min {
+        return false;
+    }
+    if value >= max {
+        return true;
+    }
+
+    let chance = (value - min) as f64 / (max - min) as f64;
+    check_chance(mobile, id, chance, rng)
+}
+
+// Rolls whether `mobile` succeeds at something it has `chance` of doing with
+// the skill, from 0 to 1, e.g. landing a blow. Only attempts that could go
+// either way teach anything.
+pub fn check_chance(mobile: &mut Mobile, id: SkillId, chance: f64, rng: &mut impl Rng) -> bool {
+    let (success, learned) = roll_chance(mobile, id, chance, rng);
+    if learned {
+        gain(mobile, id);
+    }
+    success
+}
+
+// The same without touching the mobile, for callers that only want to
+// borrow it mutably when it changes. Returns whether it succeeded and
+// whether it gains from trying.
+pub fn roll_chance(mobile: &Mobile, id: SkillId, chance: f64, rng: &mut impl Rng) -> (bool, bool) {
+    if chance <= 0.0 {
+        return (false, false);
+    }
+    if chance >= 1.0 {
+        return (true, false);
+    }
+
+    let success = rng.gen_bool(chance);
+
+    // Skills gain fastest when they're low and the attempt was hard.
+    // Failing teaches less than succeeding, but still something.
+    let skill = mobile.skills.get(id);
+    let headroom = skill.cap.saturating_sub(skill.base) as f64 / skill.cap.max(1) as f64;
+    let difficulty = (1.0 - chance) * if success { 0.5 } else { 0.2 };
+    let gain_chance = ((headroom + difficulty) / 2.0).max(0.01);
+    let learned = rng.gen_bool(gain_chance.min(1.0)) && can_gain(mobile, id);
+
+    (success, learned)
+}
+
+// Raises the skill by a tenth, if it's set to go up and is under its cap.
+// A mobile at its total cap only gains by lowering a skill set to go down.
+// Returns whether it gained.
+pub fn gain(mobile: &mut Mobile, id: SkillId) -> bool {
+    if !can_gain(mobile, id) {
+        return false;
+    }
+
+    if mobile.skills.total() >= mobile.skills.total_cap as u32 {
+        let lowered = mobile
+            .skills
+            .iter()
+            .find(|&(other, skill)| other != id && skill.lock == SkillLock::Down && skill.base > 0)
+            .map(|(other, skill)| (other, skill.base));
+        let Some((other, base)) = lowered else {
+            return false;
+        };
+        mobile.set_skill(other, base - 1);
+    }
+
+    mobile.set_skill(id, mobile.skills.get(id).base + 1);
+    true
+}
+
+// Whether `gain` would raise the skill.
+fn can_gain(mobile: &Mobile, id: SkillId) -> bool {
+    let skill = mobile.skills.get(id);
+    if skill.lock != SkillLock::Up || skill.base >= skill.cap {
+        return false;
+    }
+
+    mobile.skills.total() < mobile.skills.total_cap as u32
+        || mobile
+            .skills
+            .iter()
+            .any(|(other, skill)| other != id && skill.lock == SkillLock::Down && skill.base > 0)
+}
+
+// Checks the skill of a mobile in the world.
+pub fn check_skill(shard: &mut Shard, serial: Serial, id: SkillId, min: u16, max: u16) -> bool {
+    let Some(mobile) = shard.world.mobile_mut(serial) else {
+        return false;
+    };
+    check(mobile, id, min, max, &mut rand::thread_rng())
+}
+
+// A player asked to use a skill from the skill list or a macro. What using
+// it does is up to subscribers to UseSkill.
+pub fn use_skill(shard: &mut Shard, session_id: SessionId, id: SkillId) {
+    let Some(mobile) = shard
+        .sessions
+        .get(session_id)
+        .and_then(|session| session.mobile)
+    else {
+        return;
+    };
+    if !SKILLS.get(id as usize).is_some_and(|skill| skill.usable) {
+        return;
+    }
+
+    events::publish(shard, &mut UseSkill { mobile, skill: id });
+}
+
+pub fn set_lock(shard: &mut Shard, session_id: SessionId, id: SkillId, lock: SkillLock) {
+    let Some(serial) = shard
+        .sessions
+        .get(session_id)
+        .and_then(|session| session.mobile)
+    else {
+        return;
+    };
+    if id as usize >= SKILL_COUNT {
+        return;
+    }
+
+    if let Some(mobile) = shard.world.mobile_mut(serial) {
+        mobile.set_skill_lock(id, lock);
+    }
+}
+
+// Sends a player every one of their character's skills.
+pub fn send_skill_list(shard: &Shard, session_id: SessionId) {
+    let Some(session) = shard.sessions.get(session_id) else {
+        return;
+    };
+    if let Some(mobile) = shard.session_mobile(session_id) {
+        session.send(packets::skill_list_packet(&mobile.skills));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+    use rand::rngs::mock::StepRng;
+
+    fn mobile() -> Mobile {
+        Mobile::new(1, "Bob", 0x190, START_LOCATION)
+    }
+
+    // Every roll comes up 0, so every chance that isn't 0 succeeds.
+    fn lucky() -> StepRng {
+        StepRng::new(0, 0)
+    }
+
+    // Every roll comes up as high as it can, so every chance that isn't 1
+    // fails.
+    fn unlucky() -> StepRng {
+        StepRng::new(u64::MAX, 0)
+    }
+
+    #[test]
+    fn it_only_gains_from_checks_that_could_go_either_way() {
+        let magery = by_name("magery").unwrap();
+        let mut mobile = mobile();
+        mobile.set_skill(magery, 500);
+
+        assert!(!check(&mut mobile, magery, 600, 1000, &mut lucky()));
+        assert!(check(&mut mobile, magery, 0, 500, &mut lucky()));
+        assert_eq!(mobile.skills.get(magery).base, 500);
+
+        assert!(check(&mut mobile, magery, 0, 1000, &mut lucky()));
+        assert_eq!(mobile.skills.get(magery).base, 501);
+        assert!(!check(&mut mobile, magery, 0, 1000, &mut unlucky()));
+        assert_eq!(mobile.skills.get(magery).base, 501);
+    }
+
+    #[test]
+    fn it_stops_gaining_at_the_caps_and_locks() {
+        let magery = by_name("magery").unwrap();
+        let tactics = by_name("tactics").unwrap();
+        let mut mobile = mobile();
+        mobile.set_skill(magery, DEFAULT_CAP);
+        assert!(!gain(&mut mobile, magery));
+
+        mobile.set_skill_lock(tactics, SkillLock::Locked);
+        assert!(!gain(&mut mobile, tactics));
+        mobile.set_skill_lock(tactics, SkillLock::Up);
+
+        // At the total cap, tactics can only gain what magery gives up.
+        mobile.skills.total_cap = DEFAULT_CAP;
+        assert!(!gain(&mut mobile, tactics));
+        mobile.set_skill_lock(magery, SkillLock::Down);
+        assert!(gain(&mut mobile, tactics));
+        assert_eq!(mobile.skills.get(magery).base, DEFAULT_CAP - 1);
+        assert_eq!(mobile.skills.get(tactics).base, 1);
+    }
+
+    #[test]
+    fn it_knows_skills_by_name_and_number() {
+        assert_eq!(by_name("alchemy"), Some(0));
+        assert_eq!(by_name("wrestling"), Some(43));
+        assert_eq!(by_name("throwing"), Some(57));
+        assert_eq!(name(25), "magery");
+        assert_eq!(by_name("Magery"), None);
+    }
+}
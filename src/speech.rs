@@ -0,0 +1,203 @@
+use crate::commands::{Caller, Commands};
+use crate::events;
+use crate::sessions::{SessionId, Sessions};
+use crate::shard::Shard;
+use crate::tcp::packets;
+use crate::world::Serial;
+
+// The client sets these bits on the speech type when the request carries
+// the keyword IDs it recognised in the text (e.g. "vendor buy").
+pub const ENCODED_SPEECH_FLAG: u8 = 0xC0;
+
+const WHISPER_RANGE: u16 = 1;
+const REGULAR_RANGE: u16 = 12;
+const YELL_RANGE: u16 = 18;
+
+const SYSTEM_SERIAL: u32 = 0xFFFFFFFF;
+const SYSTEM_BODY: u16 = 0xFFFF;
+const SYSTEM_HUE: u16 = 0x3B2;
+const SYSTEM_FONT: u16 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpeechType {
+    Regular,
+    Broadcast,
+    Emote,
+    Label,
+    Focus,
+    Whisper,
+    Yell,
+    Spell,
+    Guild,
+    Alliance,
+    Command,
+}
+
+impl SpeechType {
+    pub fn from_u8(value: u8) -> Option<SpeechType> {
+        match value {
+            0x00 => Some(SpeechType::Regular),
+            0x01 => Some(SpeechType::Broadcast),
+            0x02 => Some(SpeechType::Emote),
+            0x06 => Some(SpeechType::Label),
+            0x07 => Some(SpeechType::Focus),
+            0x08 => Some(SpeechType::Whisper),
+            0x09 => Some(SpeechType::Yell),
+            0x0A => Some(SpeechType::Spell),
+            0x0D => Some(SpeechType::Guild),
+            0x0E => Some(SpeechType::Alliance),
+            0x0F => Some(SpeechType::Command),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            SpeechType::Regular => 0x00,
+            SpeechType::Broadcast => 0x01,
+            SpeechType::Emote => 0x02,
+            SpeechType::Label => 0x06,
+            SpeechType::Focus => 0x07,
+            SpeechType::Whisper => 0x08,
+            SpeechType::Yell => 0x09,
+            SpeechType::Spell => 0x0A,
+            SpeechType::Guild => 0x0D,
+            SpeechType::Alliance => 0x0E,
+            SpeechType::Command => 0x0F,
+        }
+    }
+
+    // How far away, in tiles, this speech can be heard. Speech types the
+    // client can't normally send from the speech bar are treated as regular.
+    pub fn range(self) -> u16 {
+        match self {
+            SpeechType::Whisper => WHISPER_RANGE,
+            SpeechType::Yell => YELL_RANGE,
+            _ => REGULAR_RANGE,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Speech {
+    pub speech_type: SpeechType,
+    pub hue: u16,
+    pub font: u16,
+    pub language: String,
+    pub keywords: Vec<u16>,
+    pub text: String,
+}
+
+pub fn handle_speech_request(
+    shard: &mut Shard,
+    commands: &Commands,
+    session_id: SessionId,
+    speech: Speech,
+) {
+    if speech.text.trim().is_empty() {
+        return;
+    }
+
+    if let Some(command_line) = speech
+        .text
+        .strip_prefix(shard.config.command_prefix.as_str())
+    {
+        let command_line = String::from(command_line);
+        commands.execute(shard, Caller::Session(session_id), &command_line);
+        return;
+    }
+
+    let Some(speaker) = shard.session_mobile(session_id) else {
+        return;
+    };
+
+    let mut event = events::Speech {
+        speaker: speaker.serial,
+        speech,
+    };
+    if !events::publish(shard, &mut event) {
+        return;
+    }
+
+    send_speech(shard, event.speaker, &event.speech);
+}
+
+// Makes a mobile say something, e.g. an NPC, without it counting as speech
+// from a player.
+pub fn say(shard: &Shard, speaker: Serial, text: &str) {
+    let speech = Speech {
+        speech_type: SpeechType::Regular,
+        hue: SYSTEM_HUE,
+        font: SYSTEM_FONT,
+        language: String::from("ENU"),
+        keywords: vec![],
+        text: String::from(text),
+    };
+
+    send_speech(shard, speaker, &speech);
+}
+
+// Sends speech to everyone close enough to the speaker to hear it.
+fn send_speech(shard: &Shard, speaker: Serial, speech: &Speech) {
+    let Some(speaker) = shard.world.mobile(speaker) else {
+        return;
+    };
+
+    let packet =
+        packets::unicode_speech_packet(speaker.serial, speaker.body, &speaker.name, speech);
+
+    let range = speech.speech_type.range();
+
+    for listener in shard.sessions_in_range(&speaker.location, range) {
+        listener.send(packet.clone());
+    }
+}
+
+pub fn send_system_message(sessions: &Sessions, session_id: SessionId, text: &str) {
+    if let Some(session) = sessions.get(session_id) {
+        session.send(system_message_packet(text));
+    }
+}
+
+pub fn broadcast_system_message(sessions: &Sessions, text: &str) {
+    let packet = system_message_packet(text);
+
+    for session in sessions.iter() {
+        session.send(packet.clone());
+    }
+}
+
+pub fn system_message_packet(text: &str) -> Vec<u8> {
+    let speech = Speech {
+        speech_type: SpeechType::Regular,
+        hue: SYSTEM_HUE,
+        font: SYSTEM_FONT,
+        language: String::from("ENU"),
+        keywords: vec![],
+        text: String::from(text),
+    };
+
+    packets::unicode_speech_packet(SYSTEM_SERIAL, SYSTEM_BODY, "System", &speech)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_speech_types() {
+        for value in 0..=0xFF {
+            if let Some(speech_type) = SpeechType::from_u8(value) {
+                assert_eq!(speech_type.to_u8(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn it_has_a_range_for_each_speech_type() {
+        assert_eq!(SpeechType::Regular.range(), 12);
+        assert_eq!(SpeechType::Emote.range(), 12);
+        assert_eq!(SpeechType::Whisper.range(), 1);
+        assert_eq!(SpeechType::Yell.range(), 18);
+    }
+}
@@ -1,43 +1,363 @@
-pub struct StateDelta {
-    pub property: String,
+use std::str::FromStr;
+
+use crate::skills::SkillId;
+use crate::world::{Item, Mobile};
+
+// The most items a single stack can hold.
+pub const MAX_AMOUNT: u16 = 60_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MobileProperty {
+    Hits,
+    MaxHits,
+    Stamina,
+    MaxStamina,
+    Mana,
+    MaxMana,
+    Hue,
+    Body,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemProperty {
+    Amount,
+    Hue,
+    Graphic,
+}
+
+// Properties are named the same way as for the set command.
+impl FromStr for MobileProperty {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "hits" => Ok(MobileProperty::Hits),
+            "maxhits" => Ok(MobileProperty::MaxHits),
+            "stamina" => Ok(MobileProperty::Stamina),
+            "maxstamina" => Ok(MobileProperty::MaxStamina),
+            "mana" => Ok(MobileProperty::Mana),
+            "maxmana" => Ok(MobileProperty::MaxMana),
+            "hue" => Ok(MobileProperty::Hue),
+            "body" => Ok(MobileProperty::Body),
+            _ => Err(format!("Mobiles don't have a {} property", name)),
+        }
+    }
+}
+
+impl FromStr for ItemProperty {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "amount" => Ok(ItemProperty::Amount),
+            "hue" => Ok(ItemProperty::Hue),
+            "graphic" => Ok(ItemProperty::Graphic),
+            _ => Err(format!("Items don't have a {} property", name)),
+        }
+    }
+}
+
+// Everything about a mobile that clients are told about when it changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MobileChange {
+    Name,
+    Body,
+    Hue,
+    Notoriety,
+    // Location or direction.
+    Position,
+    Hits,
+    MaxHits,
+    Stamina,
+    MaxStamina,
+    Mana,
+    MaxMana,
+    // Strength, dexterity or intelligence.
+    Stats,
+    WarMode,
+    // Its base, cap or lock. Only the mobile's own player is told.
+    Skill(SkillId),
+}
+
+impl From<MobileProperty> for MobileChange {
+    fn from(property: MobileProperty) -> Self {
+        match property {
+            MobileProperty::Hits => MobileChange::Hits,
+            MobileProperty::MaxHits => MobileChange::MaxHits,
+            MobileProperty::Stamina => MobileChange::Stamina,
+            MobileProperty::MaxStamina => MobileChange::MaxStamina,
+            MobileProperty::Mana => MobileChange::Mana,
+            MobileProperty::MaxMana => MobileChange::MaxMana,
+            MobileProperty::Hue => MobileChange::Hue,
+            MobileProperty::Body => MobileChange::Body,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemChange {
+    Name,
+    Graphic,
+    Hue,
+    Amount,
+    Position,
+}
+
+impl From<ItemProperty> for ItemChange {
+    fn from(property: ItemProperty) -> Self {
+        match property {
+            ItemProperty::Amount => ItemChange::Amount,
+            ItemProperty::Hue => ItemChange::Hue,
+            ItemProperty::Graphic => ItemChange::Graphic,
+        }
+    }
+}
+
+// A set of changes, however many times each was made, in the order they
+// were first made.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dirty<C>(Vec<C>);
+
+impl<C> Default for Dirty<C> {
+    fn default() -> Self {
+        Dirty(vec![])
+    }
+}
+
+impl<C: Copy + PartialEq> Dirty<C> {
+    pub fn mark(&mut self, change: C) {
+        if !self.0.contains(&change) {
+            self.0.push(change);
+        }
+    }
+
+    pub fn contains(&self, change: C) -> bool {
+        self.0.contains(&change)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Nudges one property of an entity up or down. Each kind of entity has its
+// own properties, so a delta can't name one the entity doesn't have.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateDelta<P> {
+    pub property: P,
     pub delta: i32,
 }
 
-pub trait State {
-    fn update_state(&mut self, state_deltas: &Vec<StateDelta>);
+impl<P> StateDelta<P> {
+    pub fn new(property: P, delta: i32) -> Self {
+        StateDelta { property, delta }
+    }
 }
 
-pub struct Character {
-    pub hitpoints: i32,
-    pub name: String,
+// Entities keep track of what has changed about them since clients were
+// last told, so a flush can send one update however many times something
+// changed in between.
+pub trait State {
+    type Property: Copy + PartialEq;
+    type Change: Copy + PartialEq + From<Self::Property>;
+
+    // Applies the deltas in order, keeping every value within its limits,
+    // and returns the properties whose values changed, each listed once.
+    // The changes are marked too.
+    fn update_state(&mut self, state_deltas: &[StateDelta<Self::Property>]) -> Vec<Self::Property>;
+
+    fn dirty(&mut self) -> &mut Dirty<Self::Change>;
+
+    fn mark_changed(&mut self, change: Self::Change) {
+        self.dirty().mark(change);
+    }
+
+    // Hands over the changes marked since this was last called.
+    fn take_changed(&mut self) -> Dirty<Self::Change> {
+        std::mem::take(self.dirty())
+    }
 }
 
-impl State for Character {
-    fn update_state(&mut self, state_deltas: &Vec<StateDelta>) {
+impl State for Mobile {
+    type Property = MobileProperty;
+    type Change = MobileChange;
+
+    fn update_state(&mut self, state_deltas: &[StateDelta<MobileProperty>]) -> Vec<MobileProperty> {
+        let mut changed = Changed::default();
+
         for state_delta in state_deltas {
-            if state_delta.property == "hitpoints" {
-                self.hitpoints += state_delta.delta;
-                println!(
-                    "Character {} hitpoints are now: {}",
-                    self.name, self.hitpoints
-                );
+            match state_delta.property {
+                MobileProperty::Hits => {
+                    let hits = add(self.hits, state_delta.delta, 0, self.max_hits);
+                    changed.set(&mut self.hits, hits, MobileProperty::Hits);
+                }
+                MobileProperty::MaxHits => {
+                    let max_hits = add(self.max_hits, state_delta.delta, 1, u16::MAX);
+                    changed.set(&mut self.max_hits, max_hits, MobileProperty::MaxHits);
+                    // Lowering the maximum can take hits down with it.
+                    let hits = self.hits.min(self.max_hits);
+                    changed.set(&mut self.hits, hits, MobileProperty::Hits);
+                }
+                MobileProperty::Stamina => {
+                    let stamina = add(self.stamina, state_delta.delta, 0, self.max_stamina);
+                    changed.set(&mut self.stamina, stamina, MobileProperty::Stamina);
+                }
+                MobileProperty::MaxStamina => {
+                    let max_stamina = add(self.max_stamina, state_delta.delta, 1, u16::MAX);
+                    changed.set(
+                        &mut self.max_stamina,
+                        max_stamina,
+                        MobileProperty::MaxStamina,
+                    );
+                    let stamina = self.stamina.min(self.max_stamina);
+                    changed.set(&mut self.stamina, stamina, MobileProperty::Stamina);
+                }
+                MobileProperty::Mana => {
+                    let mana = add(self.mana, state_delta.delta, 0, self.max_mana);
+                    changed.set(&mut self.mana, mana, MobileProperty::Mana);
+                }
+                MobileProperty::MaxMana => {
+                    let max_mana = add(self.max_mana, state_delta.delta, 1, u16::MAX);
+                    changed.set(&mut self.max_mana, max_mana, MobileProperty::MaxMana);
+                    let mana = self.mana.min(self.max_mana);
+                    changed.set(&mut self.mana, mana, MobileProperty::Mana);
+                }
+                MobileProperty::Hue => {
+                    let hue = add(self.hue, state_delta.delta, 0, u16::MAX);
+                    changed.set(&mut self.hue, hue, MobileProperty::Hue);
+                }
+                MobileProperty::Body => {
+                    let body = add(self.body, state_delta.delta, 0, u16::MAX);
+                    changed.set(&mut self.body, body, MobileProperty::Body);
+                }
             }
         }
+
+        for &property in &changed.0 {
+            self.mark_changed(property.into());
+        }
+        changed.0
     }
-}
 
-pub struct Monster {
-    pub anger: i32,
-    pub name: String,
+    fn dirty(&mut self) -> &mut Dirty<MobileChange> {
+        &mut self.dirty
+    }
 }
 
-impl State for Monster {
-    fn update_state(&mut self, state_deltas: &Vec<StateDelta>) {
+impl State for Item {
+    type Property = ItemProperty;
+    type Change = ItemChange;
+
+    fn update_state(&mut self, state_deltas: &[StateDelta<ItemProperty>]) -> Vec<ItemProperty> {
+        let mut changed = Changed::default();
+
         for state_delta in state_deltas {
-            if state_delta.property == "anger" {
-                self.anger += state_delta.delta;
-                println!("Monster {} anger is now: {}", self.name, self.anger);
+            let (value, min, max) = match state_delta.property {
+                ItemProperty::Amount => (&mut self.amount, 1, MAX_AMOUNT),
+                ItemProperty::Hue => (&mut self.hue, 0, u16::MAX),
+                ItemProperty::Graphic => (&mut self.graphic, 0, u16::MAX),
+            };
+            let new_value = add(*value, state_delta.delta, min, max);
+            changed.set(value, new_value, state_delta.property);
+        }
+
+        for &property in &changed.0 {
+            self.mark_changed(property.into());
+        }
+        changed.0
+    }
+
+    fn dirty(&mut self) -> &mut Dirty<ItemChange> {
+        &mut self.dirty
+    }
+}
+
+// The properties changed so far, in the order they first changed.
+struct Changed<P>(Vec<P>);
+
+impl<P> Default for Changed<P> {
+    fn default() -> Self {
+        Changed(vec![])
+    }
+}
+
+impl<P: PartialEq> Changed<P> {
+    fn set(&mut self, value: &mut u16, new_value: u16, property: P) {
+        if *value != new_value {
+            *value = new_value;
+            if !self.0.contains(&property) {
+                self.0.push(property);
             }
         }
     }
 }
+
+fn add(value: u16, delta: i32, min: u16, max: u16) -> u16 {
+    (value as i32 + delta).clamp(min as i32, max as i32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+
+    #[test]
+    fn it_changes_the_properties_in_the_deltas() {
+        let mut mobile = Mobile::new(1, "Bob", 0x190, START_LOCATION);
+        let changed = mobile.update_state(&[
+            StateDelta::new(MobileProperty::Hits, -30),
+            StateDelta::new(MobileProperty::Hue, 5),
+            StateDelta::new(MobileProperty::Hits, 10),
+        ]);
+
+        assert_eq!(mobile.hits, 80);
+        assert_eq!(mobile.hue, 5);
+        assert_eq!(changed, vec![MobileProperty::Hits, MobileProperty::Hue]);
+    }
+
+    #[test]
+    fn it_keeps_hits_within_max_hits() {
+        let mut mobile = Mobile::new(1, "Bob", 0x190, START_LOCATION);
+        assert_eq!(
+            mobile.update_state(&[StateDelta::new(MobileProperty::Hits, 10)]),
+            vec![]
+        );
+        assert_eq!(mobile.hits, 100);
+
+        let changed = mobile.update_state(&[StateDelta::new(MobileProperty::MaxHits, -40)]);
+        assert_eq!(mobile.max_hits, 60);
+        assert_eq!(mobile.hits, 60);
+        assert_eq!(changed, vec![MobileProperty::MaxHits, MobileProperty::Hits]);
+
+        mobile.update_state(&[StateDelta::new(MobileProperty::Hits, -1000)]);
+        assert_eq!(mobile.hits, 0);
+    }
+
+    #[test]
+    fn it_marks_each_change_once_until_it_is_taken() {
+        let mut mobile = Mobile::new(1, "Bob", 0x190, START_LOCATION);
+        for _ in 0..10 {
+            mobile.update_state(&[StateDelta::new(MobileProperty::Hits, -1)]);
+        }
+        mobile.set_property("name", "Robert").unwrap();
+
+        let dirty = mobile.take_changed();
+        assert_eq!(dirty, {
+            let mut expected = Dirty::default();
+            expected.mark(MobileChange::Hits);
+            expected.mark(MobileChange::Name);
+            expected
+        });
+        assert!(mobile.take_changed().is_empty());
+    }
+
+    #[test]
+    fn it_keeps_item_amounts_in_range() {
+        let mut item = Item::new(0x0EED, START_LOCATION);
+        item.update_state(&[StateDelta::new(ItemProperty::Amount, -5)]);
+        assert_eq!(item.amount, 1);
+
+        item.update_state(&[StateDelta::new(ItemProperty::Amount, 100_000)]);
+        assert_eq!(item.amount, MAX_AMOUNT);
+    }
+}
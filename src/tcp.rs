@@ -1,210 +1,1008 @@
+use std::net::Shutdown;
 use std::str;
+use std::sync::Arc;
 
 use async_std::{
+    channel::{self, Receiver},
     net::{TcpListener, TcpStream, ToSocketAddrs},
     prelude::*,
     task,
 };
 
-use crate::huffman;
+use crate::combat;
+use crate::commands::Commands;
+use crate::death;
+use crate::events::{self, DropItem, PickUpItem, Use};
+use crate::game_loop::{Inbox, Message};
+use crate::location::Location;
+use crate::sessions::{Session, SessionId};
+use crate::shard::Shard;
+use crate::skills::{self, SkillId, SkillLock};
+use crate::speech::{self, Speech, SpeechType, ENCODED_SPEECH_FLAG};
+use crate::world::Serial;
 
-mod packets;
+pub mod packets;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-async fn connection_loop(mut stream: TcpStream) -> Result<()> {
+async fn connection_loop(stream: TcpStream, inbox: Inbox) -> Result<()> {
+    let stream = Arc::new(stream);
+    let addr = stream.peer_addr()?;
+
+    let (outgoing_tx, outgoing_rx) = channel::unbounded::<Vec<u8>>();
+    let (session_tx, session_rx) = channel::bounded(1);
+    inbox.send(Message::Connected {
+        outgoing: outgoing_tx,
+        session_tx,
+    });
+    let session_id = session_rx.recv().await?;
+    task::spawn(connection_writer_loop(outgoing_rx, Arc::clone(&stream)));
+
     let mut buffer = [0; 1024];
 
-    while let Ok(received) = stream.read(&mut buffer).await {
+    while let Ok(received) = (&*stream).read(&mut buffer).await {
         if received == 0 {
-            let addr = stream.peer_addr().unwrap();
             println!("Connection closed by: {}", addr);
             break;
         } else {
-            parse_packets(buffer, &mut stream).await?;
-            buffer = [0; 1024];
+            inbox.send(Message::Received(session_id, buffer[..received].to_vec()));
         }
     }
+
+    // Dropping the session drops its outgoing sender, which ends the writer.
+    inbox.send(Message::Disconnected(session_id));
+
+    Ok(())
+}
+
+async fn connection_writer_loop(
+    outgoing_rx: Receiver<Vec<u8>>,
+    stream: Arc<TcpStream>,
+) -> Result<()> {
+    let mut stream = &*stream;
+
+    while let Ok(packet) = outgoing_rx.recv().await {
+        stream.write_all(&packet).await?;
+        stream.flush().await?;
+    }
+
+    // The queue only closes once the session has been disconnected or
+    // removed, so make sure the reading side stops too.
+    let _ = stream.shutdown(Shutdown::Both);
+
     Ok(())
 }
 
-async fn accept_loop(addr: impl ToSocketAddrs) -> Result<()> {
-    let listener = TcpListener::bind(addr).await?;
+async fn accept_loop(listener: TcpListener, inbox: Inbox) -> Result<()> {
     let mut incoming = listener.incoming();
     while let Some(stream) = incoming.next().await {
         let stream = stream?;
         let addr = stream.peer_addr()?;
         println!("Connection received from: {}", addr);
-        task::spawn(connection_loop(stream));
+        task::spawn(connection_loop(stream, inbox.clone()));
     }
     Ok(())
 }
 
-pub fn start() -> Result<()> {
-    let fut = accept_loop("127.0.0.1:2593");
-    task::block_on(fut)
+pub struct Listener {
+    accept_task: task::JoinHandle<Result<()>>,
 }
 
-fn read_u8(input: &mut &[u8]) -> u8 {
-    let (int_bytes, rest) = input.split_at(1);
-    *input = rest;
-    return *&int_bytes[0];
+impl Listener {
+    // Stops accepting new connections. Existing connections carry on until
+    // their sessions are disconnected.
+    pub fn stop(self) {
+        task::block_on(self.accept_task.cancel());
+    }
 }
 
-fn read_u16(input: &mut &[u8]) -> u16 {
-    let (int_bytes, rest) = input.split_at(2);
+// Binds the listening socket and then accepts connections in the background.
+pub fn start(addr: impl ToSocketAddrs, inbox: Inbox) -> Result<Listener> {
+    let listener = task::block_on(TcpListener::bind(addr))?;
+
+    let accept_task = task::spawn(async {
+        let result = accept_loop(listener, inbox).await;
+        if let Err(e) = &result {
+            println!("Error from TCP: {:?}", e);
+        }
+        result
+    });
+
+    Ok(Listener { accept_task })
+}
+
+// The readers return None when the packet is too short for what's being
+// read, which means the client sent a malformed packet.
+fn read_bytes<'a>(input: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if input.len() < length {
+        return None;
+    }
+    let (bytes, rest) = input.split_at(length);
     *input = rest;
-    u16::from_be_bytes(
-        int_bytes
-            .try_into()
-            .expect("int_bytes should always be two bytes long"),
+    Some(bytes)
+}
+
+fn read_u8(input: &mut &[u8]) -> Option<u8> {
+    Some(read_bytes(input, 1)?[0])
+}
+
+fn read_u16(input: &mut &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(read_bytes(input, 2)?.try_into().ok()?))
+}
+
+fn read_u32(input: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(read_bytes(input, 4)?.try_into().ok()?))
+}
+
+// Fixed length strings are null padded. Clients don't always send valid
+// UTF-8, so anything else is replaced rather than refused.
+fn read_string(input: &mut &[u8], length: usize) -> Option<String> {
+    let string_bytes = read_bytes(input, length)?;
+    Some(
+        String::from_utf8_lossy(string_bytes)
+            .trim_end_matches('\0')
+            .to_owned(),
     )
 }
 
-fn read_u32(input: &mut &[u8]) -> u32 {
-    let (int_bytes, rest) = input.split_at(4);
-    *input = rest;
-    u32::from_be_bytes(int_bytes.try_into().unwrap())
+fn read_null_terminated_string(input: &mut &[u8]) -> String {
+    let length = input
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(input.len());
+    let (string_bytes, rest) = input.split_at(length);
+    *input = rest.get(1..).unwrap_or_default();
+    String::from_utf8_lossy(string_bytes).into_owned()
 }
 
-fn read_string<'a>(input: &'a mut &[u8], length: u8) -> &'a str {
-    let (string_bytes, rest) = input.split_at(length.into());
-    *input = rest;
-    return str::from_utf8(string_bytes).unwrap();
+fn read_null_terminated_unicode_string(input: &mut &[u8]) -> String {
+    let mut characters = vec![];
+
+    while let Some(character) = read_u16(input) {
+        if character == 0 {
+            break;
+        }
+        characters.push(character);
+    }
+
+    String::from_utf16_lossy(&characters)
+}
+
+// Encoded speech starts with a 12 bit keyword count followed by the 12 bit
+// keyword IDs, all packed together and padded out to a byte boundary.
+fn read_speech_keywords(input: &mut &[u8]) -> Option<Vec<u16>> {
+    let value = read_u16(input)?;
+    let count = value >> 4;
+    let mut hold = value & 0xF;
+
+    let mut keywords = vec![];
+
+    for i in 0..count {
+        if i % 2 == 0 {
+            keywords.push((hold << 8) | read_u8(input)? as u16);
+        } else {
+            let value = read_u16(input)?;
+            keywords.push(value >> 4);
+            hold = value & 0xF;
+        }
+    }
+
+    Some(keywords)
+}
+
+// Splits a variable length packet's body off the buffer. `buffer_slice`
+// should be positioned just after the packet ID.
+fn split_variable_length_packet<'a>(buffer_slice: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let packet_length = read_u16(buffer_slice)? as usize;
+    read_bytes(buffer_slice, packet_length.saturating_sub(3))
 }
 
-fn handle_encrypted_login_seed_packet(buffer_slice: &mut &[u8]) {
+fn handle_encrypted_login_seed_packet(buffer_slice: &mut &[u8]) -> Option<()> {
     println!("\nEncrypted Login Seed packet received:");
     let packet_length = 20;
-    let (mut bytes, rest) = buffer_slice.split_at(packet_length);
-    *buffer_slice = rest;
-    let seed = read_u32(&mut bytes);
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let seed = read_u32(&mut bytes)?;
     println!("seed: {}", seed);
-    let major = read_u32(&mut bytes);
-    let minor = read_u32(&mut bytes);
-    let revision = read_u32(&mut bytes);
-    let patch = read_u32(&mut bytes);
+    let major = read_u32(&mut bytes)?;
+    let minor = read_u32(&mut bytes)?;
+    let revision = read_u32(&mut bytes)?;
+    let patch = read_u32(&mut bytes)?;
     println!("client version: {}.{}.{}.{}", major, minor, revision, patch);
+    Some(())
 }
 
-fn handle_account_login_request_packet(buffer_slice: &mut &[u8]) {
+fn handle_account_login_request_packet(buffer_slice: &mut &[u8]) -> Option<(String, String)> {
     println!("\nAccount Login Request packet received:");
     let packet_length = 61;
-    let (mut bytes, rest) = buffer_slice.split_at(packet_length);
-    *buffer_slice = rest;
-    let username = read_string(&mut bytes, 30);
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let username = read_string(&mut bytes, 30)?;
     println!("username: {}", username);
-    let password = read_string(&mut bytes, 30);
-    println!("password: {}", password);
+    let password = read_string(&mut bytes, 30)?;
+    Some((username, password))
 }
 
-fn handle_server_select_packet(buffer_slice: &mut &[u8]) {
+fn handle_server_select_packet(buffer_slice: &mut &[u8]) -> Option<()> {
     println!("\nServer Select packet received:");
     let packet_length = 2;
-    let (mut bytes, rest) = buffer_slice.split_at(packet_length);
-    *buffer_slice = rest;
-    let server_index = read_u16(&mut bytes);
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let server_index = read_u16(&mut bytes)?;
     println!("server_index: {}", server_index);
+    Some(())
 }
 
-fn handle_post_login_packet(buffer_slice: &mut &[u8]) {
+fn handle_post_login_packet(buffer_slice: &mut &[u8]) -> Option<(String, String)> {
     println!("Post Login packet received:");
     let packet_length = 64;
-    let (mut bytes, rest) = buffer_slice.split_at(packet_length);
-    *buffer_slice = rest;
-    let encryption_key = read_u32(&mut bytes);
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let encryption_key = read_u32(&mut bytes)?;
     println!("encryption_key: {}, ", encryption_key);
-    let username = read_string(&mut bytes, 30);
+    let username = read_string(&mut bytes, 30)?;
     println!("username: {}, ", username);
-    let password = read_string(&mut bytes, 30);
-    println!("password: {}", password);
+    let password = read_string(&mut bytes, 30)?;
+    Some((username, password))
+}
+
+fn handle_ascii_speech_request_packet(buffer_slice: &mut &[u8]) -> Option<Speech> {
+    println!("\nASCII Speech Request packet received:");
+    let mut bytes = split_variable_length_packet(buffer_slice)?;
+    let speech_type = read_u8(&mut bytes)?;
+    let hue = read_u16(&mut bytes)?;
+    let font = read_u16(&mut bytes)?;
+    let text = read_null_terminated_string(&mut bytes);
+    println!("text: {}", text);
+
+    Some(Speech {
+        speech_type: SpeechType::from_u8(speech_type).unwrap_or(SpeechType::Regular),
+        hue,
+        font,
+        language: String::from("ENU"),
+        keywords: vec![],
+        text,
+    })
+}
+
+fn handle_unicode_speech_request_packet(buffer_slice: &mut &[u8]) -> Option<Speech> {
+    println!("\nUnicode Speech Request packet received:");
+    let mut bytes = split_variable_length_packet(buffer_slice)?;
+    let speech_type = read_u8(&mut bytes)?;
+    let hue = read_u16(&mut bytes)?;
+    let font = read_u16(&mut bytes)?;
+    let language = read_string(&mut bytes, 4)?;
+
+    let (keywords, text) = if speech_type & ENCODED_SPEECH_FLAG != 0 {
+        let keywords = read_speech_keywords(&mut bytes)?;
+        (keywords, read_null_terminated_string(&mut bytes))
+    } else {
+        (vec![], read_null_terminated_unicode_string(&mut bytes))
+    };
+    println!("keywords: {:X?}, text: {}", keywords, text);
+
+    let speech_type = speech_type & !ENCODED_SPEECH_FLAG;
+
+    Some(Speech {
+        speech_type: SpeechType::from_u8(speech_type).unwrap_or(SpeechType::Regular),
+        hue,
+        font,
+        language,
+        keywords,
+        text,
+    })
+}
+
+fn handle_pick_up_item_packet(buffer_slice: &mut &[u8]) -> Option<(Serial, u16)> {
+    println!("\nPick Up Item packet received:");
+    let packet_length = 6;
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let item = read_u32(&mut bytes)?;
+    let amount = read_u16(&mut bytes)?;
+    println!("item: 0x{:08X}, amount: {}", item, amount);
+    Some((item, amount))
+}
+
+// Returns the item, where it was dropped and the container it was dropped
+// into, if any.
+fn handle_drop_item_packet(buffer_slice: &mut &[u8]) -> Option<(Serial, Location, Option<Serial>)> {
+    println!("\nDrop Item packet received:");
+    let packet_length = 14;
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let item = read_u32(&mut bytes)?;
+    let x = read_u16(&mut bytes)?;
+    let y = read_u16(&mut bytes)?;
+    let z = read_u8(&mut bytes)? as i8;
+    let _grid_index = read_u8(&mut bytes)?;
+    let container = match read_u32(&mut bytes)? {
+        0xFFFFFFFF => None,
+        container => Some(container),
+    };
+    println!("item: 0x{:08X}, container: {:X?}", item, container);
+    Some((item, Location { x, y, z }, container))
+}
+
+// The top bit is set when the client wants the paperdoll rather than to use
+// the mobile, which makes no difference to what's double-clicked.
+fn handle_double_click_packet(buffer_slice: &mut &[u8]) -> Option<Serial> {
+    println!("\nDouble Click packet received:");
+    let packet_length = 4;
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let target = read_u32(&mut bytes)? & 0x7FFFFFFF;
+    println!("target: 0x{:08X}", target);
+    Some(target)
 }
 
-async fn send_server_list_packet(stream: &mut TcpStream) -> Result<()> {
+fn handle_attack_request_packet(buffer_slice: &mut &[u8]) -> Option<Serial> {
+    println!("\nAttack Request packet received:");
+    let packet_length = 4;
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let defender = read_u32(&mut bytes)?;
+    println!("defender: 0x{:08X}", defender);
+    Some(defender)
+}
+
+fn handle_war_mode_packet(buffer_slice: &mut &[u8]) -> Option<bool> {
+    println!("\nRequest War Mode packet received:");
+    let packet_length = 4;
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let war_mode = read_u8(&mut bytes)? != 0;
+    println!("war mode: {}", war_mode);
+    Some(war_mode)
+}
+
+// Returns which gump was closed and the button that closed it, 0 if it was
+// just closed. Which switches were on and what was typed in aren't needed
+// by any gump yet.
+fn handle_gump_response_packet(buffer_slice: &mut &[u8]) -> Option<(u32, u32)> {
+    println!("\nGump Menu Selection packet received:");
+    let mut bytes = split_variable_length_packet(buffer_slice)?;
+    let _serial = read_u32(&mut bytes)?;
+    let gump_id = read_u32(&mut bytes)?;
+    let button = read_u32(&mut bytes)?;
+    println!("gump: 0x{:08X}, button: {}", gump_id, button);
+    Some((gump_id, button))
+}
+
+// The client answers the death screen, but there's nothing to choose
+// between, so the answer is ignored.
+fn handle_death_status_packet(buffer_slice: &mut &[u8]) -> Option<()> {
+    println!("\nDeath Status packet received:");
+    let packet_length = 1;
+    read_bytes(buffer_slice, packet_length)?;
+    Some(())
+}
+
+// Returns what kind of action was asked for and its command, e.g. "25 0" to
+// use skill 25.
+fn handle_request_action_packet(buffer_slice: &mut &[u8]) -> Option<(u8, String)> {
+    println!("\nRequest Action packet received:");
+    let mut bytes = split_variable_length_packet(buffer_slice)?;
+    let action_type = read_u8(&mut bytes)?;
+    let command = read_null_terminated_string(&mut bytes);
+    println!("type: 0x{:02X}, command: {}", action_type, command);
+    Some((action_type, command))
+}
+
+// The skill ID comes first in a skill use command.
+fn skill_to_use(command: &str) -> Option<SkillId> {
+    command.split_whitespace().next()?.parse().ok()
+}
+
+// Returns the raw skill ID and lock, which may not be valid ones.
+fn handle_skill_lock_packet(buffer_slice: &mut &[u8]) -> Option<(u16, u8)> {
+    println!("\nSkill Lock packet received:");
+    let mut bytes = split_variable_length_packet(buffer_slice)?;
+    let skill = read_u16(&mut bytes)?;
+    let lock = read_u8(&mut bytes)?;
+    println!("skill: {}, lock: {}", skill, lock);
+    Some((skill, lock))
+}
+
+// Returns what the client wants to know, e.g. 0x05 for skills, and about
+// which mobile.
+fn handle_status_request_packet(buffer_slice: &mut &[u8]) -> Option<(u8, Serial)> {
+    println!("\nStatus Request packet received:");
+    let packet_length = 9;
+    let mut bytes = read_bytes(buffer_slice, packet_length)?;
+    let _pattern = read_u32(&mut bytes)?;
+    let request_type = read_u8(&mut bytes)?;
+    let serial = read_u32(&mut bytes)?;
+    println!("type: 0x{:02X}, serial: 0x{:08X}", request_type, serial);
+    Some((request_type, serial))
+}
+
+// Nothing happens on a double-click unless a subscriber makes it.
+fn use_(shard: &mut Shard, session_id: SessionId, target: Serial) {
+    let Some(mobile) = shard
+        .sessions
+        .get(session_id)
+        .and_then(|session| session.mobile)
+    else {
+        return;
+    };
+    if !shard.world.exists(target) {
+        return;
+    }
+
+    events::publish(shard, &mut Use { mobile, target });
+}
+
+// Lets subscribers stop the player lifting the item. The item stays where
+// it is until it's dropped.
+fn pick_up_item(shard: &mut Shard, session_id: SessionId, item: Serial, amount: u16) -> bool {
+    let Some(mobile) = shard
+        .sessions
+        .get(session_id)
+        .and_then(|session| session.mobile)
+    else {
+        return false;
+    };
+    if !shard.world.items.contains_key(&item) {
+        return false;
+    }
+
+    events::publish(
+        shard,
+        &mut PickUpItem {
+            mobile,
+            item,
+            amount,
+        },
+    )
+}
+
+fn drop_item(
+    shard: &mut Shard,
+    session_id: SessionId,
+    item: Serial,
+    location: Location,
+    container: Option<Serial>,
+) -> bool {
+    let Some(mobile) = shard
+        .sessions
+        .get(session_id)
+        .and_then(|session| session.mobile)
+    else {
+        return false;
+    };
+    if !shard.world.items.contains_key(&item) {
+        return false;
+    }
+
+    let mut drop = DropItem {
+        mobile,
+        item,
+        location,
+        container,
+    };
+    if !events::publish(shard, &mut drop) {
+        return false;
+    }
+
+    let Some(container) = drop.container else {
+        return shard.world.place_on_ground(item, drop.location).is_ok();
+    };
+    if shard.world.place_in(item, container).is_err() {
+        return false;
+    }
+    // Where in the container's gump it was dropped.
+    if let Some(item) = shard.world.item_mut(item) {
+        item.location = drop.location;
+    }
+
+    true
+}
+
+fn send_reject_move_item_packet(session: &Session) {
+    // Reason 0x00: "You cannot pick that up."
+    let buffer = packets::reject_move_item_packet(0x00);
+
+    session.send(buffer.into());
+}
+
+fn send_server_list_packet(session: &Session) {
     let buffer = packets::server_list_packet();
 
-    stream.write_all(&buffer).await?;
-    stream.flush().await?;
+    session.send(buffer.into());
 
     println!("\nSent Server List packet: {:X?}", buffer);
+}
 
-    Ok(())
+fn send_login_denied_packet(session: &Session) {
+    // Reason 0x03: "Your account credentials are invalid"
+    let buffer = packets::login_denied_packet(0x03);
+
+    session.send(buffer.into());
+
+    println!("\nSent Login Denied packet: {:X?}", buffer);
 }
 
-async fn send_server_redirect_packet(stream: &mut TcpStream) -> Result<()> {
+fn send_server_redirect_packet(session: &Session) {
     let buffer = packets::server_redirect_packet();
 
-    stream.write_all(&buffer).await?;
-    stream.flush().await?;
+    session.send(buffer.into());
 
     println!("\nSent Server Redirect packet: {:X?}", buffer);
-
-    Ok(())
 }
 
-async fn send_features_packet(stream: &mut TcpStream) -> Result<()> {
+fn send_features_packet(session: &Session) {
     let src = packets::features_packet();
 
-    let mut output = Vec::new();
+    println!("\nSent Features packet: {:X?}", src);
 
-    println!("\nCompressing Features packet: {:X?}", src);
-    huffman::compress(src, &mut output);
+    session.send(src);
+}
 
-    stream.write_all(&output).await?;
-    stream.flush().await?;
+fn send_character_list_packet(session: &Session) {
+    let src = packets::character_list_packet();
 
-    println!("\nSent compressed Features packet: {:X?}", output);
+    println!("\nSent Character List packet: {:02X?}", src);
 
-    Ok(())
+    session.send(src);
 }
 
-async fn send_character_list_packet(stream: &mut TcpStream) -> Result<()> {
-    let src = packets::character_list_packet();
+// Runs on the game loop, which hands over whatever each connection has
+// received since the last tick. Packets can arrive split across reads, so
+// what's left over waits for the rest. A client that sends something that
+// can't be a packet is disconnected.
+pub fn parse_packets(bytes: &[u8], session_id: SessionId, shard: &mut Shard, commands: &Commands) {
+    let Some(session) = shard.sessions.get_mut(session_id) else {
+        return;
+    };
+    if session.is_disconnected() {
+        return;
+    }
+    session.received.extend_from_slice(bytes);
+
+    // Every connection starts with a seed. Newer clients send it as a
+    // packet; older ones, and every client reconnecting to the game server,
+    // send just the four bytes.
+    if !session.seeded {
+        if session.received.first() != Some(&0xEF) {
+            if session.received.len() < 4 {
+                return;
+            }
+            session.received.drain(..4);
+        }
+        session.seeded = true;
+    }
 
-    let mut output = Vec::new();
+    println!("\n============= Parsing packet =============\n");
 
-    println!("\nCompressing Character List packet: {:02X?}", src);
-    huffman::compress(src, &mut output);
+    loop {
+        let Some(session) = shard.sessions.get_mut(session_id) else {
+            return;
+        };
+        let packet = match next_packet(&mut session.received) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => break,
+            Err(reason) => {
+                disconnect_malformed(shard, session_id, &reason);
+                return;
+            }
+        };
 
-    stream.write_all(&output).await?;
-    stream.flush().await?;
+        if handle_packet(&packet, session_id, shard, commands).is_none() {
+            let reason = format!("Malformed packet 0x{:02X}", packet[0]);
+            disconnect_malformed(shard, session_id, &reason);
+            return;
+        }
+    }
 
-    println!("\nSent compressed Character List packet: {:X?}", output);
+    println!("\n======== Finished parsing packet. ========\n");
+}
 
-    Ok(())
+// How long a packet from the client is, including its ID.
+enum Length {
+    Fixed(usize),
+    // Given in the two bytes after the ID.
+    Variable,
 }
 
-async fn parse_packets(buffer: [u8; 1024], mut stream: &mut TcpStream) -> Result<()> {
-    let mut buffer_slice = &buffer[..];
+// Every packet a client might send has to be known, even the ones that
+// aren't handled, so it can be skipped to get to the next one.
+fn packet_length(packet_id: u8) -> Option<Length> {
+    let length = match packet_id {
+        0x03 | 0x12 | 0x3A | 0x98 | 0xAD | 0xB1 | 0xB8 | 0xBD | 0xBF | 0xC2 | 0xD4 | 0xD6
+        | 0xD7 => return Some(Length::Variable),
+        0x00 => 104,
+        0x01 => 5,
+        0x02 => 7,
+        0x05 => 5,
+        0x06 => 5,
+        0x07 => 7,
+        0x08 => 15,
+        0x09 => 5,
+        0x13 => 10,
+        0x22 => 3,
+        0x2C => 2,
+        0x34 => 10,
+        0x5D => 73,
+        0x6C => 19,
+        0x72 => 5,
+        0x73 => 2,
+        0x75 => 35,
+        0x7D => 13,
+        0x80 => 62,
+        0x83 => 39,
+        0x91 => 65,
+        0x9B => 258,
+        0xA0 => 3,
+        0xA4 => 149,
+        0xA7 => 4,
+        0xB5 => 64,
+        0xB6 => 9,
+        0xBB => 9,
+        0xC8 => 2,
+        0xD1 => 2,
+        0xD9 => 268,
+        0xEF => 21,
+        0xF8 => 106,
+        _ => return None,
+    };
+
+    Some(Length::Fixed(length))
+}
 
-    println!("\n============= Parsing packet =============\n");
+// Takes the next whole packet off what has been received. Returns
+// Ok(None) if the rest of it hasn't arrived yet.
+fn next_packet(received: &mut Vec<u8>) -> std::result::Result<Option<Vec<u8>>, String> {
+    let Some(&packet_id) = received.first() else {
+        return Ok(None);
+    };
+
+    let length = match packet_length(packet_id) {
+        Some(Length::Fixed(length)) => length,
+        Some(Length::Variable) => {
+            let Some(&[high, low]) = received.get(1..3) else {
+                return Ok(None);
+            };
+            let length = u16::from_be_bytes([high, low]) as usize;
+            if length < 3 {
+                return Err(format!("Packet 0x{:02X} is too short", packet_id));
+            }
+            length
+        }
+        None => return Err(format!("Unknown packet 0x{:02X}", packet_id)),
+    };
+    if received.len() < length {
+        return Ok(None);
+    }
 
-    while buffer_slice.len() > 0 {
-        let packet_id = read_u8(&mut buffer_slice);
+    Ok(Some(received.drain(..length).collect()))
+}
 
-        match packet_id {
-            0xEF => {
-                handle_encrypted_login_seed_packet(&mut buffer_slice);
+fn disconnect_malformed(shard: &mut Shard, session_id: SessionId, reason: &str) {
+    if let Some(session) = shard.sessions.get_mut(session_id) {
+        println!("Disconnecting session {}: {}", session_id, reason);
+        session.received.clear();
+        session.disconnect();
+    }
+}
+
+// Handles one whole packet, ID and all. Returns None if it was malformed.
+fn handle_packet(
+    packet: &[u8],
+    session_id: SessionId,
+    shard: &mut Shard,
+    commands: &Commands,
+) -> Option<()> {
+    let (&packet_id, mut buffer_slice) = packet.split_first()?;
+    let buffer_slice = &mut buffer_slice;
+
+    match packet_id {
+        0xEF => handle_encrypted_login_seed_packet(buffer_slice)?,
+        0x80 => {
+            let (username, password) = handle_account_login_request_packet(buffer_slice)?;
+            shard.login(session_id, &username, &password, move |shard, login| {
+                let Some(session) = shard.sessions.get(session_id) else {
+                    return;
+                };
+                match login {
+                    Ok(()) => send_server_list_packet(session),
+                    Err(_) => send_login_denied_packet(session),
+                }
+            });
+        }
+        0xA0 => {
+            handle_server_select_packet(buffer_slice)?;
+            if let Some(session) = shard.sessions.get(session_id) {
+                send_server_redirect_packet(session);
             }
-            0x80 => {
-                handle_account_login_request_packet(&mut buffer_slice);
-                send_server_list_packet(&mut stream).await?;
+        }
+        0x91 => {
+            let (username, password) = handle_post_login_packet(buffer_slice)?;
+            // The client expects everything after this packet compressed,
+            // however long the login takes.
+            if let Some(session) = shard.sessions.get_mut(session_id) {
+                session.enable_compression();
             }
-            0xA0 => {
-                handle_server_select_packet(&mut buffer_slice);
-                send_server_redirect_packet(&mut stream).await?;
+            shard.login(session_id, &username, &password, move |shard, login| {
+                let Some(session) = shard.sessions.get(session_id) else {
+                    return;
+                };
+                match login {
+                    Ok(()) => {
+                        send_features_packet(session);
+                        send_character_list_packet(session);
+                        shard.enter_world(session_id);
+                    }
+                    Err(_) => send_login_denied_packet(session),
+                }
+            });
+        }
+        0x03 => {
+            let speech = handle_ascii_speech_request_packet(buffer_slice)?;
+            speech::handle_speech_request(shard, commands, session_id, speech);
+        }
+        0xAD => {
+            let speech = handle_unicode_speech_request_packet(buffer_slice)?;
+            speech::handle_speech_request(shard, commands, session_id, speech);
+        }
+        0x06 => {
+            let target = handle_double_click_packet(buffer_slice)?;
+            use_(shard, session_id, target);
+        }
+        0x07 => {
+            let (item, amount) = handle_pick_up_item_packet(buffer_slice)?;
+            if !pick_up_item(shard, session_id, item, amount) {
+                if let Some(session) = shard.sessions.get(session_id) {
+                    send_reject_move_item_packet(session);
+                }
             }
-            0x91 => {
-                handle_post_login_packet(&mut buffer_slice);
-                send_features_packet(&mut stream).await?;
-                send_character_list_packet(&mut stream).await?;
+        }
+        0x08 => {
+            let (item, location, container) = handle_drop_item_packet(buffer_slice)?;
+            if !drop_item(shard, session_id, item, location, container) {
+                if let Some(session) = shard.sessions.get(session_id) {
+                    send_reject_move_item_packet(session);
+                }
             }
-            0x73 => continue,
-            _ => continue,
         }
+        0x05 => {
+            let defender = handle_attack_request_packet(buffer_slice)?;
+            combat::attack(shard, session_id, defender);
+        }
+        0x72 => {
+            let war_mode = handle_war_mode_packet(buffer_slice)?;
+            combat::set_war_mode(shard, session_id, war_mode);
+        }
+        0xB1 => {
+            let (gump_id, button) = handle_gump_response_packet(buffer_slice)?;
+            death::answer_gump(shard, session_id, gump_id, button);
+        }
+        0x2C => handle_death_status_packet(buffer_slice)?,
+        0x12 => {
+            let (action_type, command) = handle_request_action_packet(buffer_slice)?;
+            // Spells, doors and emotes come this way too, but nothing
+            // handles them yet.
+            if action_type == 0x24 {
+                if let Some(skill) = skill_to_use(&command) {
+                    skills::use_skill(shard, session_id, skill);
+                }
+            }
+        }
+        0x3A => {
+            let (skill, lock) = handle_skill_lock_packet(buffer_slice)?;
+            if let (Ok(skill), Some(lock)) = (SkillId::try_from(skill), SkillLock::from_u8(lock)) {
+                skills::set_lock(shard, session_id, skill, lock);
+            }
+        }
+        0x34 => {
+            let (request_type, serial) = handle_status_request_packet(buffer_slice)?;
+            // Players can only see their own skills.
+            let own = shard
+                .sessions
+                .get(session_id)
+                .is_some_and(|session| session.mobile == Some(serial));
+            if request_type == 0x05 && own {
+                skills::send_skill_list(shard, session_id);
+            }
+        }
+        // Everything else the client sends is ignored for now.
+        _ => {}
     }
 
-    println!("\n======== Finished parsing packet. ========\n");
+    Some(())
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shard::tests::shard;
+    use async_std::channel::{self, Receiver};
+
+    fn shard_with_session(name: &str) -> (Shard, SessionId, Receiver<Vec<u8>>) {
+        let (mut shard, _) = shard(name);
+        let (outgoing_tx, outgoing_rx) = channel::unbounded();
+        let session_id = shard.sessions.add(outgoing_tx);
+        (shard, session_id, outgoing_rx)
+    }
+
+    fn login_packet() -> Vec<u8> {
+        let mut packet = vec![0x80; 62];
+        packet[1..31].fill(0);
+        packet[1..4].copy_from_slice(b"bob");
+        packet[31..61].fill(0);
+        packet[31..35].copy_from_slice(b"pass");
+        packet
+    }
+
+    #[test]
+    fn it_reads_a_unicode_speech_request() {
+        let buffer = [
+            0x00, 0x14, 0x09, 0x00, 0x34, 0x00, 0x03, 0x45, 0x4E, 0x55, 0x00, 0x00, 0x48, 0x00,
+            0x69, 0x00, 0x21, 0x00, 0x00,
+        ];
+        let mut buffer_slice = &buffer[..];
+
+        let speech = handle_unicode_speech_request_packet(&mut buffer_slice);
+
+        assert_eq!(
+            speech,
+            Some(Speech {
+                speech_type: SpeechType::Yell,
+                hue: 0x0034,
+                font: 0x0003,
+                language: String::from("ENU"),
+                keywords: vec![],
+                text: String::from("Hi!"),
+            })
+        );
+        assert!(buffer_slice.is_empty());
+    }
+
+    #[test]
+    fn it_reads_an_encoded_unicode_speech_request() {
+        // Three keywords: 0x010, 0x155 and 0x2AB, followed by "bank"
+        let buffer = [
+            0x00, 0x17, 0xC0, 0x00, 0x34, 0x00, 0x03, 0x45, 0x4E, 0x55, 0x00, 0x00, 0x30, 0x10,
+            0x15, 0x52, 0xAB, 0x62, 0x61, 0x6E, 0x6B, 0x00,
+        ];
+        let mut buffer_slice = &buffer[..];
+
+        let speech = handle_unicode_speech_request_packet(&mut buffer_slice).unwrap();
+
+        assert_eq!(speech.speech_type, SpeechType::Regular);
+        assert_eq!(speech.keywords, vec![0x010, 0x155, 0x2AB]);
+        assert_eq!(speech.text, "bank");
+        assert!(buffer_slice.is_empty());
+    }
+
+    #[test]
+    fn it_reads_an_ascii_speech_request() {
+        let buffer = [
+            0x00, 0x0E, 0x02, 0x00, 0x34, 0x00, 0x03, 0x77, 0x61, 0x76, 0x65, 0x73, 0x00,
+        ];
+        let mut buffer_slice = &buffer[..];
+
+        let speech = handle_ascii_speech_request_packet(&mut buffer_slice).unwrap();
+
+        assert_eq!(speech.speech_type, SpeechType::Emote);
+        assert_eq!(speech.text, "waves");
+        assert!(buffer_slice.is_empty());
+    }
+
+    #[test]
+    fn it_reads_skill_requests() {
+        let buffer = [0x00, 0x06, 0x00, 0x19, 0x02, 0x73];
+        let mut buffer_slice = &buffer[..];
+        assert_eq!(
+            handle_skill_lock_packet(&mut buffer_slice),
+            Some((25, SkillLock::Locked as u8))
+        );
+        assert_eq!(buffer_slice, [0x73]);
+
+        let buffer = [0x00, 0x09, 0x24, 0x32, 0x31, 0x20, 0x30, 0x00];
+        let mut buffer_slice = &buffer[..];
+        let (action_type, command) = handle_request_action_packet(&mut buffer_slice).unwrap();
+        assert_eq!(action_type, 0x24);
+        assert_eq!(skill_to_use(&command), Some(21));
+    }
+
+    #[test]
+    fn it_reads_combat_requests() {
+        let buffer = [0x00, 0x00, 0x00, 0x02, 0x73];
+        let mut buffer_slice = &buffer[..];
+        assert_eq!(handle_attack_request_packet(&mut buffer_slice), Some(2));
+        assert_eq!(buffer_slice, [0x73]);
+
+        let buffer = [0x01, 0x00, 0x32, 0x00, 0x73];
+        let mut buffer_slice = &buffer[..];
+        assert_eq!(handle_war_mode_packet(&mut buffer_slice), Some(true));
+        assert_eq!(buffer_slice, [0x73]);
+    }
+
+    #[test]
+    fn it_reads_gump_responses() {
+        let buffer = [
+            0x00, 0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x2C, 0x01, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x73,
+        ];
+        let mut buffer_slice = &buffer[..];
+        assert_eq!(
+            handle_gump_response_packet(&mut buffer_slice),
+            Some((0x2C01, 1))
+        );
+        assert_eq!(buffer_slice, [0x73]);
+    }
+
+    #[test]
+    fn it_leaves_the_next_packet_in_the_buffer() {
+        let buffer = [0x00, 0x0A, 0x00, 0x00, 0x34, 0x00, 0x03, 0x00, 0x00, 0x73];
+        let mut buffer_slice = &buffer[..];
+
+        handle_ascii_speech_request_packet(&mut buffer_slice);
+
+        assert_eq!(buffer_slice, [0x73]);
+    }
+
+    #[test]
+    fn it_rejects_truncated_and_invalid_speech_requests() {
+        // Too short for the language, and then for what the length claims.
+        let buffer = [0x00, 0x08, 0x00, 0x00, 0x34];
+        assert_eq!(handle_unicode_speech_request_packet(&mut &buffer[..]), None);
+        let buffer = [
+            0x00, 0x10, 0x00, 0x00, 0x34, 0x00, 0x03, 0x45, 0x4E, 0x55, 0x00,
+        ];
+        assert_eq!(handle_unicode_speech_request_packet(&mut &buffer[..]), None);
+
+        // Claims a body longer than was sent.
+        let buffer = [0x00, 0x20, 0x00, 0x00, 0x34, 0x00, 0x03, 0x68, 0x69, 0x00];
+        assert_eq!(handle_ascii_speech_request_packet(&mut &buffer[..]), None);
+        let buffer = [0x00, 0x02];
+        assert_eq!(handle_ascii_speech_request_packet(&mut &buffer[..]), None);
+    }
+
+    #[test]
+    fn it_reads_speech_that_is_not_valid_utf8() {
+        let buffer = [
+            0x00, 0x10, 0x00, 0x00, 0x34, 0x00, 0x03, 0xFF, 0xFE, 0x00, 0x00, 0x00, 0x68, 0x00,
+            0x00,
+        ];
+        let speech = handle_unicode_speech_request_packet(&mut &buffer[..]).unwrap();
+        assert_eq!(speech.language, "\u{FFFD}\u{FFFD}");
+        assert_eq!(speech.text, "h");
+
+        let buffer = [0x00, 0x0A, 0x00, 0x00, 0x34, 0x00, 0x03, 0xC3, 0x00];
+        let speech = handle_ascii_speech_request_packet(&mut &buffer[..]).unwrap();
+        assert_eq!(speech.text, "\u{FFFD}");
+    }
+
+    #[test]
+    fn it_waits_for_the_rest_of_a_split_packet() {
+        let (mut shard, session_id, outgoing_rx) = shard_with_session("tcp-split");
+        let commands = Commands::new();
+        let packet = login_packet();
+
+        let mut first = vec![0x0A, 0x00, 0x00, 0x01];
+        first.extend_from_slice(&packet[..20]);
+        parse_packets(&first, session_id, &mut shard, &commands);
+        assert!(outgoing_rx.try_recv().is_err());
+
+        parse_packets(&packet[20..], session_id, &mut shard, &commands);
+        assert!(outgoing_rx.try_recv().is_ok());
+        assert!(shard.sessions.get(session_id).unwrap().received.is_empty());
+    }
+
+    #[test]
+    fn it_disconnects_clients_that_send_malformed_packets() {
+        let commands = Commands::new();
+
+        let (mut shard, session_id, _outgoing_rx) = shard_with_session("tcp-unknown");
+        parse_packets(
+            &[0x0A, 0x00, 0x00, 0x01, 0xFF, 0x00],
+            session_id,
+            &mut shard,
+            &commands,
+        );
+        assert!(shard.sessions.get(session_id).unwrap().is_disconnected());
+
+        // Framed correctly, but too short for what it says it holds.
+        let (mut shard, session_id, _outgoing_rx) = shard_with_session("tcp-truncated");
+        parse_packets(
+            &[0x0A, 0x00, 0x00, 0x01, 0xAD, 0x00, 0x05, 0x00, 0x00],
+            session_id,
+            &mut shard,
+            &commands,
+        );
+        assert!(shard.sessions.get(session_id).unwrap().is_disconnected());
+    }
 }
@@ -1,5 +1,9 @@
 use byteorder::{BigEndian, ByteOrder};
 
+use crate::skills::{Skill, SkillId, Skills, SKILL_COUNT};
+use crate::speech::Speech;
+use crate::world::Mobile;
+
 pub fn server_list_packet() -> [u8; 46] {
     let mut buffer: [u8; 46] = [0; 46];
 
@@ -32,6 +36,13 @@ pub fn server_list_packet() -> [u8; 46] {
     buffer
 }
 
+pub fn login_denied_packet(reason: u8) -> [u8; 2] {
+    [
+        0x82, // packet ID
+        reason,
+    ]
+}
+
 pub fn server_redirect_packet() -> [u8; 11] {
     let mut buffer: [u8; 11] = [0; 11];
 
@@ -102,9 +113,300 @@ pub fn character_list_packet() -> Vec<u8> {
     src
 }
 
+pub fn unicode_speech_packet(serial: u32, body: u16, name: &str, speech: &Speech) -> Vec<u8> {
+    let text: Vec<u16> = speech.text.encode_utf16().collect();
+    let packet_length = 48 + text.len() * 2 + 2;
+
+    let mut src = vec![];
+
+    src.push(0xAE); // packet ID
+    src.append(&mut (packet_length as u16).to_be_bytes().into()); // packet size
+    src.append(&mut serial.to_be_bytes().into()); // speaker serial
+    src.append(&mut body.to_be_bytes().into()); // speaker body
+    src.push(speech.speech_type.to_u8()); // speech type
+    src.append(&mut speech.hue.to_be_bytes().into()); // text hue
+    src.append(&mut speech.font.to_be_bytes().into()); // text font
+    src.append(&mut fixed_length_string(&speech.language, 4)); // language, e.g. "ENU"
+    src.append(&mut fixed_length_string(name, 30)); // speaker name
+
+    for character in text {
+        src.append(&mut character.to_be_bytes().into());
+    }
+    src.append(&mut vec![0x00, 0x00]); // null terminator
+
+    src
+}
+
+// Tells the client it can't pick up, or put down, the item it tried to.
+pub fn reject_move_item_packet(reason: u8) -> [u8; 2] {
+    [
+        0x27, // packet ID
+        reason,
+    ]
+}
+
+pub fn update_hits_packet(serial: u32, max_hits: u16, hits: u16) -> Vec<u8> {
+    update_stat_packet(0xA1, serial, max_hits, hits)
+}
+
+pub fn update_mana_packet(serial: u32, max_mana: u16, mana: u16) -> Vec<u8> {
+    update_stat_packet(0xA2, serial, max_mana, mana)
+}
+
+pub fn update_stamina_packet(serial: u32, max_stamina: u16, stamina: u16) -> Vec<u8> {
+    update_stat_packet(0xA3, serial, max_stamina, stamina)
+}
+
+fn update_stat_packet(packet_id: u8, serial: u32, max: u16, current: u16) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(packet_id); // packet ID
+    src.append(&mut serial.to_be_bytes().into()); // mobile serial
+    src.append(&mut max.to_be_bytes().into()); // maximum
+    src.append(&mut current.to_be_bytes().into()); // current value
+
+    src
+}
+
+// The full status is only for the mobile's own player. Everyone else gets
+// the name and health bar, with hits already scaled however the caller
+// wants them shown.
+pub fn status_packet(mobile: &Mobile, full: bool, max_hits: u16, hits: u16) -> Vec<u8> {
+    let packet_length: u16 = if full { 66 } else { 43 };
+
+    let mut src = vec![];
+
+    src.push(0x11); // packet ID
+    src.append(&mut packet_length.to_be_bytes().into()); // packet size
+    src.append(&mut mobile.serial.to_be_bytes().into()); // mobile serial
+    src.append(&mut fixed_length_string(&mobile.name, 30)); // mobile name
+    src.append(&mut hits.to_be_bytes().into()); // hits
+    src.append(&mut max_hits.to_be_bytes().into()); // max hits
+    src.push(0x00); // can't be renamed
+    src.push(full as u8); // status type, 1 if the stats below follow
+
+    if full {
+        src.push(0x00); // sex and race
+        src.append(&mut mobile.strength.to_be_bytes().into()); // strength
+        src.append(&mut mobile.dexterity.to_be_bytes().into()); // dexterity
+        src.append(&mut mobile.intelligence.to_be_bytes().into()); // intelligence
+        src.append(&mut mobile.stamina.to_be_bytes().into()); // stamina
+        src.append(&mut mobile.max_stamina.to_be_bytes().into()); // max stamina
+        src.append(&mut mobile.mana.to_be_bytes().into()); // mana
+        src.append(&mut mobile.max_mana.to_be_bytes().into()); // max mana
+        src.append(&mut vec![0x00; 4]); // gold
+        src.append(&mut vec![0x00; 2]); // armour rating
+        src.append(&mut vec![0x00; 2]); // weight
+    }
+
+    src
+}
+
+// Tells other players that a mobile they can see has moved or changed how
+// it looks.
+pub fn mobile_moving_packet(mobile: &Mobile) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0x77); // packet ID
+    src.append(&mut mobile.serial.to_be_bytes().into()); // mobile serial
+    src.append(&mut mobile.body.to_be_bytes().into()); // body
+    src.append(&mut mobile.location.x.to_be_bytes().into()); // x
+    src.append(&mut mobile.location.y.to_be_bytes().into()); // y
+    src.append(&mut mobile.location.z.to_be_bytes().into()); // z
+    src.push(mobile.direction); // direction
+    src.append(&mut mobile.hue.to_be_bytes().into()); // hue
+    src.push(flags(mobile)); // flags, e.g. war mode or hidden
+    src.push(mobile.notoriety.to_u8()); // notoriety
+
+    src
+}
+
+// Redraws the player's own mobile, e.g. after it was moved or changed.
+pub fn draw_player_packet(mobile: &Mobile) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0x20); // packet ID
+    src.append(&mut mobile.serial.to_be_bytes().into()); // mobile serial
+    src.append(&mut mobile.body.to_be_bytes().into()); // body
+    src.push(0x00); // unknown
+    src.append(&mut mobile.hue.to_be_bytes().into()); // hue
+    src.push(flags(mobile)); // flags, e.g. war mode or hidden
+    src.append(&mut mobile.location.x.to_be_bytes().into()); // x
+    src.append(&mut mobile.location.y.to_be_bytes().into()); // y
+    src.append(&mut vec![0x00, 0x00]); // unknown
+    src.push(mobile.direction); // direction
+    src.append(&mut mobile.location.z.to_be_bytes().into()); // z
+
+    src
+}
+
+fn flags(mobile: &Mobile) -> u8 {
+    if mobile.war_mode {
+        0x40
+    } else {
+        0x00
+    }
+}
+
+// Every skill, with its cap. Skills are numbered from 1 in the full list
+// and the list ends with a 0.
+pub fn skill_list_packet(skills: &Skills) -> Vec<u8> {
+    let packet_length = 6 + SKILL_COUNT * 9;
+
+    let mut src = vec![];
+
+    src.push(0x3A); // packet ID
+    src.append(&mut (packet_length as u16).to_be_bytes().into()); // packet size
+    src.push(0x02); // list type, a full list with caps
+
+    for (id, skill) in skills.iter() {
+        src.append(&mut (id as u16 + 1).to_be_bytes().into()); // skill ID
+        append_skill(&mut src, skill);
+    }
+    src.append(&mut vec![0x00, 0x00]); // end of the list
+
+    src
+}
+
+// One skill that changed, with its cap. Skills are numbered from 0 here.
+pub fn skill_update_packet(id: SkillId, skill: &Skill) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0x3A); // packet ID
+    src.append(&mut 13u16.to_be_bytes().into()); // packet size
+    src.push(0xDF); // list type, a single skill with its cap
+    src.append(&mut (id as u16).to_be_bytes().into()); // skill ID
+    append_skill(&mut src, skill);
+
+    src
+}
+
+fn append_skill(src: &mut Vec<u8>, skill: &Skill) {
+    src.append(&mut skill.value().to_be_bytes().into()); // value
+    src.append(&mut skill.base.to_be_bytes().into()); // base value
+    src.push(skill.lock.to_u8()); // lock
+    src.append(&mut skill.cap.to_be_bytes().into()); // cap
+}
+
+// Confirms whether the player is in war mode, or takes them out of it.
+pub fn war_mode_packet(war_mode: bool) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0x72); // packet ID
+    src.push(war_mode as u8); // war mode
+    src.append(&mut vec![0x00, 0x32, 0x00]); // unknown, always these
+
+    src
+}
+
+// Highlights who the player is fighting, or nobody if `defender` is 0.
+pub fn change_combatant_packet(defender: u32) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0xAA); // packet ID
+    src.append(&mut defender.to_be_bytes().into()); // defender serial
+
+    src
+}
+
+// Tells the attacker's player that a swing was made, hit or miss.
+pub fn swing_packet(attacker: u32, defender: u32) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0x2F); // packet ID
+    src.push(0x00); // unknown
+    src.append(&mut attacker.to_be_bytes().into()); // attacker serial
+    src.append(&mut defender.to_be_bytes().into()); // defender serial
+
+    src
+}
+
+// Shows how much damage a mobile took over its head.
+pub fn damage_packet(serial: u32, amount: u16) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0x0B); // packet ID
+    src.append(&mut serial.to_be_bytes().into()); // mobile serial
+    src.append(&mut amount.to_be_bytes().into()); // damage
+
+    src
+}
+
+// Plays an action of the mobile's body once, e.g. swinging a sword.
+pub fn animation_packet(serial: u32, action: u16) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0x6E); // packet ID
+    src.append(&mut serial.to_be_bytes().into()); // mobile serial
+    src.append(&mut action.to_be_bytes().into()); // action
+    src.append(&mut 7u16.to_be_bytes().into()); // frame count
+    src.append(&mut 1u16.to_be_bytes().into()); // repeat count
+    src.push(0x00); // forwards
+    src.push(0x00); // don't repeat
+    src.push(0x00); // no delay between frames
+
+    src
+}
+
+// Shows the death screen and turns on ghost mode, or turns ghost mode off
+// again once the player is resurrected.
+pub fn death_status_packet(dead: bool) -> Vec<u8> {
+    vec![
+        0x2C,                           // packet ID
+        if dead { 0x00 } else { 0x02 }, // action
+    ]
+}
+
+// Opens a gump, laid out by `layout`, whose text commands refer to `text`
+// by index. The client answers with the button that closed it.
+pub fn gump_packet(
+    serial: u32,
+    gump_id: u32,
+    x: u32,
+    y: u32,
+    layout: &str,
+    text: &[&str],
+) -> Vec<u8> {
+    let mut src = vec![];
+
+    src.push(0xB0); // packet ID
+    src.append(&mut vec![0x00, 0x00]); // packet size, filled in below
+    src.append(&mut serial.to_be_bytes().into()); // serial of whoever it's from
+    src.append(&mut gump_id.to_be_bytes().into()); // gump ID
+    src.append(&mut x.to_be_bytes().into()); // x
+    src.append(&mut y.to_be_bytes().into()); // y
+    src.append(&mut ((layout.len() + 1) as u16).to_be_bytes().into()); // layout size
+    src.append(&mut layout.as_bytes().into()); // layout
+    src.push(0x00); // null terminator
+    src.append(&mut (text.len() as u16).to_be_bytes().into()); // number of text lines
+    for line in text {
+        let line: Vec<u16> = line.encode_utf16().collect();
+        src.append(&mut (line.len() as u16).to_be_bytes().into()); // line length
+        for character in line {
+            src.append(&mut character.to_be_bytes().into());
+        }
+    }
+
+    let packet_length = src.len() as u16;
+    src[1..3].copy_from_slice(&packet_length.to_be_bytes());
+
+    src
+}
+
+// Truncates or null pads a string to exactly `length` bytes, always leaving
+// room for at least one null terminator.
+fn fixed_length_string(string: &str, length: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = string.bytes().take(length - 1).collect();
+    bytes.resize(length, 0x00);
+    bytes
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::location::START_LOCATION;
+    use crate::skills::SkillLock;
+    use crate::speech::SpeechType;
 
     #[test]
     fn it_creates_the_correct_packet() {
@@ -153,4 +455,123 @@ mod tests {
 
         assert_eq!(packet, expected);
     }
+
+    #[test]
+    fn it_creates_a_unicode_speech_packet() {
+        let speech = Speech {
+            speech_type: SpeechType::Yell,
+            hue: 0x0034,
+            font: 0x0003,
+            language: String::from("ENU"),
+            keywords: vec![],
+            text: String::from("Hi!"),
+        };
+
+        let packet = unicode_speech_packet(0x00000001, 0x0190, "Bob", &speech);
+
+        let mut expected = vec![
+            0xAE, 0x00, 0x38, 0x00, 0x00, 0x00, 0x01, 0x01, 0x90, 0x09, 0x00, 0x34, 0x00, 0x03,
+            0x45, 0x4E, 0x55, 0x00, 0x42, 0x6F, 0x62,
+        ];
+        expected.append(&mut vec![0x00; 27]);
+        expected.append(&mut vec![0x00, 0x48, 0x00, 0x69, 0x00, 0x21, 0x00, 0x00]);
+
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn it_creates_mobile_update_packets() {
+        let mut mobile = Mobile::new(0x00000002, "Bob", 0x0190, START_LOCATION);
+        mobile.hue = 0x0021;
+        mobile.direction = 4;
+
+        assert_eq!(
+            update_hits_packet(mobile.serial, 100, 90),
+            vec![0xA1, 0x00, 0x00, 0x00, 0x02, 0x00, 0x64, 0x00, 0x5A]
+        );
+        assert_eq!(
+            mobile_moving_packet(&mobile),
+            vec![
+                0x77, 0x00, 0x00, 0x00, 0x02, 0x01, 0x90, 0x06, 0x42, 0x06, 0x37, 0x14, 0x04, 0x00,
+                0x21, 0x00, 0x01,
+            ]
+        );
+        assert_eq!(
+            draw_player_packet(&mobile),
+            vec![
+                0x20, 0x00, 0x00, 0x00, 0x02, 0x01, 0x90, 0x00, 0x00, 0x21, 0x00, 0x06, 0x42, 0x06,
+                0x37, 0x00, 0x00, 0x04, 0x14,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_sizes_status_packets_by_type() {
+        let mobile = Mobile::new(0x00000002, "Bob", 0x0190, START_LOCATION);
+
+        let full = status_packet(&mobile, true, 100, 100);
+        assert_eq!(full.len(), 66);
+        assert_eq!(&full[1..3], &[0x00, 0x42]);
+
+        let others = status_packet(&mobile, false, 25, 25);
+        assert_eq!(others.len(), 43);
+        assert_eq!(&others[37..43], &[0x00, 0x19, 0x00, 0x19, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn it_creates_skill_packets() {
+        let mut skills = Skills::default();
+        let skill = skills.get_mut(25);
+        skill.base = 505;
+        skill.lock = SkillLock::Locked;
+
+        let list = skill_list_packet(&skills);
+        assert_eq!(list.len(), 528);
+        assert_eq!(&list[..4], &[0x3A, 0x02, 0x10, 0x02]);
+        assert_eq!(
+            &list[4 + 25 * 9..4 + 26 * 9],
+            &[0x00, 0x1A, 0x01, 0xF9, 0x01, 0xF9, 0x02, 0x03, 0xE8]
+        );
+        assert_eq!(&list[526..], &[0x00, 0x00]);
+
+        assert_eq!(
+            skill_update_packet(25, skills.get(25)),
+            vec![0x3A, 0x00, 0x0D, 0xDF, 0x00, 0x19, 0x01, 0xF9, 0x01, 0xF9, 0x02, 0x03, 0xE8]
+        );
+    }
+
+    #[test]
+    fn it_creates_combat_packets() {
+        assert_eq!(war_mode_packet(true), vec![0x72, 0x01, 0x00, 0x32, 0x00]);
+        assert_eq!(
+            change_combatant_packet(0x0000_0002),
+            vec![0xAA, 0x00, 0x00, 0x00, 0x02]
+        );
+        assert_eq!(
+            swing_packet(0x0000_0001, 0x0000_0002),
+            vec![0x2F, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02]
+        );
+        assert_eq!(
+            damage_packet(0x0000_0002, 12),
+            vec![0x0B, 0x00, 0x00, 0x00, 0x02, 0x00, 0x0C]
+        );
+        assert_eq!(animation_packet(0x0000_0001, 31).len(), 14);
+    }
+
+    #[test]
+    fn it_creates_gump_packets() {
+        let gump = gump_packet(1, 2, 3, 4, "{ text 0 0 0 0 }", &["Hi"]);
+        assert_eq!(&gump[..3], &[0xB0, 0x00, 0x2E]);
+        assert_eq!(gump.len(), 0x2E);
+        assert_eq!(&gump[19..21], &[0x00, 0x11]);
+        assert_eq!(
+            &gump[38..],
+            &[0x00, 0x01, 0x00, 0x02, 0x00, 0x48, 0x00, 0x69]
+        );
+    }
+
+    #[test]
+    fn it_truncates_fixed_length_strings() {
+        assert_eq!(fixed_length_string("ENUS", 4), vec![0x45, 0x4E, 0x55, 0x00]);
+    }
 }
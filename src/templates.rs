@@ -0,0 +1,656 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::combat;
+use crate::location::Location;
+use crate::skills;
+use crate::world::{Notoriety, Serial, World};
+
+// The kinds of item and mobile there are, read from the .toml files in the
+// templates directory, e.g.
+//
+//     [item.dagger]
+//     graphic = 0x0F51
+//     name = "a dagger"
+//
+//     [mobile.orc]
+//     name = "an orc"
+//     body = 0x11
+//     loot = [{ item = "gold", amount = [10, 50] }]
+//
+// Items and mobiles made from a template remember its ID.
+#[derive(Debug, Default)]
+pub struct Templates {
+    items: BTreeMap<String, ItemTemplate>,
+    mobiles: BTreeMap<String, MobileTemplate>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ItemTemplate {
+    pub graphic: u16,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub hue: u16,
+    // In stones, for each one of a stack.
+    #[serde(default)]
+    pub weight: f32,
+    // Stackable items are made as one pile rather than one of each.
+    #[serde(default)]
+    pub stackable: bool,
+    // The gump shown when the item is opened, for containers.
+    pub gump: Option<u16>,
+    // For things that can be fought with.
+    pub weapon: Option<Weapon>,
+    // How much damage it soaks up when held or worn, at most.
+    #[serde(default)]
+    pub armor: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Weapon {
+    // The skill it's fought with, e.g. "swordsmanship".
+    pub skill: String,
+    // The least and most it does before strength is added.
+    pub damage: [u16; 2],
+    // Higher is faster.
+    pub speed: u16,
+    // How a human holding it swings it.
+    pub animation: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MobileTemplate {
+    pub name: String,
+    pub body: u16,
+    #[serde(default)]
+    pub hue: u16,
+    #[serde(default)]
+    pub notoriety: Notoriety,
+    #[serde(default)]
+    pub stats: Stats,
+    // Skill names to values, e.g. wrestling = 50.0. Values over the usual
+    // cap raise the cap to match.
+    #[serde(default)]
+    pub skills: BTreeMap<String, f64>,
+    // How it behaves. Only healers do anything different so far, offering
+    // to resurrect ghosts.
+    #[serde(default)]
+    pub ai: Ai,
+    // Item templates it's made holding.
+    #[serde(default)]
+    pub equipment: Vec<String>,
+    // What it might be carrying, rolled for each time one is made.
+    #[serde(default)]
+    pub loot: Vec<Loot>,
+}
+
+// Mobiles are made with all of their hits, stamina and mana.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stats {
+    pub strength: u16,
+    pub dexterity: u16,
+    pub intelligence: u16,
+    pub hits: u16,
+    pub stamina: u16,
+    pub mana: u16,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            strength: 50,
+            dexterity: 50,
+            intelligence: 50,
+            hits: 100,
+            stamina: 100,
+            mana: 100,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Ai {
+    #[default]
+    None,
+    Animal,
+    Monster,
+    Guard,
+    Vendor,
+    Healer,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Loot {
+    pub item: String,
+    // From 0, never, to 1, always.
+    #[serde(default = "always")]
+    pub chance: f64,
+    #[serde(default)]
+    pub amount: Amount,
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+// Either an exact amount, e.g. 5, or a range to pick from, e.g. [10, 50].
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Amount {
+    Exactly(u16),
+    Between([u16; 2]),
+}
+
+impl Default for Amount {
+    fn default() -> Self {
+        Amount::Exactly(1)
+    }
+}
+
+// How a templates file is laid out: an item table and a mobile table, each
+// keyed by template ID.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateFile {
+    #[serde(default)]
+    item: BTreeMap<String, ItemTemplate>,
+    #[serde(default)]
+    mobile: BTreeMap<String, MobileTemplate>,
+}
+
+impl Templates {
+    pub fn item(&self, id: &str) -> Option<&ItemTemplate> {
+        self.items.get(id)
+    }
+
+    pub fn mobile(&self, id: &str) -> Option<&MobileTemplate> {
+        self.mobiles.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len() + self.mobiles.len()
+    }
+
+    pub fn create_item(
+        &self,
+        world: &mut World,
+        id: &str,
+        location: Location,
+    ) -> Result<Serial, String> {
+        let template = self
+            .item(id)
+            .ok_or_else(|| format!("There's no item template called {}", id))?;
+
+        let serial = world.add_item(template.graphic, location);
+        let item = world.item_mut(serial).unwrap();
+        item.name = template.name.clone();
+        item.hue = template.hue;
+        item.template = Some(String::from(id));
+
+        Ok(serial)
+    }
+
+    // Makes the mobile along with its equipment and whatever loot it rolls.
+    pub fn create_mobile(
+        &self,
+        world: &mut World,
+        id: &str,
+        location: Location,
+    ) -> Result<Serial, String> {
+        let template = self
+            .mobile(id)
+            .ok_or_else(|| format!("There's no mobile template called {}", id))?;
+
+        let serial = world.add_mobile(&template.name, template.body, location);
+        let mobile = world.mobile_mut(serial).unwrap();
+        mobile.hue = template.hue;
+        mobile.notoriety = template.notoriety;
+        mobile.hits = template.stats.hits;
+        mobile.max_hits = template.stats.hits;
+        mobile.stamina = template.stats.stamina;
+        mobile.max_stamina = template.stats.stamina;
+        mobile.mana = template.stats.mana;
+        mobile.max_mana = template.stats.mana;
+        mobile.strength = template.stats.strength;
+        mobile.dexterity = template.stats.dexterity;
+        mobile.intelligence = template.stats.intelligence;
+        mobile.template = Some(String::from(id));
+        // Validation made sure every skill exists and has a value.
+        for (name, &points) in &template.skills {
+            let skill = skills::by_name(name).unwrap();
+            let base = skills::from_points(points).unwrap();
+            let cap = &mut mobile.skills.get_mut(skill).cap;
+            *cap = (*cap).max(base);
+            mobile.set_skill(skill, base);
+        }
+
+        for item in &template.equipment {
+            let item = self.create_item(world, item, location)?;
+            world.place_in(item, serial)?;
+        }
+        for rolled in self.roll_loot(template, &mut rand::thread_rng()) {
+            let item = rolled.create(self, world, location)?;
+            world.place_in(item, serial)?;
+        }
+
+        Ok(serial)
+    }
+
+    fn roll_loot<'a>(
+        &'a self,
+        template: &'a MobileTemplate,
+        rng: &mut impl Rng,
+    ) -> Vec<Rolled<'a>> {
+        let mut drops = vec![];
+        for loot in &template.loot {
+            if !rng.gen_bool(loot.chance) {
+                continue;
+            }
+            let amount = match loot.amount {
+                Amount::Exactly(amount) => amount,
+                Amount::Between([min, max]) => rng.gen_range(min..=max),
+            };
+            if amount == 0 {
+                continue;
+            }
+
+            // Validation made sure every loot item has a template.
+            if self.items[&loot.item].stackable {
+                drops.push(Rolled {
+                    item: &loot.item,
+                    amount,
+                });
+            } else {
+                drops.extend((0..amount).map(|_| Rolled {
+                    item: &loot.item,
+                    amount: 1,
+                }));
+            }
+        }
+        drops
+    }
+
+    // Returns "field: message" for everything wrong with the template that
+    // the file's syntax can't catch.
+    fn check_item(template: &ItemTemplate) -> Vec<String> {
+        let mut problems = vec![];
+
+        if !template.weight.is_finite() || template.weight < 0.0 {
+            problems.push(format!(
+                "weight: Must be 0 or more, not {}",
+                template.weight
+            ));
+        }
+        if template.gump.is_some() && template.stackable {
+            problems.push(String::from("gump: Containers can't be stackable"));
+        }
+
+        if let Some(weapon) = &template.weapon {
+            if !combat::WEAPON_SKILLS.contains(&weapon.skill.as_str()) {
+                problems.push(format!(
+                    "weapon.skill: {} isn't one of {}",
+                    weapon.skill,
+                    combat::WEAPON_SKILLS.join(", ")
+                ));
+            }
+            let [min, max] = weapon.damage;
+            if min > max {
+                problems.push(format!(
+                    "weapon.damage: The least, {}, is more than the most, {}",
+                    min, max
+                ));
+            }
+            if weapon.speed == 0 {
+                problems.push(String::from("weapon.speed: Must be at least 1"));
+            }
+        }
+
+        problems
+    }
+
+    fn check_mobile(&self, template: &MobileTemplate) -> Vec<String> {
+        let mut problems = vec![];
+
+        if template.stats.hits == 0 {
+            problems.push(String::from("stats.hits: Must be at least 1"));
+        }
+        for (name, &points) in &template.skills {
+            if skills::by_name(name).is_none() {
+                problems.push(format!("skills.{}: There's no skill called {}", name, name));
+            } else if skills::from_points(points).is_none() {
+                problems.push(format!("skills.{}: {} isn't a skill value", name, points));
+            }
+        }
+        for (index, item) in template.equipment.iter().enumerate() {
+            if self.item(item).is_none() {
+                problems.push(format!(
+                    "equipment[{}]: There's no item template called {}",
+                    index, item
+                ));
+            }
+        }
+        for (index, loot) in template.loot.iter().enumerate() {
+            if self.item(&loot.item).is_none() {
+                problems.push(format!(
+                    "loot[{}].item: There's no item template called {}",
+                    index, loot.item
+                ));
+            }
+            if !(0.0..=1.0).contains(&loot.chance) {
+                problems.push(format!(
+                    "loot[{}].chance: Must be from 0 to 1, not {}",
+                    index, loot.chance
+                ));
+            }
+            if let Amount::Between([min, max]) = loot.amount {
+                if min > max {
+                    problems.push(format!(
+                        "loot[{}].amount: The least, {}, is more than the most, {}",
+                        index, min, max
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+// One item rolled from a loot table.
+#[derive(Debug, PartialEq)]
+struct Rolled<'a> {
+    item: &'a str,
+    amount: u16,
+}
+
+impl Rolled<'_> {
+    fn create(
+        &self,
+        templates: &Templates,
+        world: &mut World,
+        location: Location,
+    ) -> Result<Serial, String> {
+        let serial = templates.create_item(world, self.item, location)?;
+        world.item_mut(serial).unwrap().amount = self.amount;
+        Ok(serial)
+    }
+}
+
+// Reads every .toml file in `directory`, in name order. A missing directory
+// just means there are no templates. Returns every problem found, each
+// naming the file and field it's in, rather than stopping at the first.
+pub fn load(directory: &str) -> Result<Templates, Vec<String>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Templates::default()),
+        Err(e) => return Err(vec![format!("{}: {}", directory, e)]),
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "toml")
+        })
+        .collect();
+    paths.sort();
+
+    let mut errors = vec![];
+    let sources = paths
+        .iter()
+        .filter_map(|path| {
+            let name = path.to_string_lossy().into_owned();
+            match fs::read_to_string(path) {
+                Ok(source) => Some((name, source)),
+                Err(e) => {
+                    errors.push(format!("{}: {}", name, e));
+                    None
+                }
+            }
+        })
+        .collect();
+
+    match load_sources(sources) {
+        Ok(templates) if errors.is_empty() => Ok(templates),
+        Ok(_) => Err(errors),
+        Err(mut load_errors) => {
+            errors.append(&mut load_errors);
+            Err(errors)
+        }
+    }
+}
+
+// Takes (path, source) pairs. Each file is checked on its own first, then
+// references between templates are checked once every file has been read.
+fn load_sources(sources: Vec<(String, String)>) -> Result<Templates, Vec<String>> {
+    let mut templates = Templates::default();
+    // Which file each template came from, for the errors.
+    let mut files: HashMap<String, String> = HashMap::new();
+    let mut errors = vec![];
+
+    for (path, source) in sources {
+        let file: TemplateFile = match parse(&source) {
+            Ok(file) => file,
+            Err(e) => {
+                errors.push(format!("{}:{}", path, e));
+                continue;
+            }
+        };
+
+        for (id, template) in file.item {
+            let key = format!("item.{}", id);
+            if let Some(first) = files.get(&key) {
+                errors.push(format!("{}: {}: Already defined in {}", path, key, first));
+                continue;
+            }
+            files.insert(key, path.clone());
+            templates.items.insert(id, template);
+        }
+        for (id, template) in file.mobile {
+            let key = format!("mobile.{}", id);
+            if let Some(first) = files.get(&key) {
+                errors.push(format!("{}: {}: Already defined in {}", path, key, first));
+                continue;
+            }
+            files.insert(key, path.clone());
+            templates.mobiles.insert(id, template);
+        }
+    }
+
+    for (id, template) in &templates.items {
+        let path = &files[&format!("item.{}", id)];
+        for problem in Templates::check_item(template) {
+            errors.push(format!("{}: item.{}.{}", path, id, problem));
+        }
+    }
+    for (id, template) in &templates.mobiles {
+        let path = &files[&format!("mobile.{}", id)];
+        for problem in templates.check_mobile(template) {
+            errors.push(format!("{}: mobile.{}.{}", path, id, problem));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(templates)
+    } else {
+        Err(errors)
+    }
+}
+
+// Returns "line: field: message", e.g. "3: item.dagger.hue: invalid type".
+fn parse(source: &str) -> Result<TemplateFile, String> {
+    serde_path_to_error::deserialize(toml::Deserializer::new(source)).map_err(|e| {
+        let line = e
+            .inner()
+            .span()
+            .map(|span| source[..span.start].matches('\n').count() + 1)
+            .unwrap_or(1);
+        let path = e.path().to_string();
+        if path == "." {
+            format!("{}: {}", line, e.inner().message())
+        } else {
+            format!("{}: {}: {}", line, path, e.inner().message())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+
+    const ITEMS: &str = r#"
+        [item.gold]
+        graphic = 0x0EED
+        name = "gold"
+        stackable = true
+
+        [item.dagger]
+        graphic = 0x0F51
+        name = "a dagger"
+        hue = 0x0481
+        weight = 1.0
+    "#;
+
+    fn load_sources(sources: &[(&str, &str)]) -> Result<Templates, Vec<String>> {
+        super::load_sources(
+            sources
+                .iter()
+                .map(|(path, source)| (path.to_string(), source.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn it_makes_mobiles_with_their_equipment_and_loot() {
+        let templates = load_sources(&[
+            ("items.toml", ITEMS),
+            (
+                "orcs.toml",
+                r#"
+                [mobile.orc]
+                name = "an orc"
+                body = 0x11
+                notoriety = "enemy"
+                stats = { hits = 60, mana = 0 }
+                skills = { wrestling = 50.0, tactics = 120.5 }
+                equipment = ["dagger"]
+                loot = [
+                    { item = "gold", amount = [10, 10] },
+                    { item = "dagger", amount = 2 },
+                    { item = "dagger", chance = 0.0 },
+                ]
+                "#,
+            ),
+        ])
+        .unwrap();
+        let mut world = World::new();
+
+        let orc = templates
+            .create_mobile(&mut world, "orc", START_LOCATION)
+            .unwrap();
+
+        let mobile = world.mobile(orc).unwrap();
+        assert_eq!(mobile.name, "an orc");
+        assert_eq!(mobile.notoriety, Notoriety::Enemy);
+        assert_eq!((mobile.hits, mobile.max_hits), (60, 60));
+        assert_eq!((mobile.stamina, mobile.mana), (100, 0));
+        assert_eq!(mobile.template.as_deref(), Some("orc"));
+        let wrestling = mobile.skills.get(skills::by_name("wrestling").unwrap());
+        assert_eq!((wrestling.base, wrestling.cap), (500, 1000));
+        let tactics = mobile.skills.get(skills::by_name("tactics").unwrap());
+        assert_eq!((tactics.base, tactics.cap), (1205, 1205));
+
+        let mut carried: Vec<_> = world
+            .contents(orc)
+            .iter()
+            .map(|serial| {
+                let item = &world.items[serial];
+                (item.template.clone().unwrap(), item.amount)
+            })
+            .collect();
+        carried.sort();
+        assert_eq!(
+            carried,
+            vec![
+                (String::from("dagger"), 1),
+                (String::from("dagger"), 1),
+                (String::from("dagger"), 1),
+                (String::from("gold"), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_syntax_errors_with_the_file_line_and_field() {
+        let errors = load_sources(&[(
+            "items.toml",
+            "[item.dagger]\ngraphic = 0x0F51\nhue = \"red\"\n",
+        )])
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("items.toml:3: item.dagger.hue: "),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn it_reports_every_bad_reference_and_value() {
+        let errors = load_sources(&[
+            ("items.toml", ITEMS),
+            (
+                "more.toml",
+                r#"
+                [item.gold]
+                graphic = 0x0EED
+
+                [item.club]
+                graphic = 0x13B4
+                weight = -1.0
+                weapon = { skill = "magery", damage = [8, 2], speed = 40, animation = 13 }
+
+                [item.pouch]
+                graphic = 0x0E79
+                stackable = true
+                gump = 0x003C
+
+                [mobile.orc]
+                name = "an orc"
+                body = 0x11
+                skills = { swimming = 50.0 }
+                equipment = ["axe"]
+                loot = [{ item = "gold", chance = 2.0, amount = [5, 1] }]
+                "#,
+            ),
+        ])
+        .unwrap_err();
+
+        assert_eq!(
+            errors,
+            vec![
+                "more.toml: item.gold: Already defined in items.toml",
+                "more.toml: item.club.weight: Must be 0 or more, not -1",
+                "more.toml: item.club.weapon.skill: magery isn't one of swordsmanship, mace_fighting, fencing, wrestling",
+                "more.toml: item.club.weapon.damage: The least, 8, is more than the most, 2",
+                "more.toml: item.pouch.gump: Containers can't be stackable",
+                "more.toml: mobile.orc.skills.swimming: There's no skill called swimming",
+                "more.toml: mobile.orc.equipment[0]: There's no item template called axe",
+                "more.toml: mobile.orc.loot[0].chance: Must be from 0 to 1, not 2",
+                "more.toml: mobile.orc.loot[0].amount: The least, 5, is more than the most, 1",
+            ]
+        );
+    }
+}
@@ -1,26 +1,639 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
 
-mod execution_thread;
+use crate::config::Config;
+
+#[cfg(test)]
+mod benchmark;
+mod clock;
+mod execution;
 mod prioritisation_thread;
-mod registration_thread;
+
+#[cfg(test)]
+pub use clock::ManualClock;
+pub use clock::{Clock, MonotonicClock};
+
+pub type TimerId = u64;
+
+// How many times a timer fires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Repeat {
+    Once,
+    // Nothing in the game repeats a set number of times yet.
+    #[allow(dead_code)]
+    Times(u32),
+    Forever,
+}
+
+impl Repeat {
+    // What's left after one more firing, or None if that was the last.
+    fn after_firing(self) -> Option<Repeat> {
+        match self {
+            Repeat::Once | Repeat::Times(0..=1) => None,
+            Repeat::Times(n) => Some(Repeat::Times(n - 1)),
+            Repeat::Forever => Some(Repeat::Forever),
+        }
+    }
+}
+
+// What a repeating timer does about the firings it missed when it runs late,
+// e.g. because the server stalled. Missed firings that are skipped don't
+// count towards `Repeat::Times`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Late {
+    // Fire once now, then carry on at the usual times.
+    Skip,
+    // Fire once for every missed firing, one straight after the other.
+    CatchUp,
+    // Fire once now, then next an interval from now.
+    #[allow(dead_code)]
+    FromNow,
+}
+
+impl Late {
+    // When a timer that was due at `due` but ran at `now` fires next.
+    fn next(self, due: i64, now: i64, interval: i64) -> i64 {
+        match self {
+            Late::CatchUp => due + interval,
+            Late::FromNow => now + interval,
+            Late::Skip if interval <= 0 => now,
+            Late::Skip => due + interval * ((now - due).max(0) / interval + 1),
+        }
+    }
+}
 
 pub struct Timer {
-    pub repetitions: isize,
+    pub repeat: Repeat,
+    pub late: Late,
     pub interval: i64,
     pub next: i64, // TODO rename to `next_tick`?
-    pub callback: Box<dyn FnMut() -> () + Send>,
+    pub callback: Box<dyn FnMut() + Send>,
+}
+
+// The part of a timer shared between its handle and the timer threads. Once
+// the timer is registered, `next` here is the one that counts.
+struct TimerState {
+    id: TimerId,
+    active: AtomicBool,
+    // Unlike `active`, stays false once the timer finishes repeating.
+    cancelled: AtomicBool,
+    next: AtomicI64,
+}
+
+// A registered timer, as passed between the timer threads.
+struct Scheduled {
+    timer: Timer,
+    state: Arc<TimerState>,
+    // How many times in a row the callback has panicked.
+    panics: u32,
+}
+
+impl Scheduled {
+    fn id(&self) -> TimerId {
+        self.state.id
+    }
+
+    fn is_active(&self) -> bool {
+        self.state.active.load(Ordering::Relaxed)
+    }
+
+    fn next(&self) -> i64 {
+        self.state.next.load(Ordering::Relaxed)
+    }
+}
+
+// Everything the prioritisation thread needs to hear about. It sleeps until
+// the next timer is due or one of these arrives.
+enum Message {
+    Register(Scheduled),
+    Rescheduled(TimerId),
+    Cancelled(TimerId),
+    // The clock has jumped forward.
+    Tick,
+    #[cfg(test)]
+    Flush(mpsc::Sender<()>),
+    Stop,
+}
+
+// Where timer callbacks run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Execution {
+    // On a pool of this many worker threads, so a slow callback only holds
+    // up its own worker.
+    Workers(usize),
+    // On the game loop thread, once a tick, via `Timers::run_due`.
+    MainLoop,
+}
+
+// What happens to a timer whose callback panics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnPanic {
+    Drop,
+    // Fire again `after` ticks later, giving up after `times` panics in a
+    // row.
+    Retry { times: u32, after: i64 },
+}
+
+pub struct Options {
+    pub execution: Execution,
+    pub on_panic: OnPanic,
+}
+
+impl Options {
+    pub fn from_config(config: &Config) -> Options {
+        Options {
+            execution: match config.timer_workers {
+                0 => Execution::MainLoop,
+                workers => Execution::Workers(workers),
+            },
+            on_panic: match config.timer_panic_retries {
+                0 => OnPanic::Drop,
+                times => OnPanic::Retry {
+                    times,
+                    after: config.timer_panic_retry_ms as i64,
+                },
+            },
+        }
+    }
+}
+
+// Returned when registering a timer, for stopping or moving it later.
+#[derive(Clone)]
+pub struct TimerHandle {
+    state: Arc<TimerState>,
+    messages_tx: mpsc::Sender<Message>,
+}
+
+impl TimerHandle {
+    pub fn id(&self) -> TimerId {
+        self.state.id
+    }
+
+    // Stops the timer firing again, and drops its callback.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+        self.state.active.store(false, Ordering::Relaxed);
+        let _ = self.messages_tx.send(Message::Cancelled(self.id()));
+    }
+
+    // Moves the next firing to the `next` tick. Has no effect once the timer
+    // has been cancelled or has finished repeating.
+    pub fn reschedule(&self, next: i64) {
+        if self.is_active() {
+            self.state.next.store(next, Ordering::Relaxed);
+            let _ = self.messages_tx.send(Message::Rescheduled(self.id()));
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.active.load(Ordering::Relaxed)
+    }
+
+    // Whether the timer was cancelled, as opposed to having finished. A
+    // firing whose callback hands work on to the game loop can check this
+    // before doing it.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// Counters updated by the timer threads, for keeping an eye on the timer
+// subsystem from the console.
+#[derive(Default)]
+pub struct Stats {
+    pub registered: AtomicUsize,
+    pub pending: AtomicUsize,
+    pub running: AtomicUsize,
+    pub executed: AtomicUsize,
+    pub cancelled: AtomicUsize,
+    pub skipped: AtomicUsize,
+    pub panicked: AtomicUsize,
+    // How late callbacks ran compared to when they were due, in ticks.
+    pub total_lateness: AtomicU64,
+    pub worst_lateness: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct Timers {
+    clock: Arc<dyn Clock>,
+    messages_tx: mpsc::Sender<Message>,
+    next_id: Arc<AtomicU64>,
+    stats: Arc<Stats>,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    main_loop: Option<(execution::Queue, execution::Executor)>,
+}
+
+impl Timers {
+    // Timers registered after `stop` never fire.
+    pub fn register(&self, timer: Timer) -> TimerHandle {
+        let state = Arc::new(TimerState {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            active: AtomicBool::new(true),
+            cancelled: AtomicBool::new(false),
+            next: AtomicI64::new(timer.next),
+        });
+
+        self.stats.registered.fetch_add(1, Ordering::Relaxed);
+        let _ = self.messages_tx.send(Message::Register(Scheduled {
+            timer,
+            state: Arc::clone(&state),
+            panics: 0,
+        }));
+
+        TimerHandle {
+            state,
+            messages_tx: self.messages_tx.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    // The current tick, for working out when a timer should next fire.
+    pub fn now(&self) -> i64 {
+        self.clock.now()
+    }
+
+    // Runs the callbacks of timers that are due on this thread, without
+    // waiting for any more. Does nothing unless the timers were started to
+    // run callbacks on the game loop.
+    pub fn run_due(&self) {
+        if let Some((queue, executor)) = &self.main_loop {
+            while let Some(scheduled) = execution::next_due(queue) {
+                executor.run(scheduled);
+            }
+        }
+    }
+
+    // Waits until every timer due so far has been run, including repeats
+    // that fell due while running them. Only for timers run by workers.
+    #[cfg(test)]
+    pub fn flush(&self) {
+        loop {
+            let executed = self.stats.executed.load(Ordering::Relaxed);
+            let (done_tx, done_rx) = mpsc::channel();
+            self.messages_tx.send(Message::Flush(done_tx)).unwrap();
+            done_rx.recv().unwrap();
+            while self.stats.running.load(Ordering::Relaxed) > 0 {
+                std::thread::yield_now();
+            }
+            if self.stats.executed.load(Ordering::Relaxed) == executed {
+                return;
+            }
+        }
+    }
+
+    // Stops firing timers. Callbacks for timers that are already due are
+    // run first; anything still waiting is dropped. Returns how many timers
+    // were dropped.
+    pub fn stop(&self) -> usize {
+        let _ = self.messages_tx.send(Message::Stop);
+
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // Helps the game loop with anything left, so it's done by the time
+        // this returns.
+        self.run_due();
+
+        self.stats.pending.load(Ordering::Relaxed)
+    }
 }
 
-pub fn start() -> mpsc::Sender<Timer> {
-    let (register_tx, register_rx) = mpsc::channel::<Timer>();
-    let (execute_tx, execute_rx) = mpsc::channel::<Timer>();
+pub fn start(clock: Arc<dyn Clock>, options: Options) -> Timers {
+    let (messages_tx, messages_rx) = mpsc::channel::<Message>();
+    let (execute_tx, execute_rx) = mpsc::channel::<Scheduled>();
+
+    let stats = Arc::new(Stats::default());
+
+    let wake_tx = messages_tx.clone();
+    clock.on_advance(Box::new(move || {
+        let _ = wake_tx.send(Message::Tick);
+    }));
+
+    let mut threads = vec![prioritisation_thread::spawn(
+        messages_rx,
+        execute_tx,
+        Arc::clone(&clock),
+        Arc::clone(&stats),
+    )];
+
+    let queue = Arc::new(Mutex::new(execute_rx));
+    let executor = execution::Executor {
+        messages_tx: messages_tx.clone(),
+        clock: Arc::clone(&clock),
+        stats: Arc::clone(&stats),
+        on_panic: options.on_panic,
+    };
+    let main_loop = match options.execution {
+        Execution::Workers(count) => {
+            threads.extend(execution::spawn_workers(count, &queue, &executor));
+            None
+        }
+        Execution::MainLoop => Some((queue, executor)),
+    };
+
+    Timers {
+        clock,
+        messages_tx,
+        next_id: Arc::new(AtomicU64::new(1)),
+        stats,
+        threads: Arc::new(Mutex::new(threads)),
+        main_loop,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manual_timers() -> (Timers, Arc<ManualClock>) {
+        manual_timers_with(OnPanic::Drop)
+    }
+
+    fn manual_timers_with(on_panic: OnPanic) -> (Timers, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::default());
+        let options = Options {
+            execution: Execution::Workers(2),
+            on_panic,
+        };
+        (start(Arc::clone(&clock) as Arc<dyn Clock>, options), clock)
+    }
+
+    // Registers a timer that panics every time it fires.
+    fn panicking_timer(timers: &Timers, repeat: Repeat) -> (TimerHandle, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&count);
+
+        let handle = timers.register(Timer {
+            repeat,
+            late: Late::Skip,
+            interval: 10,
+            next: timers.now(),
+            callback: Box::new(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                panic!("test timer");
+            }),
+        });
+
+        (handle, count)
+    }
+
+    // Registers a timer that counts how often it fires.
+    fn counting_timer(
+        timers: &Timers,
+        repeat: Repeat,
+        delay: i64,
+    ) -> (TimerHandle, Arc<AtomicUsize>) {
+        counting_timer_when_late(timers, repeat, Late::Skip, delay)
+    }
+
+    fn counting_timer_when_late(
+        timers: &Timers,
+        repeat: Repeat,
+        late: Late,
+        delay: i64,
+    ) -> (TimerHandle, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&count);
+
+        let handle = timers.register(Timer {
+            repeat,
+            late,
+            interval: 10,
+            next: timers.now() + delay,
+            callback: Box::new(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }),
+        });
+
+        (handle, count)
+    }
+
+    #[test]
+    fn it_gives_every_timer_its_own_id() {
+        let (timers, _) = manual_timers();
+        let (first, _) = counting_timer(&timers, Repeat::Once, 0);
+        let (second, _) = counting_timer(&timers, Repeat::Once, 0);
+        assert_ne!(first.id(), second.id());
+    }
+
+    #[test]
+    fn it_fires_timers_once_they_are_due() {
+        let (timers, clock) = manual_timers();
+        let (_, count) = counting_timer(&timers, Repeat::Once, 50);
+
+        clock.advance(49);
+        timers.flush();
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+
+        clock.advance(1);
+        timers.flush();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn it_stops_firing_cancelled_timers() {
+        let (timers, clock) = manual_timers();
+        let (handle, count) = counting_timer(&timers, Repeat::Once, 50);
+
+        handle.cancel();
+        clock.advance(100);
+        timers.flush();
+
+        assert!(!handle.is_active());
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert_eq!(timers.stats().cancelled.load(Ordering::Relaxed), 1);
+        assert_eq!(timers.stats().pending.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn it_fires_rescheduled_timers_at_the_new_time() {
+        let (timers, clock) = manual_timers();
+        let (handle, count) = counting_timer(&timers, Repeat::Once, 60_000);
+
+        handle.reschedule(timers.now() + 20);
+        clock.advance(19);
+        timers.flush();
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+
+        clock.advance(1);
+        timers.flush();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn it_repeats_every_interval_until_it_has_finished() {
+        let (timers, clock) = manual_timers();
+        let (handle, count) = counting_timer(&timers, Repeat::Times(3), 0);
+
+        for expected in 1..=3 {
+            timers.flush();
+            assert_eq!(count.load(Ordering::Relaxed), expected);
+            assert_eq!(handle.is_active(), expected < 3);
+            clock.advance(10);
+        }
+
+        timers.flush();
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn it_repeats_forever_until_cancelled() {
+        let (timers, clock) = manual_timers();
+        let (handle, count) = counting_timer(&timers, Repeat::Forever, 10);
+
+        for _ in 0..100 {
+            clock.advance(10);
+            timers.flush();
+        }
+        handle.cancel();
+        clock.advance(10);
+        timers.flush();
+
+        assert_eq!(count.load(Ordering::Relaxed), 100);
+    }
+
+    // Fires a timer due every 10 ticks, then stalls for 35 ticks after the
+    // first firing. Returns how many times it fired when it caught up and
+    // when it next fires after that.
+    fn stall(late: Late) -> (usize, Vec<usize>) {
+        let (timers, clock) = manual_timers();
+        let (_, count) = counting_timer_when_late(&timers, Repeat::Forever, late, 10);
+
+        clock.advance(10);
+        timers.flush();
+        clock.advance(35);
+        timers.flush();
+        let caught_up = count.load(Ordering::Relaxed) - 1;
+
+        let mut later = vec![];
+        for _ in 0..10 {
+            clock.advance(1);
+            timers.flush();
+            later.push(count.load(Ordering::Relaxed) - 1 - caught_up);
+        }
+
+        (caught_up, later)
+    }
+
+    #[test]
+    fn it_skips_missed_firings_and_keeps_to_the_usual_times() {
+        // Due at 20, 30 and 40, run at 45, next due at 50.
+        let (caught_up, later) = stall(Late::Skip);
+        assert_eq!(caught_up, 1);
+        assert_eq!(later, vec![0, 0, 0, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn it_catches_up_on_every_missed_firing() {
+        let (caught_up, later) = stall(Late::CatchUp);
+        assert_eq!(caught_up, 3);
+        assert_eq!(later, vec![0, 0, 0, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn it_reschedules_late_timers_from_when_they_ran() {
+        // Run at 45, next due at 55.
+        let (caught_up, later) = stall(Late::FromNow);
+        assert_eq!(caught_up, 1);
+        assert_eq!(later, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn it_measures_how_late_timers_fire() {
+        let (timers, clock) = manual_timers();
+        counting_timer(&timers, Repeat::Once, 10);
+        counting_timer(&timers, Repeat::Once, 20);
+
+        clock.advance(25);
+        timers.flush();
+
+        let stats = timers.stats();
+        assert_eq!(stats.total_lateness.load(Ordering::Relaxed), 20);
+        assert_eq!(stats.worst_lateness.load(Ordering::Relaxed), 15);
+    }
+
+    #[test]
+    fn it_keeps_firing_other_timers_after_a_callback_panics() {
+        let (timers, clock) = manual_timers();
+        let (panicking, panics) = panicking_timer(&timers, Repeat::Forever);
+        let (_, count) = counting_timer(&timers, Repeat::Times(2), 5);
+
+        for _ in 0..2 {
+            clock.advance(10);
+            timers.flush();
+        }
+
+        assert_eq!(panics.load(Ordering::Relaxed), 1);
+        assert!(!panicking.is_active());
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(timers.stats().panicked.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn it_retries_timers_that_panic_until_it_gives_up() {
+        let (timers, clock) = manual_timers_with(OnPanic::Retry {
+            times: 2,
+            after: 100,
+        });
+        let (handle, panics) = panicking_timer(&timers, Repeat::Once);
+
+        timers.flush();
+        clock.advance(99);
+        timers.flush();
+        assert_eq!(panics.load(Ordering::Relaxed), 1);
+
+        for _ in 0..3 {
+            clock.advance(100);
+            timers.flush();
+        }
+        assert_eq!(panics.load(Ordering::Relaxed), 3);
+        assert!(!handle.is_active());
+    }
+
+    #[test]
+    fn it_runs_callbacks_on_the_main_loop() {
+        let options = Options {
+            execution: Execution::MainLoop,
+            on_panic: OnPanic::Drop,
+        };
+        let timers = start(Arc::new(ManualClock::default()), options);
+        let (fired_on_tx, fired_on_rx) = mpsc::channel();
+
+        timers.register(Timer {
+            repeat: Repeat::Once,
+            late: Late::Skip,
+            interval: 0,
+            next: timers.now(),
+            callback: Box::new(move || fired_on_tx.send(std::thread::current().id()).unwrap()),
+        });
+        let running = timers.clone();
+        let main_loop = std::thread::spawn(move || loop {
+            running.run_due();
+            if let Ok(fired_on) = fired_on_rx.try_recv() {
+                return (fired_on, std::thread::current().id());
+            }
+            std::thread::yield_now();
+        });
+
+        let (fired_on, main_loop) = main_loop.join().unwrap();
+        timers.stop();
+        assert_eq!(fired_on, main_loop);
+    }
 
-    let new_timers: Vec<Timer> = vec![];
-    let new_timers = Arc::new(Mutex::new(new_timers));
+    #[test]
+    fn it_works_with_the_monotonic_clock() {
+        let options = Options::from_config(&Config::default());
+        let timers = start(Arc::new(MonotonicClock::start()), options);
+        let (handle, count) = counting_timer(&timers, Repeat::Once, 0);
 
-    registration_thread::spawn(register_rx, Arc::clone(&new_timers));
-    prioritisation_thread::spawn(execute_tx, new_timers);
-    execution_thread::spawn(execute_rx, register_tx.clone());
+        timers.flush();
 
-    return register_tx;
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(!handle.is_active());
+    }
 }
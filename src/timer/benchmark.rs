@@ -0,0 +1,95 @@
+// Compares the CPU time used by 100,000 idle timers under the old design,
+// which polled every millisecond and moved every timer into a new Vec each
+// time, with the current one. Run it on its own with:
+//   cargo test --release benchmark -- --ignored --nocapture --test-threads=1
+
+use super::*;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+const TIMERS: i64 = 100_000;
+const MEASURE_FOR: Duration = Duration::from_secs(2);
+
+// User plus system CPU time used by this process so far, in clock ticks
+// (usually hundredths of a second). Linux only.
+fn cpu_ticks() -> u64 {
+    let stat = fs::read_to_string("/proc/self/stat").unwrap();
+    // The process name is in brackets and may contain spaces.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .unwrap()
+        .1
+        .split_whitespace()
+        .collect();
+    fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+}
+
+fn measure(setup: impl FnOnce()) -> u64 {
+    setup();
+    let before = cpu_ticks();
+    thread::sleep(MEASURE_FOR);
+    cpu_ticks() - before
+}
+
+fn idle_timer(now: i64, n: i64) -> Timer {
+    Timer {
+        repeat: Repeat::Once,
+        late: Late::Skip,
+        interval: 0,
+        // An hour or so from now, so none of them fire.
+        next: now + 3_600_000 + n,
+        callback: Box::new(|| {}),
+    }
+}
+
+#[test]
+#[ignore]
+fn benchmark_idle_timers() {
+    let clock: Arc<dyn Clock> = Arc::new(MonotonicClock::start());
+    let running = Arc::new(AtomicBool::new(true));
+
+    let polling = measure(|| {
+        let timers: Vec<Timer> = (0..TIMERS).map(|n| idle_timer(clock.now(), n)).collect();
+        let new_timers = Arc::new(Mutex::new(timers));
+        let running = Arc::clone(&running);
+        let clock = Arc::clone(&clock);
+
+        // The loop prioritisation_thread used to run.
+        thread::spawn(move || {
+            let mut timers: Vec<Timer> = Vec::new();
+            while running.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+                timers.append(&mut new_timers.lock().unwrap());
+
+                let now = clock.now();
+                let mut not_due = vec![];
+                for timer in timers {
+                    if timer.next > now {
+                        not_due.push(timer);
+                    }
+                }
+                timers = not_due;
+            }
+        });
+    });
+    running.store(false, Ordering::Relaxed);
+
+    let timers = start(Arc::clone(&clock), Options::from_config(&Config::default()));
+    let heap = measure(|| {
+        for n in 0..TIMERS {
+            timers.register(idle_timer(timers.now(), n));
+        }
+        // Let the registrations be taken in before measuring.
+        while timers.stats().pending.load(Ordering::Relaxed) < TIMERS as usize {
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+    timers.stop();
+
+    println!(
+        "CPU ticks over {:?} with {} idle timers: polling {}, heap {}",
+        MEASURE_FOR, TIMERS, polling, heap
+    );
+    assert!(heap < polling);
+}
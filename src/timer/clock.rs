@@ -0,0 +1,88 @@
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, Ordering};
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Where the timer subsystem gets the current tick from. Ticks are
+// milliseconds from some fixed point and never go backwards.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> i64;
+
+    // Has `wake` called whenever the time moves other than by real time
+    // passing, so anything sleeping until a tick can check again.
+    fn on_advance(&self, _wake: Box<dyn Fn() + Send + Sync>) {}
+}
+
+// Real time, from a monotonic source so timers aren't thrown out when the
+// system clock is changed. Ticks count from when the clock was started.
+pub struct MonotonicClock {
+    started: Instant,
+}
+
+impl MonotonicClock {
+    pub fn start() -> MonotonicClock {
+        MonotonicClock {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> i64 {
+        self.started.elapsed().as_millis() as i64
+    }
+}
+
+// Time that only moves when told to, for stepping through timers in tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct ManualClock {
+    now: AtomicI64,
+    wakers: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn advance(&self, ticks: i64) {
+        self.now.fetch_add(ticks, Ordering::Relaxed);
+        for wake in self.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    fn on_advance(&self, wake: Box<dyn Fn() + Send + Sync>) {
+        self.wakers.lock().unwrap().push(wake);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_never_goes_backwards() {
+        let clock = MonotonicClock::start();
+        let first = clock.now();
+        assert!(clock.now() >= first);
+    }
+
+    #[test]
+    fn it_wakes_sleepers_when_advanced_by_hand() {
+        let clock = ManualClock::default();
+        let (wake_tx, wake_rx) = std::sync::mpsc::channel();
+        clock.on_advance(Box::new(move || wake_tx.send(()).unwrap()));
+
+        clock.advance(250);
+
+        assert_eq!(clock.now(), 250);
+        assert!(wake_rx.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,145 @@
+use super::{Clock, Late, Message, OnPanic, Scheduled, Stats};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Due timers, waiting for a worker or the game loop to run them.
+pub type Queue = Arc<Mutex<mpsc::Receiver<Scheduled>>>;
+
+// Takes the next due timer off the queue, waiting for one if need be.
+// Returns None once the timers have been stopped and the queue is empty.
+pub fn next(queue: &Queue) -> Option<Scheduled> {
+    queue.lock().unwrap().recv().ok()
+}
+
+// Takes the next due timer off the queue if there is one, without waiting.
+pub fn next_due(queue: &Queue) -> Option<Scheduled> {
+    queue.lock().unwrap().try_recv().ok()
+}
+
+pub fn spawn_workers(count: usize, queue: &Queue, executor: &Executor) -> Vec<JoinHandle<()>> {
+    (0..count)
+        .map(|_| {
+            let queue = Arc::clone(queue);
+            let executor = executor.clone();
+            thread::spawn(move || {
+                while let Some(scheduled) = next(&queue) {
+                    executor.run(scheduled);
+                }
+            })
+        })
+        .collect()
+}
+
+// Runs timer callbacks and puts repeating timers back in the queue.
+#[derive(Clone)]
+pub struct Executor {
+    pub messages_tx: mpsc::Sender<Message>,
+    pub clock: Arc<dyn Clock>,
+    pub stats: Arc<Stats>,
+    pub on_panic: OnPanic,
+}
+
+impl Executor {
+    pub fn run(&self, scheduled: Scheduled) {
+        self.fire(scheduled);
+        self.stats.running.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn fire(&self, mut scheduled: Scheduled) {
+        // The timer may have been cancelled while it was queued.
+        if !scheduled.is_active() {
+            self.stats.cancelled.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let now = self.clock.now();
+        let due = scheduled.next();
+        let lateness = (now - due).max(0) as u64;
+        self.stats
+            .total_lateness
+            .fetch_add(lateness, Ordering::Relaxed);
+        self.stats
+            .worst_lateness
+            .fetch_max(lateness, Ordering::Relaxed);
+
+        // A panicking callback only takes its own timer down, not the thread
+        // running it.
+        let callback = &mut scheduled.timer.callback;
+        let result = panic::catch_unwind(AssertUnwindSafe(callback));
+        self.stats.executed.fetch_add(1, Ordering::Relaxed);
+
+        let next = match result {
+            Ok(()) => {
+                scheduled.panics = 0;
+                self.next_firing(&mut scheduled, due, now)
+            }
+            Err(payload) => {
+                self.stats.panicked.fetch_add(1, Ordering::Relaxed);
+                scheduled.panics += 1;
+                self.retry(&scheduled, now, payload)
+            }
+        };
+
+        match next {
+            Some(next) if scheduled.is_active() => {
+                // Unless the callback rescheduled the timer itself.
+                let _ = scheduled.state.next.compare_exchange(
+                    due,
+                    next,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                // Nothing is listening once the timers have been stopped.
+                let _ = self.messages_tx.send(Message::Register(scheduled));
+            }
+            _ => scheduled.state.active.store(false, Ordering::Relaxed),
+        }
+    }
+
+    // When a timer that ran fine fires next, or None if it has finished.
+    fn next_firing(&self, scheduled: &mut Scheduled, due: i64, now: i64) -> Option<i64> {
+        let timer = &mut scheduled.timer;
+        timer.repeat = timer.repeat.after_firing()?;
+
+        if timer.late != Late::CatchUp && timer.interval > 0 {
+            let missed = (now - due).max(0) / timer.interval;
+            self.stats
+                .skipped
+                .fetch_add(missed as usize, Ordering::Relaxed);
+        }
+
+        Some(timer.late.next(due, now, timer.interval))
+    }
+
+    // When a timer whose callback panicked is tried again, if at all.
+    fn retry(&self, scheduled: &Scheduled, now: i64, payload: Box<dyn Any + Send>) -> Option<i64> {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown error");
+
+        match self.on_panic {
+            OnPanic::Retry { times, after } if scheduled.panics <= times => {
+                println!(
+                    "Timer {} panicked: {}. Retrying in {} ms",
+                    scheduled.id(),
+                    message,
+                    after
+                );
+                Some(now + after)
+            }
+            _ => {
+                println!(
+                    "Timer {} panicked: {}. Dropping it",
+                    scheduled.id(),
+                    message
+                );
+                None
+            }
+        }
+    }
+}
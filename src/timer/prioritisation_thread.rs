@@ -1,36 +1,240 @@
-use super::Timer;
-use crate::ticks::current_ticks;
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
+use super::{Clock, Message, Scheduled, Stats, TimerId};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-pub fn spawn(execute_tx: mpsc::Sender<Timer>, new_timers: Arc<Mutex<Vec<Timer>>>) {
+// Sleeps until the soonest timer is due or a message arrives, so an idle
+// server costs nothing however many timers are waiting.
+pub fn spawn(
+    messages_rx: mpsc::Receiver<Message>,
+    execute_tx: mpsc::Sender<Scheduled>,
+    clock: Arc<dyn Clock>,
+    stats: Arc<Stats>,
+) -> JoinHandle<()> {
     thread::spawn(move || {
-        let mut timers: Vec<Timer> = Vec::new();
+        let mut queue = Queue::default();
+        #[cfg(test)]
+        let mut flushes = vec![];
 
         loop {
-            thread::sleep(Duration::from_millis(1));
-            {
-                let mut new_timers = new_timers.lock().unwrap();
+            let message = match queue.next_due() {
+                Some(next) => {
+                    let wait = (next - clock.now()).max(0) as u64;
+                    messages_rx.recv_timeout(Duration::from_millis(wait))
+                }
+                None => messages_rx
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
 
-                while let Some(timer) = new_timers.pop() {
-                    timers.push(timer);
+            let stopping = match message {
+                Ok(Message::Register(timer)) => {
+                    // A timer cancelled while its callback ran can be handed
+                    // back after the message cancelling it.
+                    if timer.is_active() {
+                        stats.pending.fetch_add(1, Ordering::Relaxed);
+                        queue.insert(timer);
+                    } else {
+                        stats.cancelled.fetch_add(1, Ordering::Relaxed);
+                    }
+                    false
+                }
+                Ok(Message::Rescheduled(id)) => {
+                    queue.rescheduled(id);
+                    false
+                }
+                Ok(Message::Cancelled(id)) => {
+                    // Dropping a cancelled timer drops its callback too.
+                    if queue.remove(id).is_some() {
+                        stats.pending.fetch_sub(1, Ordering::Relaxed);
+                        stats.cancelled.fetch_add(1, Ordering::Relaxed);
+                    }
+                    false
                 }
+                #[cfg(test)]
+                Ok(Message::Flush(done)) => {
+                    flushes.push(done);
+                    false
+                }
+                Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => true,
+                Ok(Message::Tick) | Err(RecvTimeoutError::Timeout) => false,
+            };
+
+            // Timers that are already due still fire when stopping.
+            while let Some(timer) = queue.pop_due(clock.now()) {
+                stats.pending.fetch_sub(1, Ordering::Relaxed);
+                stats.running.fetch_add(1, Ordering::Relaxed);
+                if execute_tx.send(timer).is_err() {
+                    return;
+                }
+            }
+
+            #[cfg(test)]
+            for done in flushes.drain(..) {
+                let _ = done.send(());
+            }
+
+            if stopping {
+                return;
             }
+        }
+    })
+}
 
-            let mut not_due = vec![];
+// Waiting timers, with a min-heap of when they're due. Rescheduling pushes a
+// new heap entry rather than finding the old one, so the heap can hold stale
+// entries; they're skipped when they come up.
+#[derive(Default)]
+struct Queue {
+    timers: HashMap<TimerId, Scheduled>,
+    due: BinaryHeap<Reverse<(i64, TimerId)>>,
+}
 
-            let now = current_ticks();
+impl Queue {
+    fn insert(&mut self, timer: Scheduled) {
+        self.due.push(Reverse((timer.next(), timer.id())));
+        self.timers.insert(timer.id(), timer);
+    }
 
-            for timer in timers {
-                if timer.next > now {
-                    not_due.push(timer);
-                } else {
-                    execute_tx.send(timer).unwrap();
-                }
+    fn rescheduled(&mut self, id: TimerId) {
+        // A timer that isn't here is being run, and is put back with its new
+        // time afterwards.
+        if let Some(timer) = self.timers.get(&id) {
+            self.due.push(Reverse((timer.next(), id)));
+        }
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Scheduled> {
+        let timer = self.timers.remove(&id);
+
+        // Stale entries for cancelled timers would otherwise wait until
+        // they're due to be cleared out.
+        if self.due.len() > 2 * self.timers.len() + 1024 {
+            self.due = self
+                .timers
+                .values()
+                .map(|timer| Reverse((timer.next(), timer.id())))
+                .collect();
+        }
+
+        timer
+    }
+
+    fn next_due(&self) -> Option<i64> {
+        self.due.peek().map(|Reverse((next, _))| *next)
+    }
+
+    fn pop_due(&mut self, now: i64) -> Option<Scheduled> {
+        while let Some(&Reverse((next, id))) = self.due.peek() {
+            if next > now {
+                return None;
+            }
+            self.due.pop();
+
+            let is_current = self
+                .timers
+                .get(&id)
+                .is_some_and(|timer| timer.next() == next);
+            if is_current {
+                return self.timers.remove(&id);
             }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::{Late, ManualClock, Repeat, Timer, TimerState};
+    use std::sync::atomic::{AtomicBool, AtomicI64};
 
-            timers = not_due;
+    fn scheduled(id: TimerId, next: i64) -> Scheduled {
+        Scheduled {
+            timer: Timer {
+                repeat: Repeat::Once,
+                late: Late::Skip,
+                interval: 0,
+                next,
+                callback: Box::new(|| {}),
+            },
+            state: Arc::new(TimerState {
+                id,
+                active: AtomicBool::new(true),
+                cancelled: AtomicBool::new(false),
+                next: AtomicI64::new(next),
+            }),
+            panics: 0,
         }
-    });
+    }
+
+    #[test]
+    fn it_pops_timers_in_the_order_they_are_due() {
+        let mut queue = Queue::default();
+        queue.insert(scheduled(1, 300));
+        queue.insert(scheduled(2, 100));
+        queue.insert(scheduled(3, 200));
+
+        assert_eq!(queue.next_due(), Some(100));
+        assert_eq!(queue.pop_due(250).map(|timer| timer.id()), Some(2));
+        assert_eq!(queue.pop_due(250).map(|timer| timer.id()), Some(3));
+        assert!(queue.pop_due(250).is_none());
+    }
+
+    #[test]
+    fn it_skips_stale_entries_for_rescheduled_and_removed_timers() {
+        let mut queue = Queue::default();
+        let timer = scheduled(1, 100);
+        let state = Arc::clone(&timer.state);
+        queue.insert(timer);
+        queue.insert(scheduled(2, 150));
+
+        state.next.store(200, Ordering::Relaxed);
+        queue.rescheduled(1);
+        queue.remove(2);
+
+        assert!(queue.pop_due(199).is_none());
+        assert_eq!(queue.pop_due(200).map(|timer| timer.id()), Some(1));
+    }
+
+    #[test]
+    fn it_drops_timers_cancelled_during_their_callback() {
+        let (messages_tx, messages_rx) = mpsc::channel();
+        let (execute_tx, _execute_rx) = mpsc::channel();
+        let stats = Arc::new(Stats::default());
+        let thread = spawn(
+            messages_rx,
+            execute_tx,
+            Arc::new(ManualClock::default()),
+            Arc::clone(&stats),
+        );
+
+        let captured = Arc::new(());
+        let held = Arc::clone(&captured);
+        let mut timer = scheduled(1, 100);
+        timer.timer.callback = Box::new(move || {
+            let _ = &held;
+        });
+        // Cancelled after the executor saw it was still active, so the
+        // cancellation arrives before the timer is handed back.
+        timer.state.cancelled.store(true, Ordering::Relaxed);
+        timer.state.active.store(false, Ordering::Relaxed);
+        messages_tx.send(Message::Cancelled(1)).unwrap();
+        messages_tx.send(Message::Register(timer)).unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        messages_tx.send(Message::Flush(done_tx)).unwrap();
+        done_rx.recv().unwrap();
+
+        assert_eq!(Arc::strong_count(&captured), 1);
+        assert_eq!(stats.pending.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.cancelled.load(Ordering::Relaxed), 1);
+
+        messages_tx.send(Message::Stop).unwrap();
+        thread.join().unwrap();
+    }
 }
@@ -0,0 +1,219 @@
+use crate::sessions::Session;
+use crate::shard::Shard;
+use crate::state::{MobileChange, State};
+use crate::tcp::packets;
+use crate::world::Mobile;
+
+// How far away players see other mobiles change.
+const UPDATE_RANGE: u16 = 18;
+
+// Other players only see a health bar, so their hits are sent out of this
+// rather than the real numbers.
+const HEALTH_BAR_STEPS: u16 = 25;
+
+// Sends each client the fewest packets that bring it up to date with the
+// mobiles that changed since the last flush, however many times they
+// changed in between. The game loop calls this at the end of every tick.
+pub fn flush(shard: &mut Shard) {
+    for serial in shard.world.take_touched_mobiles() {
+        // Taking the changes isn't a change to save, so this goes around
+        // World::mobile_mut.
+        let Some(mobile) = shard.world.mobiles.get_mut(&serial) else {
+            continue;
+        };
+        let changed = mobile.take_changed();
+        if changed.is_empty() {
+            continue;
+        }
+
+        let mobile = &shard.world.mobiles[&serial];
+        let (own, others) = packets_for(mobile, |change| changed.contains(change));
+
+        for session in shard.sessions.iter() {
+            if session.mobile == Some(serial) {
+                send_all(session, &own);
+            }
+        }
+        for session in shard.sessions_in_range(&mobile.location, UPDATE_RANGE) {
+            if session.mobile != Some(serial) {
+                send_all(session, &others);
+            }
+        }
+    }
+}
+
+// Returns the packets for the mobile's own player and for everyone else who
+// can see it.
+fn packets_for(
+    mobile: &Mobile,
+    changed: impl Fn(MobileChange) -> bool,
+) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let any = |changes: &[MobileChange]| changes.iter().any(|&change| changed(change));
+    let mut own = vec![];
+    let mut others = vec![];
+    let (max_health, health) = health_bar(mobile);
+
+    // The full status has every stat in it, so it replaces the single stat
+    // updates.
+    if any(&[
+        MobileChange::Name,
+        MobileChange::Stats,
+        MobileChange::MaxHits,
+        MobileChange::MaxStamina,
+        MobileChange::MaxMana,
+    ]) {
+        own.push(packets::status_packet(
+            mobile,
+            true,
+            mobile.max_hits,
+            mobile.hits,
+        ));
+    } else {
+        if changed(MobileChange::Hits) {
+            own.push(packets::update_hits_packet(
+                mobile.serial,
+                mobile.max_hits,
+                mobile.hits,
+            ));
+        }
+        if changed(MobileChange::Mana) {
+            own.push(packets::update_mana_packet(
+                mobile.serial,
+                mobile.max_mana,
+                mobile.mana,
+            ));
+        }
+        if changed(MobileChange::Stamina) {
+            own.push(packets::update_stamina_packet(
+                mobile.serial,
+                mobile.max_stamina,
+                mobile.stamina,
+            ));
+        }
+    }
+
+    if changed(MobileChange::Name) {
+        others.push(packets::status_packet(mobile, false, max_health, health));
+    } else if any(&[MobileChange::Hits, MobileChange::MaxHits]) {
+        others.push(packets::update_hits_packet(
+            mobile.serial,
+            max_health,
+            health,
+        ));
+    }
+
+    for (id, skill) in mobile.skills.iter() {
+        if changed(MobileChange::Skill(id)) {
+            own.push(packets::skill_update_packet(id, skill));
+        }
+    }
+
+    if any(&[
+        MobileChange::Body,
+        MobileChange::Hue,
+        MobileChange::Notoriety,
+        MobileChange::Position,
+        MobileChange::WarMode,
+    ]) {
+        own.push(packets::draw_player_packet(mobile));
+        others.push(packets::mobile_moving_packet(mobile));
+    }
+
+    (own, others)
+}
+
+fn health_bar(mobile: &Mobile) -> (u16, u16) {
+    let health = mobile.hits as u32 * HEALTH_BAR_STEPS as u32 / mobile.max_hits.max(1) as u32;
+    (HEALTH_BAR_STEPS, health as u16)
+}
+
+fn send_all(session: &Session, packets: &[Vec<u8>]) {
+    for packet in packets {
+        session.send(packet.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+    use crate::sessions::SessionId;
+    use crate::shard::tests;
+    use crate::state::{MobileProperty, StateDelta};
+    use crate::world::Serial;
+    use async_std::channel::{self, Receiver};
+
+    fn shard(name: &str) -> Shard {
+        tests::shard(name).0
+    }
+
+    fn player(shard: &mut Shard, name: &str) -> (Serial, SessionId, Receiver<Vec<u8>>) {
+        let (outgoing_tx, outgoing_rx) = channel::unbounded();
+        let session_id = shard.sessions.add(outgoing_tx);
+        let mobile = shard.world.add_mobile(name, 0x190, START_LOCATION);
+        shard.sessions.get_mut(session_id).unwrap().mobile = Some(mobile);
+        (mobile, session_id, outgoing_rx)
+    }
+
+    fn received(outgoing_rx: &Receiver<Vec<u8>>) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| outgoing_rx.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn it_sends_one_update_however_many_times_hits_changed() {
+        let mut shard = shard("updates-hits");
+        let (bob, _, bob_rx) = player(&mut shard, "Bob");
+        let (_, _, alice_rx) = player(&mut shard, "Alice");
+
+        for _ in 0..10 {
+            let mobile = shard.world.mobile_mut(bob).unwrap();
+            mobile.update_state(&[StateDelta::new(MobileProperty::Hits, -5)]);
+        }
+        flush(&mut shard);
+
+        assert_eq!(
+            received(&bob_rx),
+            vec![packets::update_hits_packet(bob, 100, 50)]
+        );
+        assert_eq!(
+            received(&alice_rx),
+            vec![packets::update_hits_packet(bob, 25, 12)]
+        );
+
+        flush(&mut shard);
+        assert!(received(&bob_rx).is_empty());
+    }
+
+    #[test]
+    fn it_redraws_mobiles_that_moved_for_everyone_in_range() {
+        let mut shard = shard("updates-moved");
+        let (bob, _, bob_rx) = player(&mut shard, "Bob");
+        let (_, _, alice_rx) = player(&mut shard, "Alice");
+        let (far_away, _, far_away_rx) = player(&mut shard, "Carol");
+        shard.world.mobile_mut(far_away).unwrap().location.x += 100;
+
+        let mobile = shard.world.mobile_mut(bob).unwrap();
+        let mut location = mobile.location;
+        location.x += 1;
+        mobile.move_to(location);
+        mobile.set_property("name", "Robert").unwrap();
+        flush(&mut shard);
+
+        let mobile = shard.world.mobile(bob).unwrap();
+        assert_eq!(
+            received(&bob_rx),
+            vec![
+                packets::status_packet(mobile, true, 100, 100),
+                packets::draw_player_packet(mobile),
+            ]
+        );
+        assert_eq!(
+            received(&alice_rx),
+            vec![
+                packets::status_packet(mobile, false, 25, 25),
+                packets::mobile_moving_packet(mobile),
+            ]
+        );
+        assert!(received(&far_away_rx).is_empty());
+    }
+}
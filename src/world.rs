@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::location::Location;
+use crate::timer::TimerHandle;
+
+mod item;
+mod mobile;
+
+pub use item::Item;
+pub use mobile::{Mobile, Notoriety, MAX_FOOD};
+
+pub type Serial = u32;
+
+// Mobiles and items share one serial space, split in two so the client can
+// tell them apart: item serials always have the 0x40000000 bit set.
+const FIRST_MOBILE_SERIAL: Serial = 0x00000001;
+const FIRST_ITEM_SERIAL: Serial = 0x40000000;
+
+// Everything that has changed since the last save, for saving just those
+// entities. Removed entities are listed by serial.
+pub struct Changes {
+    pub next_mobile_serial: Serial,
+    pub next_item_serial: Serial,
+    pub mobiles: Vec<Mobile>,
+    pub items: Vec<(Serial, Item)>,
+    pub removed: Vec<Serial>,
+}
+
+// Change entities through the methods below rather than the maps directly,
+// so the change is picked up by the next save.
+#[derive(Clone)]
+pub struct World {
+    next_mobile_serial: Serial,
+    next_item_serial: Serial,
+    pub mobiles: HashMap<Serial, Mobile>,
+    pub items: HashMap<Serial, Item>,
+    dirty: HashSet<Serial>,
+    // Mobiles that may have changed since clients were last updated.
+    touched: HashSet<Serial>,
+    // Timers acting on an entity, cancelled when it's removed.
+    timers: HashMap<Serial, Vec<TimerHandle>>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        World {
+            next_mobile_serial: FIRST_MOBILE_SERIAL,
+            next_item_serial: FIRST_ITEM_SERIAL,
+            mobiles: HashMap::new(),
+            items: HashMap::new(),
+            dirty: HashSet::new(),
+            touched: HashSet::new(),
+            timers: HashMap::new(),
+        }
+    }
+
+    // Rebuilds a world from a save. The serial counters have to be saved too,
+    // otherwise deleted serials could be handed out again.
+    pub fn restore(
+        next_mobile_serial: Serial,
+        next_item_serial: Serial,
+        mobiles: HashMap<Serial, Mobile>,
+        items: HashMap<Serial, Item>,
+    ) -> Self {
+        World {
+            next_mobile_serial,
+            next_item_serial,
+            mobiles,
+            items,
+            dirty: HashSet::new(),
+            touched: HashSet::new(),
+            timers: HashMap::new(),
+        }
+    }
+
+    pub fn next_serials(&self) -> (Serial, Serial) {
+        (self.next_mobile_serial, self.next_item_serial)
+    }
+
+    pub fn add_mobile(&mut self, name: &str, body: u16, location: Location) -> Serial {
+        let serial = self.next_mobile_serial;
+        self.next_mobile_serial += 1;
+
+        self.mobiles
+            .insert(serial, Mobile::new(serial, name, body, location));
+        self.dirty.insert(serial);
+
+        serial
+    }
+
+    pub fn add_item(&mut self, graphic: u16, location: Location) -> Serial {
+        let serial = self.next_item_serial;
+        self.next_item_serial += 1;
+
+        self.items.insert(serial, Item::new(graphic, location));
+        self.dirty.insert(serial);
+
+        serial
+    }
+
+    // Moves an item into a container, or onto a mobile.
+    pub fn place_in(&mut self, serial: Serial, parent: Serial) -> Result<(), String> {
+        if !self.exists(parent) {
+            return Err(format!("Nothing has serial 0x{:08X}", parent));
+        }
+
+        // Putting a container inside itself, however deeply, would orphan it.
+        let mut ancestor = Some(parent);
+        while let Some(current) = ancestor {
+            if current == serial {
+                return Err(String::from("An item can't be placed inside itself"));
+            }
+            ancestor = self.items.get(&current).and_then(|item| item.parent);
+        }
+
+        let item = self
+            .items
+            .get_mut(&serial)
+            .ok_or_else(|| format!("There's no item with serial 0x{:08X}", serial))?;
+        item.parent = Some(parent);
+        self.dirty.insert(serial);
+
+        Ok(())
+    }
+
+    // Takes an item out of whatever it was in and puts it on the ground.
+    pub fn place_on_ground(&mut self, serial: Serial, location: Location) -> Result<(), String> {
+        let item = self
+            .items
+            .get_mut(&serial)
+            .ok_or_else(|| format!("There's no item with serial 0x{:08X}", serial))?;
+        item.parent = None;
+        item.location = location;
+        self.dirty.insert(serial);
+
+        Ok(())
+    }
+
+    pub fn contents(&self, parent: Serial) -> Vec<Serial> {
+        self.items
+            .iter()
+            .filter(|(_, item)| item.parent == Some(parent))
+            .map(|(&serial, _)| serial)
+            .collect()
+    }
+
+    // Removes an entity along with everything it contains.
+    pub fn remove(&mut self, serial: Serial) -> bool {
+        for child in self.contents(serial) {
+            self.remove(child);
+        }
+
+        self.dirty.insert(serial);
+        for timer in self.timers.remove(&serial).unwrap_or_default() {
+            timer.cancel();
+        }
+
+        if is_item(serial) {
+            self.items.remove(&serial).is_some()
+        } else {
+            self.mobiles.remove(&serial).is_some()
+        }
+    }
+
+    // Ties a timer to an entity, so it stops when the entity is removed.
+    pub fn own_timer(&mut self, serial: Serial, timer: TimerHandle) {
+        let timers = self.timers.entry(serial).or_default();
+        timers.retain(TimerHandle::is_active);
+        timers.push(timer);
+    }
+
+    pub fn exists(&self, serial: Serial) -> bool {
+        self.mobiles.contains_key(&serial) || self.items.contains_key(&serial)
+    }
+
+    pub fn mobile(&self, serial: Serial) -> Option<&Mobile> {
+        self.mobiles.get(&serial)
+    }
+
+    // Handing out a mutable reference counts as a change, whether or not
+    // the caller goes on to change anything.
+    pub fn mobile_mut(&mut self, serial: Serial) -> Option<&mut Mobile> {
+        let mobile = self.mobiles.get_mut(&serial)?;
+        self.dirty.insert(serial);
+        self.touched.insert(serial);
+        Some(mobile)
+    }
+
+    pub fn item_mut(&mut self, serial: Serial) -> Option<&mut Item> {
+        let item = self.items.get_mut(&serial)?;
+        self.dirty.insert(serial);
+        Some(item)
+    }
+
+    // The mobiles handed out by mobile_mut since take_touched_mobiles was
+    // last called.
+    pub fn touched_mobiles(&self) -> Vec<Serial> {
+        self.touched.iter().copied().collect()
+    }
+
+    // Hands over the mobiles handed out by mobile_mut since this was last
+    // called, for sending whatever changed about them to clients.
+    pub fn take_touched_mobiles(&mut self) -> Vec<Serial> {
+        self.touched.drain().collect()
+    }
+
+    // Copies out whatever has changed since this was last called. Only the
+    // changed entities are copied, so this stays cheap however big the
+    // world gets.
+    pub fn take_changes(&mut self) -> Changes {
+        let mut changes = Changes {
+            next_mobile_serial: self.next_mobile_serial,
+            next_item_serial: self.next_item_serial,
+            mobiles: vec![],
+            items: vec![],
+            removed: vec![],
+        };
+
+        for serial in self.dirty.drain() {
+            if let Some(mobile) = self.mobiles.get(&serial) {
+                changes.mobiles.push(mobile.clone());
+            } else if let Some(item) = self.items.get(&serial) {
+                changes.items.push((serial, item.clone()));
+            } else {
+                changes.removed.push(serial);
+            }
+        }
+
+        changes
+    }
+
+    pub fn apply(&mut self, changes: &Changes) {
+        self.next_mobile_serial = changes.next_mobile_serial;
+        self.next_item_serial = changes.next_item_serial;
+
+        for mobile in &changes.mobiles {
+            self.mobiles.insert(mobile.serial, mobile.clone());
+        }
+        for (serial, item) in &changes.items {
+            self.items.insert(*serial, item.clone());
+        }
+        for serial in &changes.removed {
+            self.mobiles.remove(serial);
+            self.items.remove(serial);
+        }
+    }
+}
+
+pub fn is_item(serial: Serial) -> bool {
+    serial >= FIRST_ITEM_SERIAL
+}
+
+// Serials and graphics are usually written in hex, e.g. 0x40000001, but
+// accept decimal too.
+pub fn parse_number<T: FromStr + TryFrom<u32>>(value: &str) -> Option<T> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(|number| T::try_from(number).ok()),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_property<T: FromStr + TryFrom<u32>>(property: &str, value: &str) -> Result<T, String> {
+    parse_number(value).ok_or_else(|| format!("{} isn't a valid value for {}", value, property))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::START_LOCATION;
+
+    #[test]
+    fn it_allocates_serials_from_separate_ranges() {
+        let mut world = World::new();
+        let mobile = world.add_mobile("Bob", 0x190, START_LOCATION);
+        let item = world.add_item(0x0EED, START_LOCATION);
+        assert_eq!(mobile, 0x00000001);
+        assert_eq!(item, 0x40000000);
+        assert!(!is_item(mobile));
+        assert!(is_item(item));
+    }
+
+    #[test]
+    fn it_removes_entities_by_serial() {
+        let mut world = World::new();
+        let mobile = world.add_mobile("Bob", 0x190, START_LOCATION);
+        let item = world.add_item(0x0EED, START_LOCATION);
+        assert!(world.remove(mobile));
+        assert!(world.remove(item));
+        assert!(!world.remove(item));
+    }
+
+    #[test]
+    fn it_removes_the_contents_of_removed_containers() {
+        let mut world = World::new();
+        let backpack = world.add_item(0x0E75, START_LOCATION);
+        let pouch = world.add_item(0x0E79, START_LOCATION);
+        let gold = world.add_item(0x0EED, START_LOCATION);
+        world.place_in(pouch, backpack).unwrap();
+        world.place_in(gold, pouch).unwrap();
+
+        world.remove(backpack);
+
+        assert!(world.items.is_empty());
+    }
+
+    #[test]
+    fn it_refuses_to_place_a_container_inside_itself() {
+        let mut world = World::new();
+        let backpack = world.add_item(0x0E75, START_LOCATION);
+        let pouch = world.add_item(0x0E79, START_LOCATION);
+        world.place_in(pouch, backpack).unwrap();
+
+        assert!(world.place_in(backpack, pouch).is_err());
+        assert!(world.place_in(backpack, backpack).is_err());
+    }
+
+    #[test]
+    fn it_only_hands_out_changes_since_they_were_last_taken() {
+        let mut world = World::new();
+        let bob = world.add_mobile("Bob", 0x190, START_LOCATION);
+        let gold = world.add_item(0x0EED, START_LOCATION);
+        let mut copy = World::new();
+        copy.apply(&world.take_changes());
+
+        world.mobile_mut(bob).unwrap().hits = 50;
+        world.remove(gold);
+        let changes = world.take_changes();
+
+        assert_eq!(changes.mobiles.len(), 1);
+        assert!(changes.items.is_empty());
+        assert_eq!(changes.removed, vec![gold]);
+
+        copy.apply(&changes);
+        assert_eq!(copy.mobile(bob).unwrap().hits, 50);
+        assert!(!copy.exists(gold));
+        assert!(world.take_changes().mobiles.is_empty());
+    }
+
+    #[test]
+    fn it_parses_hex_and_decimal_numbers() {
+        assert_eq!(parse_number::<Serial>("0x40000001"), Some(0x40000001));
+        assert_eq!(parse_number::<u16>("12"), Some(12));
+        assert_eq!(parse_number::<i8>("-5"), Some(-5));
+        assert_eq!(parse_number::<u16>("0x10000"), None);
+        assert_eq!(parse_number::<u16>("bob"), None);
+    }
+}
@@ -0,0 +1,75 @@
+use super::{parse_property, Serial};
+use crate::location::Location;
+use crate::state::{Dirty, ItemChange, State};
+
+#[derive(Clone)]
+pub struct Item {
+    pub name: Option<String>,
+    pub graphic: u16,
+    pub hue: u16,
+    pub amount: u16,
+    // Relative to the parent for items in a container.
+    pub location: Location,
+    // The container or mobile holding this item, if it isn't on the ground.
+    pub parent: Option<Serial>,
+    // The ID of the template it was made from, if any.
+    pub template: Option<String>,
+    // When it rots away, in milliseconds since the Unix epoch, if it does.
+    // Kept as a time of day so it carries on across restarts.
+    pub decays_at: Option<i64>,
+    // What clients haven't been told about yet. Not saved.
+    pub dirty: Dirty<ItemChange>,
+}
+
+impl Item {
+    pub fn new(graphic: u16, location: Location) -> Self {
+        Item {
+            name: None,
+            graphic,
+            hue: 0,
+            amount: 1,
+            location,
+            parent: None,
+            template: None,
+            decays_at: None,
+            dirty: Dirty::default(),
+        }
+    }
+
+    pub fn set_property(&mut self, property: &str, value: &str) -> Result<(), String> {
+        let change = match property {
+            "name" => {
+                self.name = Some(String::from(value));
+                ItemChange::Name
+            }
+            "graphic" => {
+                self.graphic = parse_property(property, value)?;
+                ItemChange::Graphic
+            }
+            "hue" => {
+                self.hue = parse_property(property, value)?;
+                ItemChange::Hue
+            }
+            "amount" => {
+                self.amount = parse_property(property, value)?;
+                ItemChange::Amount
+            }
+            "x" => {
+                self.location.x = parse_property(property, value)?;
+                ItemChange::Position
+            }
+            "y" => {
+                self.location.y = parse_property(property, value)?;
+                ItemChange::Position
+            }
+            "z" => {
+                self.location.z = parse_property(property, value)?;
+                ItemChange::Position
+            }
+            _ => return Err(format!("Items don't have a {} property", property)),
+        };
+
+        self.mark_changed(change);
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use serde::Deserialize;
+
+use super::{parse_property, Serial};
+use crate::location::Location;
+use crate::skills::{self, SkillId, SkillLock, Skills};
+use crate::state::{Dirty, MobileChange, State};
+
+// How a mobile appears to others, which colours its name and health bar.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Notoriety {
+    #[default]
+    Innocent,
+    Ally,
+    Attackable,
+    Criminal,
+    Enemy,
+    Murderer,
+    Invulnerable,
+}
+
+impl Notoriety {
+    pub fn from_u8(value: u8) -> Option<Notoriety> {
+        match value {
+            1 => Some(Notoriety::Innocent),
+            2 => Some(Notoriety::Ally),
+            3 => Some(Notoriety::Attackable),
+            4 => Some(Notoriety::Criminal),
+            5 => Some(Notoriety::Enemy),
+            6 => Some(Notoriety::Murderer),
+            7 => Some(Notoriety::Invulnerable),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Notoriety::Innocent => 1,
+            Notoriety::Ally => 2,
+            Notoriety::Attackable => 3,
+            Notoriety::Criminal => 4,
+            Notoriety::Enemy => 5,
+            Notoriety::Murderer => 6,
+            Notoriety::Invulnerable => 7,
+        }
+    }
+}
+
+pub const MAX_FOOD: u8 = 20;
+
+#[derive(Clone)]
+pub struct Mobile {
+    pub serial: Serial,
+    pub name: String,
+    pub body: u16,
+    pub hue: u16,
+    pub location: Location,
+    // 0 to 7, clockwise from north.
+    pub direction: u8,
+    pub notoriety: Notoriety,
+    pub strength: u16,
+    pub dexterity: u16,
+    pub intelligence: u16,
+    pub hits: u16,
+    pub max_hits: u16,
+    pub stamina: u16,
+    pub max_stamina: u16,
+    pub mana: u16,
+    pub max_mana: u16,
+    pub skills: Skills,
+    // From 0, starving, to MAX_FOOD, full. Hungry mobiles regenerate
+    // slowly.
+    pub food: u8,
+    // How many mobiles it has helped kill.
+    pub kills: u32,
+    // The ID of the template it was made from, if any.
+    pub template: Option<String>,
+    // Whether it's ready to fight. Not saved.
+    pub war_mode: bool,
+    // What clients haven't been told about yet. Not saved.
+    pub dirty: Dirty<MobileChange>,
+}
+
+impl Mobile {
+    pub fn new(serial: Serial, name: &str, body: u16, location: Location) -> Self {
+        Mobile {
+            serial,
+            name: String::from(name),
+            body,
+            hue: 0,
+            location,
+            direction: 0,
+            notoriety: Notoriety::Innocent,
+            strength: 50,
+            dexterity: 50,
+            intelligence: 50,
+            hits: 100,
+            max_hits: 100,
+            stamina: 100,
+            max_stamina: 100,
+            mana: 100,
+            max_mana: 100,
+            skills: Skills::default(),
+            food: MAX_FOOD,
+            kills: 0,
+            template: None,
+            war_mode: false,
+            dirty: Dirty::default(),
+        }
+    }
+
+    pub fn set_body(&mut self, body: u16) {
+        self.body = body;
+        self.mark_changed(MobileChange::Body);
+    }
+
+    pub fn set_war_mode(&mut self, war_mode: bool) {
+        self.war_mode = war_mode;
+        self.mark_changed(MobileChange::WarMode);
+    }
+
+    pub fn move_to(&mut self, location: Location) {
+        self.location = location;
+        self.mark_changed(MobileChange::Position);
+    }
+
+    pub fn set_skill(&mut self, id: SkillId, base: u16) {
+        self.skills.get_mut(id).base = base;
+        self.mark_changed(MobileChange::Skill(id));
+    }
+
+    pub fn set_skill_lock(&mut self, id: SkillId, lock: SkillLock) {
+        self.skills.get_mut(id).lock = lock;
+        self.mark_changed(MobileChange::Skill(id));
+    }
+
+    // Skills are properties too, set in points, e.g. magery 50.5.
+    pub fn set_property(&mut self, property: &str, value: &str) -> Result<(), String> {
+        let change = match property {
+            "name" => {
+                self.name = String::from(value);
+                MobileChange::Name
+            }
+            "body" => {
+                self.body = parse_property(property, value)?;
+                MobileChange::Body
+            }
+            "hue" => {
+                self.hue = parse_property(property, value)?;
+                MobileChange::Hue
+            }
+            "notoriety" => {
+                self.notoriety = parse_property(property, value)
+                    .ok()
+                    .and_then(Notoriety::from_u8)
+                    .ok_or_else(|| format!("{} isn't a valid value for {}", value, property))?;
+                MobileChange::Notoriety
+            }
+            "strength" => {
+                self.strength = parse_property(property, value)?;
+                MobileChange::Stats
+            }
+            "dexterity" => {
+                self.dexterity = parse_property(property, value)?;
+                MobileChange::Stats
+            }
+            "intelligence" => {
+                self.intelligence = parse_property(property, value)?;
+                MobileChange::Stats
+            }
+            "hits" => {
+                self.hits = parse_property(property, value)?;
+                MobileChange::Hits
+            }
+            "maxhits" => {
+                self.max_hits = parse_property(property, value)?;
+                MobileChange::MaxHits
+            }
+            "stamina" => {
+                self.stamina = parse_property(property, value)?;
+                MobileChange::Stamina
+            }
+            "maxstamina" => {
+                self.max_stamina = parse_property(property, value)?;
+                MobileChange::MaxStamina
+            }
+            "mana" => {
+                self.mana = parse_property(property, value)?;
+                MobileChange::Mana
+            }
+            "maxmana" => {
+                self.max_mana = parse_property(property, value)?;
+                MobileChange::MaxMana
+            }
+            "x" => {
+                self.location.x = parse_property(property, value)?;
+                MobileChange::Position
+            }
+            "y" => {
+                self.location.y = parse_property(property, value)?;
+                MobileChange::Position
+            }
+            "z" => {
+                self.location.z = parse_property(property, value)?;
+                MobileChange::Position
+            }
+            "food" => {
+                self.food = parse_property::<u8>(property, value)?.min(MAX_FOOD);
+                return Ok(());
+            }
+            "kills" => {
+                self.kills = parse_property(property, value)?;
+                return Ok(());
+            }
+            "direction" => {
+                self.direction = parse_property::<u8>(property, value)? & 0x07;
+                MobileChange::Position
+            }
+            _ => {
+                let id = skills::by_name(property)
+                    .ok_or_else(|| format!("Mobiles don't have a {} property", property))?;
+                self.skills.get_mut(id).base = value
+                    .parse()
+                    .ok()
+                    .and_then(skills::from_points)
+                    .ok_or_else(|| format!("{} isn't a valid value for {}", value, property))?;
+                MobileChange::Skill(id)
+            }
+        };
+
+        self.mark_changed(change);
+        Ok(())
+    }
+}